@@ -0,0 +1,95 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::{self, Display},
+};
+
+use crate::brainfuck::{match_brackets, RunError};
+
+const INSTRUCTIONS: [char; 8] = ['>', '<', '+', '-', '.', ',', '[', ']'];
+
+/// Statistics about a brainfuck program.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    /// How many times each instruction occurs.
+    pub(crate) counts: BTreeMap<char, usize>,
+    /// Number of instructions, excluding whitespace and comments.
+    pub(crate) effective: usize,
+    /// Number of characters in the program.
+    pub(crate) total: usize,
+    /// Deepest nesting of loops.
+    pub(crate) max_depth: usize,
+    /// Number of distinct cells the pointer visits if every loop body runs once. This is exact
+    /// for programs whose loops end on the cell they started on, like the compiler's output.
+    pub(crate) cells_touched: usize,
+}
+
+pub(crate) fn count_instructions(code: &str) -> Result<Stats, RunError> {
+    let mut instructions = Vec::new();
+    for line in code.lines() {
+        let line = line.split_once('#').map_or(line, |(code, _)| code);
+        instructions.extend(line.chars().filter(|c| INSTRUCTIONS.contains(c)));
+    }
+    match_brackets(&instructions)?;
+
+    let mut counts: BTreeMap<char, usize> = INSTRUCTIONS.iter().map(|c| (*c, 0)).collect();
+    let mut depth = 0;
+    let mut max_depth = 0;
+    let mut ptr = 0isize;
+    let mut cells = HashSet::from([0]);
+    for c in &instructions {
+        *counts.entry(*c).or_default() += 1;
+        match c {
+            '>' => ptr += 1,
+            '<' => ptr -= 1,
+            '[' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            ']' => depth -= 1,
+            _ => (),
+        }
+        cells.insert(ptr);
+    }
+
+    Ok(Stats {
+        counts,
+        effective: instructions.len(),
+        total: code.chars().count(),
+        max_depth,
+        cells_touched: cells.len(),
+    })
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (c, count) in &self.counts {
+            writeln!(f, "{}: {}", c, count)?;
+        }
+        writeln!(f, "Instructions: {}", self.effective)?;
+        writeln!(f, "Characters: {}", self.total)?;
+        writeln!(f, "Max loop depth: {}", self.max_depth)?;
+        write!(f, "Cells touched: {}", self.cells_touched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_instructions() -> Result<(), RunError> {
+        let stats = count_instructions("++[>+<-]>.  # add one, [print]\n,[[-]>]")?;
+        let counts: Vec<usize> = INSTRUCTIONS.iter().map(|c| stats.counts[c]).collect();
+        assert_eq!(counts, [3, 1, 3, 2, 1, 1, 3, 3]);
+        assert_eq!(stats.effective, 17);
+        assert_eq!(stats.total, 38);
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(stats.cells_touched, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_unmatched() {
+        assert!(count_instructions("[[]").is_err());
+    }
+}
@@ -4,10 +4,50 @@ use std::{
     io::{self, Write},
     path::Path,
     process::Stdio,
+    time::{Duration, Instant},
 };
 
 const TEMP_FILEPATH: &str = "temp.rs";
 const TEMP_EXECPATH: &str = "temp";
+/// Number of cells on the tape of transpiled programs that don't say how many they need.
+pub(crate) const DEFAULT_TAPE_SIZE: usize = 30000;
+/// The most cells a program can ask for, so a mistyped header can't allocate all the memory.
+const MAX_TAPE_SIZE: usize = 1 << 24;
+/// Starts the comment the compiler puts on the first line of its output to tell how many cells
+/// the program needs.
+const TAPE_SIZE_PREFIX: &str = "# tape size: ";
+/// The comment the compiler puts after the tape size when the program can stop itself by moving
+/// the pointer left of the first cell, which a circular tape would turn into a wrap around.
+pub(crate) const TRAPS_COMMENT: &str = "# traps: stops by moving left of the first cell\n";
+
+/// Settings for the Rust programs brainfuck code is transpiled to.
+#[derive(Clone, Copy, Default)]
+pub struct Options {
+    /// Collect output and only flush it before reading input and at exit, instead of after
+    /// every byte.
+    pub buffered: bool,
+    /// Treat the tape as circular, so moving left of the first cell lands on the last one and
+    /// moving right of the last cell lands on the first one.
+    pub wrap: bool,
+}
+
+/// How long each step of building and running a brainfuck file took.
+#[derive(Debug, Default)]
+pub struct Timings {
+    pub transpile: Duration,
+    pub rustc: Duration,
+    pub run: Duration,
+}
+
+impl Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "transpile {:?}, rustc {:?}, run {:?}",
+            self.transpile, self.rustc, self.run
+        )
+    }
+}
 
 #[derive(Debug)]
 pub enum RunError {
@@ -15,7 +55,22 @@ pub enum RunError {
     PermissionDenied,
     Runtime(String),
     InvalidPath(String),
-    InvalidChar(usize, char),
+    /// The character at the line and column, both starting at 1, isn't a brainfuck instruction.
+    InvalidChar(usize, usize, char),
+    UnmatchedBracket(usize),
+    /// The pointer was moved left of the first cell by the instruction at the index.
+    PointerUnderflow(usize),
+    /// The program tried to print more than the number of bytes it was allowed to.
+    OutputLimit(usize),
+    /// The instruction at the index read input after all of it had been read, and there is no
+    /// more to wait for.
+    InputExhausted(usize),
+    /// The instruction at the index changed the first cell, which was reserved as a sentinel.
+    SentinelWrite(usize),
+    /// The program stops itself by moving left of the first cell, but the tape was made circular.
+    WrappedTraps,
+    /// The first line of the program asks for more cells than a tape can have.
+    TapeTooLarge(usize),
 }
 
 impl From<io::Error> for RunError {
@@ -30,23 +85,135 @@ impl Display for RunError {
         match self {
             M::CouldNotCompile => write!(f, "Could not compile the generated Rust code"),
             M::InvalidPath(s) => write!(f, "Invalid path: {}", s),
-            M::InvalidChar(i, c) => write!(f, "Invalid character at index {}: {}", i, c),
+            M::InvalidChar(line, column, c) => write!(
+                f,
+                "Invalid character at line {}, column {}: {}",
+                line, column, c
+            ),
             M::PermissionDenied => write!(f, "Permission denied"),
             M::Runtime(s) => write!(f, "Runtime error: {}", s),
+            M::UnmatchedBracket(i) => write!(f, "Unmatched bracket at index {}", i),
+            M::PointerUnderflow(i) => {
+                write!(f, "Pointer moved left of the first cell at index {}", i)
+            }
+            M::OutputLimit(n) => write!(f, "Output exceeded the limit of {} bytes", n),
+            M::InputExhausted(i) => write!(f, "No input left to read at index {}", i),
+            M::SentinelWrite(i) => write!(f, "Wrote to the sentinel cell at index {}", i),
+            M::TapeTooLarge(size) => write!(
+                f,
+                "The program asks for {} cells, but a tape can have at most {}",
+                size, MAX_TAPE_SIZE
+            ),
+            M::WrappedTraps => write!(
+                f,
+                "The program stops itself by moving left of the first cell, which a circular \
+                 tape would wrap around instead; run it without wrapping"
+            ),
+        }
+    }
+}
+
+/// Pairs up the square brackets in `code`.
+///
+/// # Returns
+/// A vector with the index of the matching bracket at the index of every bracket, and `None`
+/// for all other characters
+pub(crate) fn match_brackets(code: &[char]) -> Result<Vec<Option<usize>>, RunError> {
+    let mut matches = vec![None; code.len()];
+    let mut open = Vec::new();
+    for (i, c) in code.iter().enumerate() {
+        match c {
+            '[' => open.push(i),
+            ']' => {
+                let start = open.pop().ok_or(RunError::UnmatchedBracket(i))?;
+                matches[start] = Some(i);
+                matches[i] = Some(start);
+            }
+            _ => (),
+        }
+    }
+    match open.pop() {
+        Some(i) => Err(RunError::UnmatchedBracket(i)),
+        None => Ok(matches),
+    }
+}
+
+/// Checks that `src` is brainfuck code that can be run: that it only contains instructions,
+/// whitespace and comments, and that its brackets are matched.
+pub(crate) fn validate(src: &str) -> Result<(), RunError> {
+    let mut instructions = Vec::new();
+    for (line, text) in src.lines().enumerate() {
+        let text = text.split_once('#').map_or(text, |(code, _)| code);
+        for (column, c) in text.chars().enumerate() {
+            match c {
+                '>' | '<' | '+' | '-' | '.' | ',' | '[' | ']' => instructions.push(c),
+                ' ' | '\t' | '\r' => (),
+                _ => return Err(RunError::InvalidChar(line + 1, column + 1, c)),
+            }
         }
     }
+    match_brackets(&instructions)?;
+    Ok(())
+}
+
+/// Creates the comment telling how many cells a compiled program needs.
+pub(crate) fn tape_size_comment(tape_size: usize) -> String {
+    format!("{}{}\n", TAPE_SIZE_PREFIX, tape_size)
+}
+
+/// Reads the number of cells a program needs from its first line, if it came from the compiler.
+pub(crate) fn tape_size(src: &str) -> Result<Option<usize>, RunError> {
+    let size = src
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix(TAPE_SIZE_PREFIX))
+        .and_then(|size| size.trim().parse().ok());
+    match size {
+        Some(size) if size > MAX_TAPE_SIZE => Err(RunError::TapeTooLarge(size)),
+        size => Ok(size),
+    }
 }
 
-fn to_bf(src: &str) -> Result<String, RunError> {
+/// Whether the compiler marked `src` as stopping itself by moving left of the first cell.
+pub(crate) fn has_traps(src: &str) -> bool {
+    src.lines()
+        .take_while(|line| line.starts_with('#'))
+        .any(|line| line == TRAPS_COMMENT.trim_end())
+}
+
+/// Transpiles brainfuck code to a Rust program.
+fn to_bf(src: &str, options: Options) -> Result<String, RunError> {
+    let Options { buffered, wrap } = options;
+    if wrap && has_traps(src) {
+        return Err(RunError::WrappedTraps);
+    }
     let mut indent = 4;
     let mut out = String::new();
     out.push_str("use std::io::Read;\n");
     out.push_str("use std::io::Write;\n\n");
     out.push_str("fn main() {\n");
+    if buffered {
+        out.push_str("    let mut out = std::io::BufWriter::new(std::io::stdout().lock());\n");
+    }
     out.push_str("    let mut sp = 0;\n");
-    out.push_str("    let mut stack = vec![0u8; 30000];\n");
+    out.push_str(&format!(
+        "    let mut stack = vec![0u8; {}];\n",
+        tape_size(src)?.unwrap_or(DEFAULT_TAPE_SIZE)
+    ));
 
-    for (i, c) in src.chars().enumerate() {
+    let mut in_comment = false;
+    let (mut line, mut column) = (1, 0);
+    for c in src.chars() {
+        if c == '\n' {
+            (line, column) = (line + 1, 0);
+        } else {
+            column += 1;
+        }
+        // Comments run from a `#` to the end of the line
+        if in_comment || c == '#' {
+            in_comment = c != '\n';
+            continue;
+        }
         if c == ']' {
             indent -= 4;
         }
@@ -55,28 +222,46 @@ fn to_bf(src: &str) -> Result<String, RunError> {
             indent += 4;
         }
         out.push_str(match c {
+            '>' if wrap => "sp = (sp + 1) % stack.len();",
+            '<' if wrap => "sp = (sp + stack.len() - 1) % stack.len();",
             '>' => "sp += 1;",
             '<' => "sp -= 1;",
             '+' => "stack[sp] = stack[sp].wrapping_add(1);",
             '-' => "stack[sp] = stack[sp].wrapping_sub(1);",
+            '.' if buffered => "write!(out, \"{}\", stack[sp] as char).unwrap();",
             '.' => "print!(\"{}\", stack[sp] as char); std::io::stdout().flush().unwrap();",
+            ',' if buffered => {
+                "out.flush().unwrap(); \
+                 stack[sp] = std::io::stdin().bytes().next().unwrap().unwrap();"
+            }
             ',' => "stack[sp] = std::io::stdin().bytes().next().unwrap().unwrap();",
             '[' => "while stack[sp] != 0 {",
             ']' => "}",
-            ' ' | '\n' | '\t' => continue,
-            _ => return Err(RunError::InvalidChar(i, c)),
+            ' ' | '\n' | '\t' | '\r' => continue,
+            _ => return Err(RunError::InvalidChar(line, column, c)),
         });
         out.push('\n');
     }
+    if buffered {
+        out.push_str("    out.flush().unwrap();\n");
+    }
     out.push_str("}\n");
     Ok(out)
 }
 
-pub fn make(srcpath: impl AsRef<Path>, outpath: impl AsRef<Path>) -> Result<(), RunError> {
+pub fn make(
+    srcpath: impl AsRef<Path>,
+    outpath: impl AsRef<Path>,
+    options: Options,
+) -> Result<Timings, RunError> {
+    let mut timings = Timings::default();
+    let start = Instant::now();
     let src = read_to_string(srcpath)?;
-    let out = to_bf(&src)?;
+    let out = to_bf(&src, options)?;
     let mut temp_file = File::create(TEMP_FILEPATH)?;
     temp_file.write_all(out.as_bytes())?;
+    timings.transpile = start.elapsed();
+    let start = Instant::now();
     let status = std::process::Command::new("rustc")
         .arg(TEMP_FILEPATH)
         .arg("-o")
@@ -86,6 +271,7 @@ pub fn make(srcpath: impl AsRef<Path>, outpath: impl AsRef<Path>) -> Result<(),
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .status()?;
+    timings.rustc = start.elapsed();
     if !status.success() {
         return Err(RunError::CouldNotCompile);
     }
@@ -99,13 +285,15 @@ pub fn make(srcpath: impl AsRef<Path>, outpath: impl AsRef<Path>) -> Result<(),
         },
     }
 
-    Ok(())
+    Ok(timings)
 }
 
-pub fn run_file(srcpath: impl AsRef<Path>) -> Result<(), RunError> {
-    make(srcpath, TEMP_EXECPATH)?;
+pub fn run_file(srcpath: impl AsRef<Path>, options: Options) -> Result<Timings, RunError> {
+    let mut timings = make(srcpath, TEMP_EXECPATH, options)?;
     let exec_path = Path::new(".").join(TEMP_EXECPATH);
+    let start = Instant::now();
     let status = std::process::Command::new(exec_path).status()?;
+    timings.run = start.elapsed();
     if !status.success() {
         return Err(RunError::Runtime(format!(
             "Process exited with status code {}",
@@ -120,5 +308,241 @@ pub fn run_file(srcpath: impl AsRef<Path>) -> Result<(), RunError> {
             _ => return Err(RunError::InvalidPath(e.to_string())),
         },
     }
-    Ok(())
+    Ok(timings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+
+    #[test]
+    fn test_match_brackets() {
+        let code: Vec<char> = "+[>[-]<]".chars().collect();
+        let matches = match_brackets(&code).unwrap();
+        assert_eq!(matches[1], Some(7));
+        assert_eq!(matches[3], Some(5));
+        assert_eq!(matches[7], Some(1));
+        assert_eq!(matches[0], None);
+        let code: Vec<char> = "[[]".chars().collect();
+        assert!(matches!(
+            match_brackets(&code),
+            Err(RunError::UnmatchedBracket(0))
+        ));
+        let code: Vec<char> = "[]]".chars().collect();
+        assert!(matches!(
+            match_brackets(&code),
+            Err(RunError::UnmatchedBracket(2))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_char() {
+        let src = "+++\n[>+<-]\n>> x\n";
+        assert!(matches!(
+            to_bf(src, Options::default()),
+            Err(RunError::InvalidChar(3, 4, 'x'))
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("+[>+<-] # comment with x\n>.").is_ok());
+        assert!(matches!(
+            validate("+\n >x"),
+            Err(RunError::InvalidChar(2, 3, 'x'))
+        ));
+        assert!(matches!(
+            validate("+[>\n]]"),
+            Err(RunError::UnmatchedBracket(4))
+        ));
+    }
+
+    #[test]
+    fn test_comments() -> Result<(), RunError> {
+        let src = read_to_string("tests/brainfuck/comments.bf")?;
+        let out = to_bf(&src, Options::default())?;
+        let loops = out.matches("while").count();
+        let prints = out.matches("print!").count();
+        assert_eq!((loops, prints), (2, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tape_size() -> Result<(), RunError> {
+        let src = tape_size_comment(3) + "+>+>+";
+        assert_eq!(tape_size(&src)?, Some(3));
+        assert!(to_bf(&src, Options::default())?.contains("vec![0u8; 3]"));
+        assert!(to_bf("+>+>+", Options::default())?.contains("vec![0u8; 30000]"));
+        let src = tape_size_comment(usize::MAX) + "+";
+        assert!(matches!(
+            to_bf(&src, Options::default()),
+            Err(RunError::TapeTooLarge(usize::MAX))
+        ));
+        Ok(())
+    }
+
+    /// Compiles the Rust program `rust` into the temporary directory, runs it with `input` on
+    /// stdin and returns what it printed.
+    fn run_rust(rust: &str, name: &str, input: &[u8]) -> Vec<u8> {
+        let dir = std::env::temp_dir();
+        let src = dir.join(format!("{}.rs", name));
+        let exec = dir.join(name);
+        std::fs::write(&src, rust).unwrap();
+        let status = std::process::Command::new("rustc")
+            .arg(&src)
+            .arg("-o")
+            .arg(&exec)
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut child = std::process::Command::new(&exec)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        let _ = remove_file(src);
+        let _ = remove_file(exec);
+        output.stdout
+    }
+
+    #[test]
+    fn test_buffered_output() -> Result<(), RunError> {
+        // Prints every byte value from 255 down to 1, 16 times over
+        let src = "++++++++++++++++[>-[.-]<-]";
+        let options = Options {
+            buffered: true,
+            ..Options::default()
+        };
+        let buffered = to_bf(src, options)?;
+        assert!(!buffered.contains("print!"));
+        let expected: Vec<u8> = (1..=255u8)
+            .rev()
+            .map(|b| b as char)
+            .collect::<String>()
+            .repeat(16)
+            .into_bytes();
+        assert_eq!(
+            run_rust(&to_bf(src, Options::default())?, "brang_unbuffered", b""),
+            expected
+        );
+        assert_eq!(run_rust(&buffered, "brang_buffered", b""), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrap() -> Result<(), RunError> {
+        // Moves back and forth between the first and the last cell
+        let src = tape_size_comment(4) + "++++++++[<++++>-]<+.";
+        let options = Options {
+            wrap: true,
+            ..Options::default()
+        };
+        let out = to_bf(&src, options)?;
+        assert!(!out.contains("sp -= 1"));
+        assert_eq!(run_rust(&out, "brang_wrap", b""), b"!");
+        let trapping = tape_size_comment(4) + TRAPS_COMMENT + "<[-]";
+        assert!(matches!(
+            to_bf(&trapping, options),
+            Err(RunError::WrappedTraps)
+        ));
+        Ok(())
+    }
+
+    /// A xorshift generator, so the random programs are the same on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        /// A number from 0 up to but not including `n`.
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// Appends about `length` random instructions to `code` and returns how many bytes they read.
+    /// Every program generated this way halts: a loop counts down the cell it starts on, and
+    /// nothing in its body changes that cell or leaves the pointer elsewhere. Input is only read
+    /// outside loops, so the number of bytes read is known.
+    ///
+    /// # Arguments
+    /// * `ptr` - The cell the pointer is at
+    /// * `counters` - The cells counted down by the loops the code is in
+    fn random_code(
+        rng: &mut Rng,
+        code: &mut String,
+        ptr: &mut usize,
+        counters: &mut Vec<usize>,
+        length: usize,
+    ) -> usize {
+        let mut reads = 0;
+        for _ in 0..length {
+            let free = !counters.contains(ptr);
+            match rng.below(8) {
+                0 => {
+                    code.push('>');
+                    *ptr += 1;
+                }
+                1 if *ptr > 0 => {
+                    code.push('<');
+                    *ptr -= 1;
+                }
+                2 | 3 if free => code.push('+'),
+                4 if free => code.push('-'),
+                5 => code.push('.'),
+                6 if counters.is_empty() => {
+                    code.push(',');
+                    reads += 1;
+                }
+                7 if free && counters.len() < 2 => {
+                    let start = *ptr;
+                    code.push('[');
+                    counters.push(start);
+                    reads += random_code(rng, code, ptr, counters, length / 2);
+                    counters.pop();
+                    let (back, steps) = if *ptr > start {
+                        ('<', *ptr - start)
+                    } else {
+                        ('>', start - *ptr)
+                    };
+                    code.extend(std::iter::repeat_n(back, steps));
+                    *ptr = start;
+                    code.push_str("-]");
+                }
+                _ => (),
+            }
+        }
+        reads
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for i in 0..6 {
+            let mut code = String::new();
+            let reads = random_code(&mut rng, &mut code, &mut 0, &mut Vec::new(), 40);
+            let input: Vec<u8> = (0..reads).map(|_| rng.below(256) as u8).collect();
+            let mut interpreter = Interpreter::from_code(&code).unwrap();
+            for byte in &input {
+                interpreter.provide_input(*byte);
+            }
+            interpreter.run_to_completion().unwrap();
+            let options = Options {
+                buffered: i % 2 == 1,
+                ..Options::default()
+            };
+            let rust = to_bf(&code, options).unwrap();
+            assert_eq!(
+                run_rust(&rust, &format!("brang_random_{}", i), &input),
+                interpreter.output().as_bytes(),
+                "{}",
+                code
+            );
+        }
+    }
 }
@@ -1,31 +1,191 @@
 #![allow(dead_code, unused_variables)]
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    rc::Rc,
+};
 
 use crate::{
-    parser::{parse, Expr, Statement},
-    tokenizer::{tokenize, Token},
+    parser::{parse, BinaryOp, Expr, FormatPiece, MatchArm, Program, Statement, Type, UnaryOp},
+    resolver::check_assignments,
+    tokenizer::{locate, tokenize, tokenize_located, Position, Span, Token},
 };
 
+#[derive(Clone, Copy)]
+struct Variable {
+    /// The cell holding the value. For strings, this is the first character of the buffer.
+    index: usize,
+    ty: Type,
+    /// Whether the variable has been assigned a value since it was defined.
+    initialized: bool,
+    /// The longest string a string variable can hold.
+    capacity: usize,
+    /// Whether the value of the variable is ever used.
+    read: bool,
+    /// Where the variable is defined, if it is defined by a `let` statement.
+    defined_at: Option<Position>,
+    /// The value the variable is known to hold at this point of the compiled code, if it was
+    /// computed from constants and hasn't been assigned since.
+    constant: Option<u8>,
+}
+
+/// How many calls of the same function can be nested before a call stops the program. Calls
+/// are inlined, so a recursive function is unrolled this many times.
+const MAX_RECURSION_DEPTH: usize = 8;
+/// How deeply calls can be nested, counting all functions, unless the options say otherwise.
+const DEFAULT_MAX_INLINE_DEPTH: usize = 32;
+/// The most calls compiled in place in one program. Every call copies the body of its function,
+/// so functions that call the next one twice double the code at every level.
+const MAX_INLINED_CALLS: usize = 4096;
+/// The file index of the positions in the prelude, which has no file name.
+const PRELUDE_FILE: usize = usize::MAX;
+/// The most iterations of a loop with a known number of iterations that are unrolled. Longer
+/// loops are compiled as loops, to keep the code small.
+const MAX_UNROLLED_ITERATIONS: usize = 16;
+/// The most copies of loop bodies compiled while unrolling a loop and the loops nested in it,
+/// so nested loops can't multiply into huge code.
+const MAX_UNROLLED_COPIES: usize = 64;
+
+/// A user-defined function. Calls to it are compiled by inlining its body.
+#[derive(Clone)]
+struct Function {
+    params: Vec<String>,
+    body: Rc<Statement>,
+}
+
+/// A loop, labeled block or function call that control flow can leave early.
+#[derive(Clone)]
+struct Frame {
+    /// The cell of a flag that is set while the frame is still running, if it can be left early.
+    running: Option<usize>,
+    kind: FrameKind,
+}
+
+#[derive(Clone, PartialEq)]
+enum FrameKind {
+    Loop,
+    Block {
+        label: String,
+    },
+    /// A function call, writing its return value to `result`.
+    Call {
+        result: usize,
+    },
+}
+
+/// The standard prelude, defining functions every program can use.
+const STD_PRELUDE: &str = include_str!("prelude.brang");
+
+/// Source code compiled in front of the program.
+#[derive(Clone, Default)]
+pub enum Prelude {
+    #[default]
+    Std,
+    None,
+    /// The source code of a prelude to use instead of the standard one.
+    Custom(String),
+}
+
+/// The base numbers are printed in.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Radix {
+    #[default]
+    Decimal,
+    /// Two lowercase hexadecimal digits per number, like `print_hex`.
+    Hex,
+}
+
+/// Settings that change how source code is compiled.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Report reading a variable that has never been assigned a value as an error, instead of
+    /// reading it as 0.
+    pub check_uninitialized: bool,
+    /// Emit code that stops the program when a value breaks an invariant the generated code
+    /// relies on, like a boolean being 0 or 1.
+    pub debug_checks: bool,
+    pub prelude: Prelude,
+    /// How deeply function calls can be nested before compiling fails, since every nested call
+    /// inlines another copy of a function body. Defaults to 32.
+    pub max_inline_depth: Option<usize>,
+    /// Put a comment in front of the code for each statement, telling which statement it is.
+    pub explain: bool,
+    /// Break the generated code into lines of at most this many characters. Comments are kept
+    /// on their own lines and aren't broken.
+    pub line_width: Option<usize>,
+    /// The base `print_number` and printing numbers with `print` use.
+    pub print_radix: Radix,
+    /// A character that starts a line comment in the source, like `#`, besides `//`.
+    pub comment_prefix: Option<char>,
+    /// Warn about arithmetic known at compile time whose result goes above this value or wraps
+    /// around, even when it involves variables.
+    pub max_cell_value: Option<u8>,
+    /// Leave the first cell unused and start allocating at the second one, so an interpreter run
+    /// with a sentinel can catch the pointer moving one cell too far left.
+    pub sentinel: bool,
+}
+
+#[derive(Clone)]
 pub(crate) struct Compiler {
     ptr: isize,
     stack_ptr: isize,
     output: Vec<char>,
-    variables: HashMap<String, usize>,
-    functions: HashMap<String, String>,
-    string_literals: HashMap<String, usize>,
+    variables: HashMap<String, Variable>,
+    functions: HashMap<String, Function>,
     free_list: Vec<usize>,
+    /// The loops and function calls enclosing the code being compiled, innermost last.
+    frames: Vec<Frame>,
+    /// The functions whose bodies are being inlined, innermost last.
+    call_stack: Vec<String>,
+    warnings: Vec<String>,
+    /// Number of cells the code emitted so far uses, from the first cell to the rightmost one
+    /// the pointer visits.
+    tape_size: usize,
+    /// The cell the pointer was at when each enclosing emitted loop was opened, innermost last.
+    /// Only tracked in debug builds.
+    loop_starts: Vec<isize>,
+    /// The first emitted loop found to end on a different cell than it started on.
+    unbalanced_loop: Option<String>,
+    /// Number of emitted loops that are open. Code inside one may run any number of times, so
+    /// a value assigned there isn't known afterwards.
+    open_loops: usize,
+    /// How many more copies of loop bodies the loops being unrolled may compile, or `None` when
+    /// no loop is being unrolled.
+    unroll_budget: Option<usize>,
+    /// Number of calls compiled in place so far.
+    inlined_calls: usize,
+    /// The names of the files being compiled, by the file index of their positions.
+    files: Vec<String>,
+    /// Whether a trap has been emitted.
+    traps: bool,
+    options: Options,
 }
 
 impl Compiler {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
+        Self::with_options(Options::default())
+    }
+
+    pub(crate) fn with_options(options: Options) -> Self {
         Self {
             ptr: 0,
-            stack_ptr: 0,
+            stack_ptr: options.sentinel as isize,
             output: Vec::new(),
             variables: HashMap::new(),
             functions: HashMap::new(),
-            string_literals: HashMap::new(),
             free_list: Vec::new(),
+            frames: Vec::new(),
+            call_stack: Vec::new(),
+            warnings: Vec::new(),
+            tape_size: 1,
+            loop_starts: Vec::new(),
+            unbalanced_loop: None,
+            open_loops: 0,
+            unroll_budget: None,
+            inlined_calls: 0,
+            files: Vec::new(),
+            traps: false,
+            options,
         }
     }
 
@@ -59,57 +219,73 @@ impl Compiler {
                 self.set_ptr(index as usize);
             }
 
+            if cfg!(debug_assertions) {
+                self.track_loop_balance(c);
+            }
+            match c {
+                '[' => self.open_loops += 1,
+                ']' => self.open_loops = self.open_loops.saturating_sub(1),
+                _ => (),
+            }
             self.output.push(c);
         }
     }
 
-    /// Allocates space for and adds a string literal to the string_literal hashmap
-    ///
-    /// # Returns
-    /// The index the string literal was stored at
-    fn add_string_literal(&mut self, string: &str) -> Result<usize, String> {
-        let index = self.malloc(string.bytes().len() + 1);
-        self.write_str(index, string);
-        self.string_literals.insert(string.to_string(), index);
-        Ok(index)
-    }
-
-    /// Writes a string as a series of bytes to the brainfuck memory, starting at `index`
+    /// Writes a string as a series of bytes to the brainfuck memory, starting at `index`.
+    /// Bytes that take a loop to build use a scratch cell on top of the stack, never a cell of
+    /// the string itself or of a neighbouring buffer.
     fn write_str(&mut self, index: usize, string: &str) {
         for (i, byte) in string.bytes().enumerate() {
-            self.set_with_gcf(index + i, index + i + 1, byte);
-        }
-        self.set(index + string.bytes().len(), 0);
-    }
-
-    fn print_str_at(&mut self, index: usize) {
-        let mut string_len = None;
-        for (string, i) in &self.string_literals {
-            if *i == index {
-                string_len = Some(string.len() as isize);
-            }
-        }
-        if let Some(len) = string_len {
-            self.set_ptr(index);
-            self.emit("[.>]");
-            self.ptr += len;
+            self.set(index + i, byte);
         }
+        self.set(index + string.len(), 0);
     }
 
+    /// Prints a string literal. Literals are never stored on the tape; each character is built
+    /// up in a temporary cell from the previous one and printed right away, so printing the same
+    /// literal in several places costs no memory.
     fn print_str(&mut self, s: &str) {
         let mut current = 0;
         let tmp = self.calloc(1);
         self.set_ptr(tmp);
         for c in s.chars() {
-            let target = c as usize;
-            let dir = if target > current { "+" } else { "-" };
-            self.emit(&dir.repeat(current.abs_diff(c as usize)));
+            let target = c as u8;
+            self.emit_delta(target as i16 - current as i16);
             self.emit(".");
             current = target;
         }
         self.dealloc(1);
     }
 
+    /// Records where loops open and checks that they close on the same cell, since the body of a
+    /// brainfuck loop must leave the pointer where it found it.
+    fn track_loop_balance(&mut self, c: char) {
+        match c {
+            '[' => self.loop_starts.push(self.ptr),
+            ']' => {
+                let start = self.loop_starts.pop();
+                if start != Some(self.ptr) && self.unbalanced_loop.is_none() {
+                    self.unbalanced_loop = Some(format!(
+                        "Internal error: loop ending at output position {} ends on cell {} but \
+                         started on cell {}",
+                        self.output.len(),
+                        self.ptr,
+                        start.map_or("?".to_string(), |start| start.to_string())
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Fails if any loop emitted so far moved the pointer.
+    fn check_loop_balance(&self) -> Result<(), String> {
+        match &self.unbalanced_loop {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
     /// Allocates `size` cells on the stack and returns the index of the first cell.
     /// The cells are not initialized.
     fn malloc(&mut self, size: usize) -> usize {
@@ -133,25 +309,182 @@ impl Compiler {
         self.stack_ptr -= size as isize;
     }
 
-    fn alloc_var(&mut self, name: &str) -> Result<usize, String> {
+    fn alloc_var(&mut self, name: &str, ty: Type, initialized: bool) -> Result<usize, String> {
         let index = if let Some(idx) = self.free_list.pop() {
             idx
         } else {
             self.calloc(1)
         };
-        match self.variables.insert(name.to_string(), index) {
+        let var = Variable {
+            index,
+            ty,
+            initialized,
+            capacity: 0,
+            read: false,
+            defined_at: None,
+            constant: None,
+        };
+        self.insert_var(name, var)?;
+        Ok(index)
+    }
+
+    /// Allocates a string variable that can hold strings of up to `capacity` characters.
+    ///
+    /// The buffer is surrounded by zeroed cells, so it can be walked to its end and back
+    /// regardless of the length of the string in it.
+    fn alloc_string_var(&mut self, name: &str, capacity: usize) -> Result<usize, String> {
+        // One cell in front of the string and one for the terminating 0
+        let index = self.calloc(capacity + 2) + 1;
+        let var = Variable {
+            index,
+            ty: Type::String,
+            initialized: true,
+            capacity,
+            read: false,
+            defined_at: None,
+            constant: None,
+        };
+        self.insert_var(name, var)?;
+        Ok(index)
+    }
+
+    fn insert_var(&mut self, name: &str, var: Variable) -> Result<(), String> {
+        match self.variables.insert(name.to_string(), var) {
             Some(_) => Err(format!("Variable {} is already defined", name)),
-            None => Ok(index),
+            None => Ok(()),
         }
     }
 
     fn dealloc_var(&mut self, name: &str) {
-        let index = self.variables.remove(name).unwrap();
-        self.free_list.push(index);
+        let var = self.variables.remove(name).unwrap();
+        self.warn_if_unused(name, &var);
+        // String buffers span several cells, so they are only freed with the stack of their block
+        if var.ty != Type::String {
+            self.free_list.push(var.index);
+        }
+    }
+
+    /// Warns about a variable that goes out of scope without its value ever being used. Names
+    /// starting with an underscore mark variables that are meant to be unused.
+    fn warn_if_unused(&mut self, name: &str, var: &Variable) {
+        let Some(position) = var.defined_at else {
+            return;
+        };
+        if var.read || name.starts_with('_') {
+            return;
+        }
+        let warning = position.in_file(
+            &self.files,
+            format!("Variable {} at {} is never used", name, position),
+        );
+        // The body of a do-while loop is compiled twice
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
+    /// Warns about the variables still in scope that were never used.
+    fn warn_unused_globals(&mut self) {
+        let mut globals: Vec<(String, Variable)> = self
+            .variables
+            .iter()
+            .map(|(name, var)| (name.clone(), *var))
+            .collect();
+        globals.sort_by_key(|(_, var)| var.defined_at);
+        for (name, var) in globals {
+            self.warn_if_unused(&name, &var);
+        }
+    }
+
+    fn variable(&self, name: &str) -> Result<Variable, String> {
+        match self.variables.get(name) {
+            Some(var) => Ok(*var),
+            None => Err(format!("Variable {} is not defined", name)),
+        }
+    }
+
+    /// Looks up the cell of a variable that is about to be read and marks it as used.
+    fn read_var(&mut self, name: &str) -> Result<usize, String> {
+        let var = self.variable(name)?;
+        if self.options.check_uninitialized && !var.initialized {
+            return Err(format!(
+                "Variable {} is read before it is assigned a value",
+                name
+            ));
+        }
+        self.variables.get_mut(name).unwrap().read = true;
+        Ok(var.index)
+    }
+
+    /// Looks up the cell of a variable that is about to be assigned and marks it as initialized.
+    fn write_var(&mut self, name: &str) -> Result<usize, String> {
+        match self.variables.get_mut(name) {
+            Some(var) => {
+                var.initialized = true;
+                var.constant = None;
+                Ok(var.index)
+            }
+            None => Err(format!("Variable {} is not defined", name)),
+        }
+    }
+
+    /// Computes the value of `expr` at compile time, if it only depends on number literals and
+    /// variables with known values.
+    fn constant_value(&self, expr: &Expr) -> Option<Constant> {
+        fold_constant(expr, &|name| self.variables.get(name)?.constant)
+    }
+
+    /// Forgets the known values of all variables, for code that may run after the variables
+    /// have been assigned, like the body of a loop.
+    fn forget_constants(&mut self) {
+        for var in self.variables.values_mut() {
+            var.constant = None;
+        }
+    }
+
+    /// Infers the type of the value an expression evaluates to.
+    fn type_of(&self, expr: &Expr) -> Type {
+        use crate::parser::BinaryOp as BO;
+        use crate::parser::Expr as E;
+        use crate::parser::UnaryOp as UO;
+        match expr {
+            E::Char(_) => Type::Char,
+            E::Boolean(_) => Type::Bool,
+            E::Unary { op: UO::Not, .. } => Type::Bool,
+            E::Binary {
+                op:
+                    BO::Eq
+                    | BO::Neq
+                    | BO::Lt
+                    | BO::Leq
+                    | BO::Gt
+                    | BO::Geq
+                    | BO::And
+                    | BO::Or
+                    | BO::LogicalAnd
+                    | BO::LogicalOr,
+                ..
+            } => Type::Bool,
+            E::String(_) => Type::String,
+            E::Binary { .. } if constant_string(expr).is_some() => Type::String,
+            E::Cast { ty, .. } => *ty,
+            E::Identifier(name) | E::Assignment { name, .. } => {
+                self.variables.get(name).map_or(Type::Number, |var| var.ty)
+            }
+            // Offsetting a character yields another character, e.g. 'a' + 1 == 'b'
+            E::Binary {
+                lhs,
+                op: BO::Add | BO::Sub | BO::WrappingAdd | BO::WrappingSub,
+                rhs,
+                ..
+            } if self.type_of(lhs) == Type::Char || self.type_of(rhs) == Type::Char => Type::Char,
+            _ => Type::Number,
+        }
     }
 
     fn move_ptr(&mut self, offset: isize) {
         self.ptr += offset;
+        self.tape_size = self.tape_size.max(self.ptr as usize + 1);
         let dir = if offset > 0 { ">" } else { "<" };
         self.emit(&dir.repeat(offset.unsigned_abs()));
     }
@@ -164,33 +497,36 @@ impl Compiler {
     fn set(&mut self, index: usize, value: u8) {
         self.set_ptr(index);
         self.emit("[-]");
-        self.emit(&"+".repeat(value as usize));
+        self.emit_delta(value as i16);
     }
 
-    fn set_with_gcf(&mut self, index: usize, temp: usize, value: u8) {
-        fn find_factors(n: usize) -> [usize; 2] {
-            let nsqrt = (n as f64).sqrt().floor() as usize;
-            [nsqrt, n - nsqrt * nsqrt]
-        }
-
-        if value < 16 {
-            self.set(index, value);
+    /// Changes the value at the current cell by `delta`, wrapping around, in whichever direction
+    /// is shorter. Large changes are made with a loop adding a factor of the change per
+    /// iteration, which takes far fewer instructions than a `+` per unit.
+    fn emit_delta(&mut self, delta: i16) {
+        let delta = delta.rem_euclid(256);
+        let (dir, magnitude) = if delta <= 128 {
+            ("+", delta as usize)
+        } else {
+            ("-", 256 - delta as usize)
+        };
+        if magnitude < 16 {
+            self.emit(&dir.repeat(magnitude));
             return;
         }
-
-        let [sroot, rest] = find_factors(value as usize);
-
-        self.set_ptr(temp);
-        self.emit(&"+".repeat(sroot));
-        self.emit("[-");
+        let root = (magnitude as f64).sqrt() as usize;
+        let rest = magnitude - root * root;
+        let index = self.ptr as usize;
+        let count = self.calloc(1);
+        self.emit(&"+".repeat(root));
+        self.emit_loop(count, |c| {
+            c.emit("-");
+            c.set_ptr(index);
+            c.emit(&dir.repeat(root));
+        });
         self.set_ptr(index);
-        self.emit(&"+".repeat(sroot));
-        self.set_ptr(temp);
-        self.emit("]");
-        if rest > 0 {
-            self.set_ptr(index);
-            self.emit(&"+".repeat(rest));
-        }
+        self.emit(&dir.repeat(rest));
+        self.dealloc(1);
     }
 
     fn move_val(&mut self, src: usize, dest: usize) {
@@ -204,23 +540,88 @@ impl Compiler {
         }
         let tmp = self.calloc(1);
         // Move value from src to tmp and dest
-        self.set_ptr(src);
-        self.emit("[-");
-        for dest in dests {
-            self.set_ptr(*dest);
-            self.emit("+");
-        }
-        self.set_ptr(tmp);
-        self.emit("+");
-        self.set_ptr(src);
-        self.emit("]");
+        self.emit_loop(src, |c| {
+            c.emit("-");
+            for dest in dests {
+                c.set_ptr(*dest);
+                c.emit("+");
+            }
+            c.set_ptr(tmp);
+            c.emit("+");
+        });
         // Move value back from tmp to src
         self.move_val(tmp, src);
         self.dealloc(1);
     }
 
+    /// Emits a loop that runs while the value at `cond` is nonzero, with the code `body` emits
+    /// inside it. The pointer is moved to `cond` to open the loop and back to it to close it, so
+    /// the loop always ends on the cell it started on.
+    ///
+    /// # Panics
+    /// If the brackets `body` emits are unbalanced, since the loop would then close the wrong
+    /// bracket.
+    fn emit_loop(&mut self, cond: usize, body: impl FnOnce(&mut Self)) {
+        let open_loops = self.open_loop(cond);
+        body(self);
+        self.close_loop(cond, open_loops);
+    }
+
+    /// Emits a loop like [`Compiler::emit_loop`] with a body that may fail to compile.
+    fn try_emit_loop(
+        &mut self,
+        cond: usize,
+        body: impl FnOnce(&mut Self) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let open_loops = self.open_loop(cond);
+        body(self)?;
+        self.close_loop(cond, open_loops);
+        Ok(())
+    }
+
+    /// Opens a loop on `cond` and returns how many loops were open before it.
+    fn open_loop(&mut self, cond: usize) -> usize {
+        let open_loops = self.open_loops;
+        self.set_ptr(cond);
+        self.emit("[");
+        open_loops
+    }
+
+    fn close_loop(&mut self, cond: usize, open_loops: usize) {
+        assert_eq!(
+            self.open_loops,
+            open_loops + 1,
+            "Loop on cell {} has a body with unbalanced brackets",
+            cond
+        );
+        self.set_ptr(cond);
+        self.emit("]");
+    }
+
+    /// Opens code that only runs if the value at `src` is nonzero. The value at `src` is left
+    /// unchanged.
+    ///
+    /// # Returns
+    /// The flag cell that must be passed to `end_if` to close the code.
+    fn begin_if(&mut self, src: usize) -> usize {
+        let flag = self.calloc(1);
+        self.copy_val(src, &[flag]);
+        self.emit(&format!("{0}[[-]", flag));
+        flag
+    }
+
+    fn end_if(&mut self, flag: usize) {
+        self.set_ptr(flag);
+        self.emit("]");
+    }
+
+    /// Negates the value at `rhs`, wrapping around like every other operation on cells, so -1
+    /// is 255 and -x is 256 - x for nonzero x.
     fn neg(&mut self, rhs: usize) {
-        todo!("Negation is not yet supported")
+        let tmp = self.calloc(1);
+        self.dadd(rhs, tmp);
+        self.dsub(tmp, rhs);
+        self.dealloc(1);
     }
 
     fn not(&mut self, rhs: usize) {
@@ -255,12 +656,7 @@ impl Compiler {
     /// Subtracts the value at `src` from the value at `dest` and writes it to `dest`.
     /// The value at `src` is set to 0. (the d stands for destructive)
     fn dsub(&mut self, src: usize, dest: usize) {
-        self.set_ptr(src);
-        self.emit("[-");
-        self.set_ptr(dest);
-        self.emit("-");
-        self.set_ptr(src);
-        self.emit("]");
+        self.emit_loop(src, |c| c.emit(&format!("-{}-", dest)));
     }
 
     fn sub(&mut self, src: usize, dest: usize) {
@@ -276,87 +672,332 @@ impl Compiler {
         let count = self.calloc(1);
         self.copy_val(dest, &[count]);
         self.set(dest, 0);
-        self.set_ptr(count);
-        self.emit("[-");
-        self.add(src, dest);
-        self.set_ptr(count);
-        self.emit("]");
+        self.emit_loop(count, |c| {
+            c.emit("-");
+            c.add(src, dest);
+        });
+        self.dealloc(1);
+    }
+
+    /// Divides the value at `n` by the value at `d`, writing the quotient to `q` and the
+    /// remainder to `r`. The value at `n` is set to 0 and the value at `d` is left unchanged.
+    /// The values at `q` and `r` are assumed to be zeroed.
+    fn divmod(&mut self, n: usize, d: usize, q: usize, r: usize) {
+        let is_multiple = self.calloc(1);
+        self.emit_loop(n, |c| {
+            c.emit(&format!("-{}+", r));
+            // Once the remainder reaches the divisor, wrap it around and bump the quotient
+            c.copy_val(r, &[is_multiple]);
+            c.sub(d, is_multiple);
+            c.not(is_multiple);
+            c.emit(&format!("{0}[[-]{1}[-]{2}+{0}]", is_multiple, r, q));
+        });
         self.dealloc(1);
     }
 
+    /// Prints the value at `src` as a decimal number without leading zeros. The ones digit is
+    /// always printed, so 0 prints as `0`. The value at `src` is left unchanged.
+    fn print_number(&mut self, src: usize) {
+        let n = self.calloc(1);
+        let ten = self.calloc(1);
+        let tens_and_up = self.calloc(1);
+        let ones = self.calloc(1);
+        let hundreds = self.calloc(1);
+        let tens = self.calloc(1);
+        let has_tens = self.calloc(1);
+        self.copy_val(src, &[n]);
+        self.set(ten, 10);
+        self.divmod(n, ten, tens_and_up, ones);
+        self.divmod(tens_and_up, ten, hundreds, tens);
+        // The tens digit is printed if it or the hundreds digit is nonzero
+        self.copy_val(hundreds, &[has_tens]);
+        self.add(tens, has_tens);
+        let zero = b'0' as i16;
+        self.emit(&format!("{}[", hundreds));
+        self.emit_delta(zero);
+        self.emit(".[-]]");
+        self.emit(&format!("{0}[[-]{1}", has_tens, tens));
+        self.emit_delta(zero);
+        self.emit(&format!(".[-]{}]", has_tens));
+        self.set_ptr(ones);
+        self.emit_delta(zero);
+        self.emit(".[-]");
+        self.dealloc(7);
+    }
+
+    /// Prints the value at `src` as a number in the base chosen by the options. The value at
+    /// `src` is left unchanged.
+    fn print_numeric(&mut self, src: usize) {
+        match self.options.print_radix {
+            Radix::Decimal => self.print_number(src),
+            Radix::Hex => self.print_hex(src),
+        }
+    }
+
+    /// Prints the value at `src` as two lowercase hexadecimal digits, so 10 prints as `0a`. The
+    /// value at `src` is left unchanged.
+    fn print_hex(&mut self, src: usize) {
+        let n = self.calloc(1);
+        let sixteen = self.calloc(1);
+        let high = self.calloc(1);
+        let low = self.calloc(1);
+        self.copy_val(src, &[n]);
+        self.set(sixteen, 16);
+        self.divmod(n, sixteen, high, low);
+        self.print_hex_digit(high);
+        self.print_hex_digit(low);
+        self.dealloc(4);
+    }
+
+    /// Prints the value at `digit`, which must be less than 16, as a hexadecimal digit. The value
+    /// at `digit` is set to 0.
+    fn print_hex_digit(&mut self, digit: usize) {
+        let is_letter = self.calloc(1);
+        let nine = self.calloc(1);
+        self.copy_val(digit, &[is_letter]);
+        self.set(nine, 9);
+        self.greater(nine, is_letter);
+        // Digits from 10 and up skip the characters between '9' and 'a'
+        self.emit(&format!("{0}[[-]{1}", is_letter, digit));
+        self.emit_delta((b'a' - b'0' - 10) as i16);
+        self.emit(&format!("{}]", is_letter));
+        self.set_ptr(digit);
+        self.emit_delta(b'0' as i16);
+        self.emit(".[-]");
+        self.dealloc(2);
+    }
+
+    /// Raises the value at `base` to the power of the value at `exp` and writes it to `dest`,
+    /// wrapping on overflow. The value at `exp` is set to 0 and the value at `base` is left
+    /// unchanged. The value at `dest` is assumed to be zeroed.
+    fn pow(&mut self, base: usize, exp: usize, dest: usize) {
+        self.set(dest, 1);
+        self.emit_loop(exp, |c| {
+            c.emit("-");
+            c.mul(base, dest);
+        });
+    }
+
+    /// Prints `true` if the value at `src` is nonzero and `false` otherwise. The value at `src`
+    /// is set to 0.
+    fn print_bool(&mut self, src: usize) {
+        let is_false = self.malloc(1);
+        self.set(is_false, 1);
+        self.emit(&format!("{0}[[-]{1}-", src, is_false));
+        self.print_str("true");
+        self.emit(&format!("{0}]{1}[-", src, is_false));
+        self.print_str("false");
+        self.emit(&format!("{0}]", is_false));
+        self.dealloc(1);
+    }
+
+    /// Divides the value at `dest` by the value at `src` and writes the quotient to `dest`.
+    /// The value at `src` is left unchanged. Dividing by 0 gives 0.
     fn div(&mut self, src: usize, dest: usize) {
-        todo!("Division is not yet supported")
+        let n = self.calloc(1);
+        let q = self.calloc(1);
+        let r = self.calloc(1);
+        self.move_val(dest, n);
+        self.divmod(n, src, q, r);
+        self.dadd(q, dest);
+        self.dealloc(3);
     }
 
+    /// Divides the value at `dest` by the value at `src` and writes the remainder to `dest`.
+    /// The value at `src` is left unchanged. The remainder of dividing by 0 is the dividend.
     fn modulo(&mut self, src: usize, dest: usize) {
-        todo!("Modulo is not yet supported")
+        let n = self.calloc(1);
+        let q = self.calloc(1);
+        let r = self.calloc(1);
+        self.move_val(dest, n);
+        self.divmod(n, src, q, r);
+        self.dadd(r, dest);
+        self.dealloc(3);
     }
 
     fn eq(&mut self, src: usize, dest: usize) {
-        self.neq(src, dest);
+        self.dsub(src, dest);
         self.not(dest);
     }
 
     fn neq(&mut self, src: usize, dest: usize) {
         self.dsub(src, dest);
+        self.normalize(dest);
+    }
+
+    /// Subtracts the value at `src` from the value at `dest`, stopping at 0 instead of wrapping
+    /// around. The value at `src` is left unchanged.
+    fn saturating_sub(&mut self, src: usize, dest: usize) {
+        let count = self.calloc(1);
+        self.copy_val(src, &[count]);
+        self.emit_loop(count, |c| {
+            c.emit("-");
+            let flag = c.begin_if(dest);
+            c.emit(&format!("{}-", dest));
+            c.end_if(flag);
+            c.dealloc(1);
+        });
+        self.dealloc(1);
+    }
+
+    /// Adds the value at `src` to the value at `dest`, stopping at 255 instead of wrapping around.
+    /// The value at `src` is left unchanged.
+    fn saturating_add(&mut self, src: usize, dest: usize) {
+        // How much can still be added before reaching 255
+        let room = self.calloc(1);
+        self.emit(&format!("{}-", room));
+        self.sub(dest, room);
+        let count = self.calloc(1);
+        self.copy_val(src, &[count]);
+        self.emit_loop(count, |c| {
+            c.emit("-");
+            let flag = c.begin_if(room);
+            c.emit(&format!("{}-{}+", room, dest));
+            c.end_if(flag);
+            c.dealloc(1);
+        });
+        self.set(room, 0);
+        self.dealloc(2);
+    }
+
+    /// Sets the value at `dest` to 1 if it is nonzero.
+    fn normalize(&mut self, dest: usize) {
+        let tmp = self.calloc(1);
+        self.emit(&format!("{0}[[-]{1}+{0}]", dest, tmp));
+        self.move_val(tmp, dest);
+        self.dealloc(1);
+    }
+
+    /// Writes a value to `dest` that is nonzero if the value at `dest` is greater than the value
+    /// at `src`. The value at `src` is left unchanged.
+    fn greater(&mut self, src: usize, dest: usize) {
+        self.saturating_sub(src, dest);
+    }
+
+    /// Writes a value to `dest` that is nonzero if the value at `dest` is less than the value at
+    /// `src`. The value at `src` is left unchanged.
+    fn less(&mut self, src: usize, dest: usize) {
+        let lhs = self.calloc(1);
+        self.move_val(dest, lhs);
+        self.copy_val(src, &[dest]);
+        self.saturating_sub(lhs, dest);
+        self.dealloc(1);
     }
 
     fn lt(&mut self, src: usize, dest: usize) {
-        todo!("Less than is not yet supported")
+        self.less(src, dest);
+        self.normalize(dest);
     }
 
     fn leq(&mut self, src: usize, dest: usize) {
-        todo!("Less than or equal is not yet supported")
+        self.greater(src, dest);
+        self.not(dest);
     }
 
     fn gt(&mut self, src: usize, dest: usize) {
-        todo!("Greater than is not yet supported")
+        self.greater(src, dest);
+        self.normalize(dest);
     }
 
     fn geq(&mut self, src: usize, dest: usize) {
-        todo!("Greater than or equal is not yet supported")
+        self.less(src, dest);
+        self.not(dest);
+    }
+
+    /// Raises the value at `dest` to the value at `lo` if it is less. The value at `lo` is left
+    /// unchanged.
+    fn clamp_min(&mut self, lo: usize, dest: usize) {
+        let below = self.calloc(1);
+        self.copy_val(dest, &[below]);
+        self.less(lo, below);
+        let flag = self.begin_if(below);
+        self.copy_val(lo, &[dest]);
+        self.end_if(flag);
+        self.dealloc(2);
+    }
+
+    /// Lowers the value at `dest` to the value at `hi` if it is greater. The value at `hi` is
+    /// left unchanged.
+    fn clamp_max(&mut self, hi: usize, dest: usize) {
+        let above = self.calloc(1);
+        self.copy_val(dest, &[above]);
+        self.greater(hi, above);
+        let flag = self.begin_if(above);
+        self.copy_val(hi, &[dest]);
+        self.end_if(flag);
+        self.dealloc(2);
+    }
+
+    /// Bounds the value at `dest` to be between the values at `lo` and `hi`, inclusive. If `lo`
+    /// is greater than `hi` the result is `hi`. The values at `lo` and `hi` are left unchanged.
+    fn clamp_cell(&mut self, lo: usize, hi: usize, dest: usize) {
+        self.clamp_min(lo, dest);
+        self.clamp_max(hi, dest);
+    }
+
+    /// Evaluates `lhs && rhs` or `lhs || rhs` into `dest` as 0 or 1. The right operand is only
+    /// evaluated if the left one doesn't decide the result, so its side effects don't happen
+    /// otherwise.
+    fn short_circuit(
+        &mut self,
+        lhs: &Expr,
+        op: &BinaryOp,
+        rhs: &Expr,
+        dest: usize,
+    ) -> Result<(), String> {
+        self.evaluate_expression(lhs, dest)?;
+        self.normalize(dest);
+        // Whether the right operand decides the result
+        let undecided = self.calloc(1);
+        self.copy_val(dest, &[undecided]);
+        if let BinaryOp::LogicalOr = op {
+            self.not(undecided);
+        }
+        let flag = self.begin_if(undecided);
+        self.set(dest, 0);
+        self.evaluate_expression(rhs, dest)?;
+        self.normalize(dest);
+        self.end_if(flag);
+        self.dealloc(2);
+        Ok(())
     }
 
+    /// Writes 1 to `dest` if the values at `dest` and `src` are both nonzero, and 0 otherwise.
+    /// Unlike `&&`, both operands have already been evaluated. The value at `src` is normalized.
     fn and(&mut self, src: usize, dest: usize) {
-        todo!("Logical and is not yet supported")
+        self.normalize(dest);
+        self.normalize(src);
+        self.mul(src, dest);
     }
 
+    /// Writes 1 to `dest` if the value at `dest` or `src` is nonzero, and 0 otherwise. Unlike
+    /// `||`, both operands have already been evaluated. The value at `src` is set to 0.
     fn or(&mut self, src: usize, dest: usize) {
-        todo!("Logical or is not yet supported")
+        // Normalizing first keeps the sum from wrapping around to 0
+        self.normalize(dest);
+        self.normalize(src);
+        self.dadd(src, dest);
+        self.normalize(dest);
     }
 
     fn compile(&mut self, statements: &[Statement]) -> Result<(), String> {
-        // // First allocate space for all string literals
-        // for stmt in statements {
-        //     use crate::parser::Statement as S;
-        //     match stmt {
-        //         S::VariableDefinition { name, initializer } => {
-        //             if let Some(Expr::String(s)) = initializer {
-        //                 if !self.string_literals.contains_key(s) {
-        //                     self.add_string_literal(s)?;
-        //                 }
-        //             }
-        //         }
-
-        //         S::Assignment {
-        //             name,
-        //             value: Expr::String(s),
-        //         } => {
-        //             if !self.string_literals.contains_key(s) {
-        //                 self.add_string_literal(s)?;
-        //             }
-        //         }
-        //         S::Return(_) => todo!("Return statements not implemented"),
-        //         S::Print(Expr::String(s)) => {
-        //             if !self.string_literals.contains_key(s) {
-        //                 self.add_string_literal(s)?;
-        //             }
-        //         }
-        //         _ => continue,
-        //     }
-        // }
-        for stmt in statements {
+        for (i, stmt) in statements.iter().enumerate() {
+            if self.options.explain {
+                self.comment(&describe(stmt));
+            }
             self.evaluate_statement(stmt)?;
+            self.check_loop_balance()?;
+            // After a statement that may leave the loop or function, the rest of its body only
+            // runs while it is still running
+            if let Some(running) = self.frames.last().and_then(|frame| frame.running) {
+                if exits(stmt) && i + 1 < statements.len() {
+                    // The flag stays allocated until the enclosing block frees its cells
+                    let flag = self.begin_if(running);
+                    self.compile(&statements[i + 1..])?;
+                    self.end_if(flag);
+                    return Ok(());
+                }
+            }
         }
         Ok(())
     }
@@ -367,26 +1008,54 @@ impl Compiler {
             S::FunctionDefinition { name, params, body } => {
                 self.function_declaration(name, params, body)?
             }
-            S::VariableDefinition { name, initializer } => {
-                self.variable_definition(name, initializer.as_ref())?
+            S::VariableDefinition {
+                name,
+                ty,
+                initializer,
+                position,
+            } => {
+                self.variable_definition(name, *ty, initializer.as_ref())?;
+                self.variables.get_mut(name).unwrap().defined_at = Some(*position);
+            }
+            S::Return(value) => self.return_statement(value.as_ref())?,
+            S::Print { format, args } => self.print_format(format, args)?,
+            S::Expression(expr) => {
+                let result = self.calloc(1);
+                self.evaluate_expression(expr, result)?;
+                self.dealloc(1);
             }
-            S::Return(_) => todo!("Return statements are not yet supported"),
-            S::Print(expr) => match expr {
-                Expr::String(s) => {
-                    self.print_str(s);
-                }
-                _ => {
-                    todo!("Print statements that doesn't use string literals are not yet supported")
-                }
-            },
             S::Block(block_statements) => self.block(block_statements)?,
             S::If {
                 condition,
                 then_branch,
                 else_branch,
             } => self.if_statement(condition, then_branch, else_branch.as_deref())?,
-            S::While { condition, body } => self.while_statement(condition, body)?,
-            S::Assignment { name, value } => self.assignment(name, value)?,
+            S::While { condition, body } => self.while_statement(condition, body, false)?,
+            S::DoWhile { body, condition } => self.while_statement(condition, body, true)?,
+            S::Break => match self.find_frame(|kind| *kind == FrameKind::Loop) {
+                Some(depth) => self.leave_frames(depth),
+                None => return Err("Break statement outside of a loop".to_string()),
+            },
+            S::LabeledBlock { label, body } => self.labeled_block(label, body)?,
+            S::Match {
+                value,
+                arms,
+                default,
+            } => self.match_statement(value, arms, default.as_deref())?,
+            S::Exit(label) => {
+                let target =
+                    |kind: &FrameKind| matches!(kind, FrameKind::Block { label: l } if l == label);
+                match self.find_frame(target) {
+                    Some(depth) => self.leave_frames(depth),
+                    None => {
+                        return Err(format!(
+                            "Exit from {}, which is not an enclosing block",
+                            label
+                        ))
+                    }
+                }
+            }
+            S::Assignment { name, value, .. } => self.assignment(name, value)?,
         }
         Ok(())
     }
@@ -397,33 +1066,224 @@ impl Compiler {
         params: &[String],
         body: &Statement,
     ) -> Result<(), String> {
-        todo!("Function declarations are not yet supported")
+        let function = Function {
+            params: params.to_vec(),
+            body: Rc::new(body.clone()),
+        };
+        match self.functions.insert(name.to_string(), function) {
+            Some(_) => Err(format!("Function {} is already defined", name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Finds the innermost frame of the current function call matching `predicate`.
+    ///
+    /// # Returns
+    /// The index of the frame in `self.frames`
+    fn find_frame(&self, predicate: impl Fn(&FrameKind) -> bool) -> Option<usize> {
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            if predicate(&frame.kind) {
+                return Some(depth);
+            }
+            if matches!(frame.kind, FrameKind::Call { .. }) {
+                break;
+            }
+        }
+        None
+    }
+
+    /// Stops the frame at `depth` and every frame inside it.
+    fn leave_frames(&mut self, depth: usize) {
+        for i in depth..self.frames.len() {
+            if let Some(running) = self.frames[i].running {
+                self.set(running, 0);
+            }
+        }
+    }
+
+    /// Writes the return value to the result cell of the innermost function call and stops
+    /// everything up to and including the call.
+    fn return_statement(&mut self, value: Option<&Expr>) -> Result<(), String> {
+        let Some(depth) = self.find_frame(|kind| matches!(kind, FrameKind::Call { .. })) else {
+            return Err("Return statement outside of a function".to_string());
+        };
+        if let Some(value) = value {
+            let FrameKind::Call { result } = self.frames[depth].kind else {
+                unreachable!()
+            };
+            let tmp = self.calloc(1);
+            self.evaluate_expression(value, tmp)?;
+            self.move_val(tmp, result);
+            self.dealloc(1);
+        }
+        self.leave_frames(depth);
+        Ok(())
+    }
+
+    /// Compiles a block that can be left early with `exit label;`.
+    fn labeled_block(&mut self, label: &str, body: &[Statement]) -> Result<(), String> {
+        let running = if body.iter().any(exits) {
+            let running = self.calloc(1);
+            self.set(running, 1);
+            Some(running)
+        } else {
+            None
+        };
+        self.frames.push(Frame {
+            running,
+            kind: FrameKind::Block {
+                label: label.to_string(),
+            },
+        });
+        let result = self.block(body);
+        self.frames.pop();
+        result?;
+        self.dealloc(running.map_or(0, |_| 1));
+        Ok(())
     }
 
     fn variable_definition(
         &mut self,
         name: &str,
+        ty: Option<Type>,
         initializer: Option<&Expr>,
     ) -> Result<(), String> {
-        let index = self.alloc_var(name)?;
+        let ty = ty.unwrap_or_else(|| initializer.map_or(Type::Number, |init| self.type_of(init)));
+        if ty == Type::String {
+            return self.string_definition(name, initializer);
+        }
+        let index = self.alloc_var(name, ty, initializer.is_some())?;
         if let Some(init) = initializer {
+            let constant = self.constant_value(init).map(|constant| constant.value);
             let expr_index = self.calloc(1);
             self.evaluate_expression(init, expr_index)?;
             self.move_val(expr_index, index);
             self.dealloc(1);
+            self.variables.get_mut(name).unwrap().constant = constant;
         }
         Ok(())
     }
 
-    fn assignment(&mut self, name: &str, value: &Expr) -> Result<(), String> {
-        let var = match self.variables.get(name) {
-            Some(index) => *index,
-            None => return Err(format!("Variable {} is not defined", name)),
+    /// Defines a string variable. Strings have value semantics: initializing a string from
+    /// another string variable copies its characters into a buffer of its own.
+    fn string_definition(&mut self, name: &str, initializer: Option<&Expr>) -> Result<(), String> {
+        let folded = initializer.and_then(constant_string).map(Expr::String);
+        let initializer = folded.as_ref().or(initializer);
+        let capacity = match initializer {
+            Some(Expr::String(s)) => s.len(),
+            Some(Expr::Identifier(src)) => self.variable(src)?.capacity,
+            Some(_) => {
+                return Err(format!(
+                    "Variable {} must be initialized with a string",
+                    name
+                ))
+            }
+            None => return Err(format!("String variable {} must be initialized", name)),
         };
+        self.alloc_string_var(name, capacity)?;
+        self.string_assignment(name, initializer.unwrap())
+    }
+
+    /// Writes a string literal or the string in another variable into a string variable.
+    fn string_assignment(&mut self, name: &str, value: &Expr) -> Result<(), String> {
+        let folded = constant_string(value).map(Expr::String);
+        let value = folded.as_ref().unwrap_or(value);
+        let dest = self.write_var(name)?;
+        let capacity = self.variable(name)?.capacity;
+        match value {
+            Expr::String(s) => {
+                if s.len() > capacity {
+                    return Err(format!(
+                        "Variable {} holds at most {} characters, found a string of {}",
+                        name,
+                        capacity,
+                        s.len()
+                    ));
+                }
+                self.write_str(dest, s);
+            }
+            Expr::Identifier(src_name) if self.type_of(value) == Type::String => {
+                self.read_var(src_name)?;
+                let src = self.variable(src_name)?;
+                if src.capacity > capacity {
+                    return Err(format!(
+                        "Variable {} holds at most {} characters, but {} may hold {}",
+                        name, capacity, src_name, src.capacity
+                    ));
+                }
+                // Copying the whole buffer is sure to include the terminating 0
+                for i in 0..=src.capacity {
+                    self.copy_val(src.index + i, &[dest + i]);
+                }
+            }
+            _ => return Err(format!("Only strings can be assigned to variable {}", name)),
+        }
+        Ok(())
+    }
+
+    /// Prints the string in the buffer starting at `index`, which must be surrounded by zeroed
+    /// cells.
+    fn print_buffer(&mut self, index: usize) {
+        self.set_ptr(index);
+        // Walk to the terminating 0 printing each character, then back to the cell in front
+        self.emit("[.>]<[<]>");
+    }
+
+    fn print_format(&mut self, format: &[FormatPiece], args: &[Expr]) -> Result<(), String> {
+        let mut args = args.iter();
+        for piece in format {
+            match piece {
+                FormatPiece::Text(text) => self.print_str(text),
+                FormatPiece::Placeholder => {
+                    let arg = args.next().ok_or("Missing argument for placeholder")?;
+                    self.print(arg)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn print(&mut self, expr: &Expr) -> Result<(), String> {
+        if let Some(s) = constant_string(expr) {
+            self.print_str(&s);
+            return Ok(());
+        }
+        let ty = self.type_of(expr);
+        if ty == Type::String {
+            let Expr::Identifier(name) = expr else {
+                return Err(
+                    "Only string literals and variables can be printed as strings".to_string(),
+                );
+            };
+            let index = self.read_var(name)?;
+            self.print_buffer(index);
+            return Ok(());
+        }
+        let value = self.calloc(1);
+        self.evaluate_expression(expr, value)?;
+        match ty {
+            Type::Char => self.emit(&format!("{}.", value)),
+            Type::Number => self.print_numeric(value),
+            Type::Bool => self.print_bool(value),
+            Type::String => unreachable!(),
+        }
+        self.dealloc(1);
+        Ok(())
+    }
+
+    fn assignment(&mut self, name: &str, value: &Expr) -> Result<(), String> {
+        if self.variable(name)?.ty == Type::String {
+            return self.string_assignment(name, value);
+        }
+        let constant = self.constant_value(value).map(|constant| constant.value);
         let expr = self.calloc(1);
         let expr = self.evaluate_expression(value, expr)?;
+        let var = self.write_var(name)?;
         self.move_val(expr, var);
         self.dealloc(1);
+        if self.open_loops == 0 {
+            self.variables.get_mut(name).unwrap().constant = constant;
+        }
         Ok(())
     }
 
@@ -439,11 +1299,16 @@ impl Compiler {
                 _ => continue,
             }
         }
+        let stack_ptr = self.stack_ptr;
         self.compile(statements)?;
         // Deallocate all variables defined in this block
         for name in varnames {
             self.dealloc_var(name);
         }
+        // Variables allocated on the stack inside the block are freed with it, so they must not be
+        // handed out again from the free list
+        self.free_list.retain(|&index| (index as isize) < stack_ptr);
+        self.stack_ptr = stack_ptr;
         Ok(())
     }
 
@@ -456,22 +1321,246 @@ impl Compiler {
         let cond = self.calloc(1);
         let one = self.malloc(1);
         self.set(one, 1);
-        self.evaluate_expression(condition, cond)?;
-        self.emit(&format!("{0}[[-]{1}-{0}", cond, one));
-        self.evaluate_statement(then_branch)?;
-        self.set_ptr(cond);
-        if let Some(branch) = else_branch {
-            self.emit(&format!("]{0}[", one));
-            self.evaluate_statement(branch)?;
-            self.set_ptr(cond);
-        }
-        self.emit("]");
+        // An inverted condition runs the else branch when the value is nonzero instead
+        let (first, second) = if self.condition(condition, cond)? {
+            (else_branch, Some(then_branch))
+        } else {
+            (Some(then_branch), else_branch)
+        };
+        self.try_emit_loop(cond, |c| {
+            c.emit(&format!("[-]{1}-{0}", cond, one));
+            match first {
+                Some(branch) => c.evaluate_statement(branch),
+                None => Ok(()),
+            }
+        })?;
+        if let Some(branch) = second {
+            // The else loop must start and end on the same cell, since the pointer stays on
+            // `one` when the loop is skipped
+            self.try_emit_loop(one, |c| {
+                c.emit("-");
+                c.evaluate_statement(branch)
+            })?;
+        }
         self.dealloc(2);
         Ok(())
     }
 
-    fn while_statement(&mut self, condition: &Expr, body: &Statement) -> Result<(), String> {
-        todo!("While statements are not yet supported")
+    /// Compiles a match statement as a chain of if statements comparing the value with each
+    /// pattern. The value is evaluated once, into a variable the source code can't name.
+    fn match_statement(
+        &mut self,
+        value: &Expr,
+        arms: &[MatchArm],
+        default: Option<&Statement>,
+    ) -> Result<(), String> {
+        let name = (0..)
+            .map(|i| format!("match value {}", i))
+            .find(|name| !self.variables.contains_key(name))
+            .unwrap();
+        self.variable_definition(&name, None, Some(value))?;
+        // The variable can't be named, so it must not be reported as unused
+        self.variables.get_mut(&name).unwrap().read = true;
+        let chain = arms
+            .iter()
+            .rev()
+            .fold(default.cloned(), |else_branch, arm| {
+                Some(Statement::If {
+                    condition: Expr::Binary {
+                        lhs: Box::new(Expr::Identifier(name.clone())),
+                        op: BinaryOp::Eq,
+                        rhs: Box::new(arm.pattern.clone()),
+                        position: arm.position,
+                    },
+                    then_branch: Box::new(arm.body.clone()),
+                    else_branch: else_branch.map(Box::new),
+                })
+            });
+        if let Some(chain) = chain {
+            self.evaluate_statement(&chain)?;
+        }
+        self.dealloc_var(&name);
+        Ok(())
+    }
+
+    /// Compiles a while loop, or a do-while loop if `run_first` is set.
+    fn while_statement(
+        &mut self,
+        condition: &Expr,
+        body: &Statement,
+        run_first: bool,
+    ) -> Result<(), String> {
+        if self.unroll_loop(condition, body, run_first)? {
+            return Ok(());
+        }
+        let can_break = exits(body);
+        if !can_break && is_constant_true(condition) {
+            self.warnings.push(
+                "Loop condition is always true and the loop has no break, so it never terminates"
+                    .to_string(),
+            );
+        }
+        let running = if can_break {
+            let running = self.calloc(1);
+            self.set(running, 1);
+            Some(running)
+        } else {
+            None
+        };
+        self.frames.push(Frame {
+            running,
+            kind: FrameKind::Loop,
+        });
+        let cond = self.calloc(1);
+        if run_first {
+            self.evaluate_statement(body)?;
+        }
+        self.loop_condition(condition, cond, running)?;
+        // The body and condition may run after any assignment in the loop
+        self.forget_constants();
+        self.try_emit_loop(cond, |c| {
+            c.evaluate_statement(body)?;
+            // Re-evaluate the condition at the end of each iteration
+            c.set(cond, 0);
+            c.loop_condition(condition, cond, running)
+        })?;
+        self.frames.pop();
+        self.dealloc(if can_break { 2 } else { 1 });
+        Ok(())
+    }
+
+    /// Compiles a loop as one copy of the body per iteration, if the known values of variables
+    /// tell when the condition turns false within [`MAX_UNROLLED_ITERATIONS`] iterations. This
+    /// saves evaluating the condition at runtime and keeps the values known in the body. Loops
+    /// nested in it share a budget of [`MAX_UNROLLED_COPIES`] copies with it, failed attempts
+    /// included.
+    ///
+    /// # Returns
+    /// Whether the loop was unrolled. If not, the compiler is left as it was.
+    fn unroll_loop(
+        &mut self,
+        condition: &Expr,
+        body: &Statement,
+        run_first: bool,
+    ) -> Result<bool, String> {
+        let outer_budget = self.unroll_budget;
+        if exits(body)
+            || outer_budget == Some(0)
+            || (!run_first && self.constant_value(condition).is_none())
+        {
+            return Ok(false);
+        }
+        // The code emitted so far stays as it is, so it is left out of the snapshot
+        let output = std::mem::take(&mut self.output);
+        let snapshot = self.clone();
+        self.output = output;
+        let output_len = self.output.len();
+        self.unroll_budget = Some(outer_budget.unwrap_or(MAX_UNROLLED_COPIES));
+        let first_warning = self.warnings.len();
+        if run_first {
+            self.unrolled_copy(body)?;
+        }
+        for _ in 0..MAX_UNROLLED_ITERATIONS {
+            let Some(constant) = self.constant_value(condition) else {
+                break;
+            };
+            // The condition is still read, if only at compile time
+            for name in &constant.variables {
+                self.read_var(name)?;
+            }
+            if constant.value == 0 {
+                // Every copy of the body finds the same problems
+                let mut seen = HashSet::new();
+                let warnings = self.warnings.split_off(first_warning);
+                self.warnings
+                    .extend(warnings.into_iter().filter(|w| seen.insert(w.clone())));
+                if outer_budget.is_none() {
+                    self.unroll_budget = None;
+                }
+                return Ok(true);
+            }
+            if self.unroll_budget == Some(0) {
+                break;
+            }
+            self.unrolled_copy(body)?;
+        }
+        // Copies compiled by the failed attempt still count against the enclosing loops
+        let budget = self.unroll_budget;
+        let output = std::mem::take(&mut self.output);
+        *self = snapshot;
+        self.output = output;
+        self.output.truncate(output_len);
+        if outer_budget.is_some() {
+            self.unroll_budget = budget;
+        }
+        Ok(false)
+    }
+
+    /// Compiles one copy of the body of a loop being unrolled, using up one copy of the budget.
+    fn unrolled_copy(&mut self, body: &Statement) -> Result<(), String> {
+        self.unroll_budget = self.unroll_budget.map(|budget| budget.saturating_sub(1));
+        self.evaluate_statement(body)
+    }
+
+    /// Evaluates a loop condition into `dest`. Once the loop has been broken out of, the
+    /// condition is not evaluated and `dest` is left at 0.
+    fn loop_condition(
+        &mut self,
+        condition: &Expr,
+        dest: usize,
+        running: Option<usize>,
+    ) -> Result<(), String> {
+        match running {
+            Some(running) => {
+                let flag = self.begin_if(running);
+                self.evaluate_condition(condition, dest)?;
+                self.end_if(flag);
+                self.dealloc(1);
+            }
+            None => {
+                self.evaluate_condition(condition, dest)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates a condition into `dest`, which is assumed to be zeroed, leaving a value that is
+    /// nonzero exactly when the condition holds.
+    fn evaluate_condition(&mut self, condition: &Expr, dest: usize) -> Result<(), String> {
+        if self.condition(condition, dest)? {
+            self.not(dest);
+        }
+        Ok(())
+    }
+
+    /// Evaluates a condition into `dest`, which is assumed to be zeroed. Branching only tells
+    /// zero from nonzero, so comparisons are left unnormalized, and `==`, `<=` and `>=` are
+    /// evaluated as their opposites to save negating them.
+    ///
+    /// # Returns
+    /// Whether the value at `dest` is nonzero when the condition is false rather than true
+    fn condition(&mut self, condition: &Expr, dest: usize) -> Result<bool, String> {
+        use crate::parser::BinaryOp as BO;
+        let Expr::Binary {
+            lhs: lhs_expr,
+            op: op @ (BO::Eq | BO::Neq | BO::Lt | BO::Leq | BO::Gt | BO::Geq),
+            rhs: rhs_expr,
+            ..
+        } = condition
+        else {
+            self.evaluate_expression(condition, dest)?;
+            return Ok(false);
+        };
+        let rhs = self.calloc(1);
+        self.evaluate_expression(lhs_expr, dest)?;
+        self.evaluate_expression(rhs_expr, rhs)?;
+        match op {
+            BO::Eq | BO::Neq => self.dsub(rhs, dest),
+            BO::Lt | BO::Geq => self.less(rhs, dest),
+            _ => self.greater(rhs, dest),
+        }
+        self.dealloc(1);
+        Ok(matches!(op, BO::Eq | BO::Leq | BO::Geq))
     }
 
     /// Evaluates an expression and writes the output to `dest`.
@@ -480,64 +1569,2137 @@ impl Compiler {
         use crate::parser::BinaryOp as BO;
         use crate::parser::Expr as E;
         use crate::parser::UnaryOp as UO;
+        if let (E::Unary { .. } | E::Binary { .. } | E::Identifier(_), Some(constant)) =
+            (expr, self.constant_value(expr))
+        {
+            for name in &constant.variables {
+                self.read_var(name)?;
+            }
+            // Arithmetic on variables wrapping around is usually intended, unless a maximum
+            // value is asked for
+            let max = self.options.max_cell_value;
+            let warning = if constant.overflowed && (constant.variables.is_empty() || max.is_some())
+            {
+                Some(format!(
+                    "Constant expression {} overflows u8 and wraps around to {}",
+                    constant.source, constant.value
+                ))
+            } else {
+                max.filter(|max| constant.peak > *max as u16).map(|max| {
+                    format!(
+                        "Constant expression {} reaches {}, above the maximum cell value {}",
+                        constant.source, constant.peak, max
+                    )
+                })
+            };
+            if let Some(warning) = warning {
+                if !self.warnings.contains(&warning) {
+                    self.warnings.push(warning);
+                }
+            }
+            self.set(dest, constant.value);
+            return Ok(dest);
+        }
         match expr {
             E::Unary { op, rhs: rhs_expr } => {
                 self.evaluate_expression(rhs_expr, dest)?;
                 match op {
                     UO::Neg => self.neg(dest),
-                    UO::Not => self.not(dest),
+                    UO::Not => {
+                        self.not(dest);
+                        self.check_bool(dest);
+                    }
                 }
             }
+            E::Binary {
+                lhs: lhs_expr,
+                op: op @ (BO::LogicalAnd | BO::LogicalOr),
+                rhs: rhs_expr,
+                ..
+            } => {
+                self.short_circuit(lhs_expr, op, rhs_expr, dest)?;
+                self.check_bool(dest);
+            }
             E::Binary {
                 lhs: lhs_expr,
                 op,
                 rhs: rhs_expr,
+                position,
             } => {
-                let lhs = self.calloc(1);
+                if matches!(op, BO::Div | BO::Mod)
+                    && self.constant_value(rhs_expr).is_some_and(|c| c.value == 0)
+                {
+                    return Err(position.in_file(
+                        &self.files,
+                        format!("Division by zero in {} at {}", expr, position),
+                    ));
+                }
+                // Every operator takes its left operand in `dest`, so it is evaluated there
                 let rhs = self.calloc(1);
-                self.evaluate_expression(lhs_expr, lhs)?;
+                self.evaluate_expression(lhs_expr, dest)?;
                 self.evaluate_expression(rhs_expr, rhs)?;
-                self.dadd(lhs, dest);
                 match op {
-                    BO::Add => self.dadd(rhs, dest),
-                    BO::Sub => self.dsub(rhs, dest),
+                    BO::Add | BO::WrappingAdd => self.dadd(rhs, dest),
+                    BO::Sub | BO::WrappingSub => self.dsub(rhs, dest),
+                    BO::SaturatingAdd => self.saturating_add(rhs, dest),
+                    BO::SaturatingSub => self.saturating_sub(rhs, dest),
                     BO::Mul => self.mul(rhs, dest),
                     BO::Div => self.div(rhs, dest),
                     BO::Mod => self.modulo(rhs, dest),
-                    BO::Eq => self.eq(rhs, dest),
-                    BO::Neq => self.neq(rhs, dest),
-                    BO::Lt => self.lt(rhs, dest),
-                    BO::Leq => self.leq(rhs, dest),
-                    BO::Gt => self.gt(rhs, dest),
-                    BO::Geq => self.geq(rhs, dest),
-                    BO::And => self.and(rhs, dest),
-                    BO::Or => self.or(rhs, dest),
+                    BO::Eq => {
+                        self.eq(rhs, dest);
+                        self.check_bool(dest);
+                    }
+                    BO::Neq => {
+                        self.neq(rhs, dest);
+                        self.check_bool(dest);
+                    }
+                    BO::Lt => {
+                        self.lt(rhs, dest);
+                        self.check_bool(dest);
+                    }
+                    BO::Leq => {
+                        self.leq(rhs, dest);
+                        self.check_bool(dest);
+                    }
+                    BO::Gt => {
+                        self.gt(rhs, dest);
+                        self.check_bool(dest);
+                    }
+                    BO::Geq => {
+                        self.geq(rhs, dest);
+                        self.check_bool(dest);
+                    }
+                    BO::And => {
+                        self.and(rhs, dest);
+                        self.check_bool(dest);
+                    }
+                    BO::Or => {
+                        self.or(rhs, dest);
+                        self.check_bool(dest);
+                    }
+                    BO::LogicalAnd | BO::LogicalOr => unreachable!("evaluated by short_circuit"),
                 }
-                self.dealloc(2);
+                self.dealloc(1);
             }
             E::Number(n) => self.set(dest, *n),
-            E::String(_) => todo!("Strings are not yet supported"),
-            E::Identifier(name) => match self.variables.get(name) {
-                Some(index) => self.copy_val(*index, &[dest]),
-                None => return Err(format!("Variable {} is not defined", name)),
-            },
-            E::FunctionCall { callee, args } => self.call(callee, args)?,
+            E::Char(c) => self.set(dest, *c as u8),
+            E::Boolean(b) => self.set(dest, *b as u8),
+            E::String(s) => {
+                return Err(format!("String {:?} can't be used as a single value", s));
+            }
+            E::Identifier(name) => {
+                if self.variable(name)?.ty == Type::String {
+                    return Err(format!(
+                        "Variable {} is a string and can't be used as a single value",
+                        name
+                    ));
+                }
+                let var = self.read_var(name)?;
+                self.copy_val(var, &[dest]);
+            }
+            E::FunctionCall { callee, args } => self.call(callee, args, dest)?,
+            E::Cast { ty, value } => {
+                if *ty == Type::String || self.type_of(value) == Type::String {
+                    return Err(format!("Can't convert {} to {}", value, ty));
+                }
+                self.evaluate_expression(value, dest)?;
+                // Any nonzero value is true
+                if *ty == Type::Bool {
+                    self.normalize(dest);
+                    self.check_bool(dest);
+                }
+            }
+            E::Assignment { name, value, .. } => {
+                self.evaluate_expression(value, dest)?;
+                let var = self.write_var(name)?;
+                self.copy_val(dest, &[var]);
+            }
         }
         Ok(dest)
     }
 
-    fn call(&self, callee: &str, args: &[Expr]) -> Result<(), String> {
-        match self.functions.get(callee) {
-            Some(name) => todo!("Function calls are not yet supported"),
-            None => Err(format!("Function {} is not defined", callee)),
+    fn call(&mut self, callee: &str, args: &[Expr], dest: usize) -> Result<(), String> {
+        match callee {
+            "print_number" => {
+                let [arg] = builtin_args(callee, args)?;
+                let value = self.calloc(1);
+                self.evaluate_expression(arg, value)?;
+                self.print_numeric(value);
+                self.dealloc(1);
+                Ok(())
+            }
+            "print_hex" => {
+                let [arg] = builtin_args(callee, args)?;
+                let value = self.calloc(1);
+                self.evaluate_expression(arg, value)?;
+                self.print_hex(value);
+                self.dealloc(1);
+                Ok(())
+            }
+            "pow" => {
+                let [base_expr, exp_expr] = builtin_args(callee, args)?;
+                let base = self.calloc(1);
+                let exp = self.calloc(1);
+                self.evaluate_expression(base_expr, base)?;
+                self.evaluate_expression(exp_expr, exp)?;
+                self.pow(base, exp, dest);
+                self.dealloc(2);
+                Ok(())
+            }
+            "clamp" => {
+                let [value_expr, lo_expr, hi_expr] = builtin_args(callee, args)?;
+                self.evaluate_expression(value_expr, dest)?;
+                let lo = self.calloc(1);
+                let hi = self.calloc(1);
+                self.evaluate_expression(lo_expr, lo)?;
+                self.evaluate_expression(hi_expr, hi)?;
+                self.clamp_cell(lo, hi, dest);
+                self.dealloc(2);
+                Ok(())
+            }
+            _ => match self.functions.get(callee).cloned() {
+                Some(function) => self.inline_call(callee, &function, args, dest),
+                None => Err(format!("Function {} is not defined", callee)),
+            },
         }
     }
+
+    /// Compiles a call to a user-defined function by compiling its body in place. The body only
+    /// sees the function's parameters and its own variables.
+    fn inline_call(
+        &mut self,
+        name: &str,
+        function: &Function,
+        args: &[Expr],
+        dest: usize,
+    ) -> Result<(), String> {
+        if args.len() != function.params.len() {
+            return Err(format!(
+                "Function {} takes {} argument{}, found {}",
+                name,
+                function.params.len(),
+                if function.params.len() == 1 { "" } else { "s" },
+                args.len()
+            ));
+        }
+        let depth = self.call_stack.iter().filter(|f| *f == name).count();
+        if depth == MAX_RECURSION_DEPTH {
+            // The program can't be unrolled any further, so reaching this call stops it
+            self.print_str(&format!(
+                "Function {} recursed deeper than {} calls\n",
+                name, MAX_RECURSION_DEPTH
+            ));
+            self.trap();
+            return Ok(());
+        }
+        let max_depth = self
+            .options
+            .max_inline_depth
+            .unwrap_or(DEFAULT_MAX_INLINE_DEPTH);
+        if self.call_stack.len() == max_depth {
+            return Err(format!(
+                "Calling function {} nests calls deeper than {} levels",
+                name, max_depth
+            ));
+        }
+        if self.inlined_calls == MAX_INLINED_CALLS {
+            return Err(format!(
+                "Calling function {} inlines more than {} calls in total",
+                name, MAX_INLINED_CALLS
+            ));
+        }
+        self.inlined_calls += 1;
+
+        // Arguments are evaluated in the scope of the caller
+        let first_arg = self.calloc(args.len());
+        for (i, arg) in args.iter().enumerate() {
+            self.evaluate_expression(arg, first_arg + i)?;
+        }
+        let caller_variables = std::mem::take(&mut self.variables);
+        let caller_free_list = std::mem::take(&mut self.free_list);
+        for (i, param) in function.params.iter().enumerate() {
+            let var = Variable {
+                index: first_arg + i,
+                ty: Type::Number,
+                initialized: true,
+                capacity: 0,
+                read: false,
+                defined_at: None,
+                constant: None,
+            };
+            self.insert_var(param, var)?;
+        }
+        let running = if exits(&function.body) {
+            let running = self.calloc(1);
+            self.set(running, 1);
+            Some(running)
+        } else {
+            None
+        };
+        self.frames.push(Frame {
+            running,
+            kind: FrameKind::Call { result: dest },
+        });
+        self.call_stack.push(name.to_string());
+        let result = self.evaluate_statement(&function.body);
+        self.call_stack.pop();
+        self.frames.pop();
+        self.variables = caller_variables;
+        self.free_list = caller_free_list;
+        result?;
+        self.dealloc(args.len() + running.map_or(0, |_| 1));
+        Ok(())
+    }
+
+    /// Stops the program by moving the pointer left of the first cell, which is an error in
+    /// brainfuck. The compiler keeps tracking the pointer as if the trap was never reached.
+    fn trap(&mut self) {
+        self.traps = true;
+        self.output
+            .extend("<".repeat(self.ptr as usize + 1).chars());
+        // Keeps the optimizer from cancelling the moves against the ones that follow
+        self.output.extend("[-]".chars());
+    }
+
+    /// With debug checks enabled, stops the program if the value at `src` is neither 0 nor 1.
+    /// The value at `src` is left unchanged.
+    fn check_bool(&mut self, src: usize) {
+        if !self.options.debug_checks {
+            return;
+        }
+        let value = self.calloc(1);
+        self.copy_val(src, &[value]);
+        let nonzero = self.begin_if(value);
+        self.emit(&format!("{}-", value));
+        let above_one = self.begin_if(value);
+        self.print_str("Expected a boolean to be 0 or 1\n");
+        self.trap();
+        self.end_if(above_one);
+        self.end_if(nonzero);
+        self.set(value, 0);
+        self.dealloc(3);
+    }
+
+    /// Puts `text` in a comment on a line of its own. Brainfuck instructions are removed from
+    /// the text, so it can't change what the code does.
+    fn comment(&mut self, text: &str) {
+        let text: String = text
+            .chars()
+            .filter(|c| !"+-<>[].,#\n".contains(*c))
+            .collect();
+        // A `#` with nothing after it would be a breakpoint
+        if text.trim().is_empty() {
+            return;
+        }
+        if self.output.last().is_some_and(|c| *c != '\n') {
+            self.output.push('\n');
+        }
+        self.output.extend("# ".chars());
+        self.output.extend(text.chars());
+        self.output.push('\n');
+    }
+
+    /// Compiles `src` on top of the code compiled so far and returns the newly emitted brainfuck.
+    /// On error the compiler is left as it was before the call.
+    pub(crate) fn compile_chunk(&mut self, src: &str) -> Result<String, String> {
+        let tokens = tokenize_located(src);
+        let program = parse(&tokens)?;
+        // The limit is on the code compiled at once
+        self.inlined_calls = 0;
+        let snapshot = self.clone();
+        let start = self.output.len();
+        if let Err(e) = self.compile(&program.statements) {
+            *self = snapshot;
+            return Err(e);
+        }
+        Ok(optimize(&self.output[start..].iter().collect::<String>()))
+    }
+}
+
+/// The value of an expression that is known at compile time.
+struct Constant {
+    value: u8,
+    /// Whether computing the value wrapped around at some point.
+    overflowed: bool,
+    /// The largest result of an operation while computing the value, before wrapping around.
+    peak: u16,
+    /// The expression as it would be written in the source code.
+    source: String,
+    /// The variables whose known values the value was computed from.
+    variables: Vec<String>,
+}
+
+/// Computes the value of arithmetic and comparisons on number and boolean literals and on
+/// variables whose values `known` gives at compile time. Comparisons give 1 or 0. Division by
+/// zero doesn't fold, so the compiler can report it.
+fn fold_constant(expr: &Expr, known: &dyn Fn(&str) -> Option<u8>) -> Option<Constant> {
+    use crate::parser::BinaryOp as BO;
+    match expr {
+        Expr::Number(n) => Some(Constant {
+            value: *n,
+            overflowed: false,
+            peak: 0,
+            source: n.to_string(),
+            variables: Vec::new(),
+        }),
+        Expr::Boolean(b) => Some(Constant {
+            value: *b as u8,
+            overflowed: false,
+            peak: 0,
+            source: b.to_string(),
+            variables: Vec::new(),
+        }),
+        Expr::Identifier(name) => Some(Constant {
+            value: known(name)?,
+            overflowed: false,
+            peak: 0,
+            source: name.clone(),
+            variables: vec![name.clone()],
+        }),
+        Expr::Unary { op, rhs } => {
+            let rhs = fold_constant(rhs, known)?;
+            let (value, symbol) = match op {
+                // Negative literals are a deliberate way to write large values, so negation
+                // doesn't count as wrapping around
+                UnaryOp::Neg => (rhs.value.wrapping_neg(), "-"),
+                UnaryOp::Not => ((rhs.value == 0) as u8, "!"),
+            };
+            let source = if rhs.source.contains(' ') {
+                format!("{}({})", symbol, rhs.source)
+            } else {
+                format!("{}{}", symbol, rhs.source)
+            };
+            Some(Constant {
+                value,
+                source,
+                ..rhs
+            })
+        }
+        Expr::Binary { lhs, op, rhs, .. } => {
+            let (lhs, rhs) = (fold_constant(lhs, known)?, fold_constant(rhs, known)?);
+            let (value, overflowed) = match op {
+                BO::Add => lhs.value.overflowing_add(rhs.value),
+                BO::Sub => lhs.value.overflowing_sub(rhs.value),
+                BO::Mul => lhs.value.overflowing_mul(rhs.value),
+                // The wrap around is what these are written for
+                BO::WrappingAdd => (lhs.value.wrapping_add(rhs.value), false),
+                BO::WrappingSub => (lhs.value.wrapping_sub(rhs.value), false),
+                BO::SaturatingAdd => (lhs.value.saturating_add(rhs.value), false),
+                BO::SaturatingSub => (lhs.value.saturating_sub(rhs.value), false),
+                BO::Div => (lhs.value.checked_div(rhs.value)?, false),
+                BO::Mod => (lhs.value.checked_rem(rhs.value)?, false),
+                BO::Eq => ((lhs.value == rhs.value) as u8, false),
+                BO::Neq => ((lhs.value != rhs.value) as u8, false),
+                BO::Lt => ((lhs.value < rhs.value) as u8, false),
+                BO::Leq => ((lhs.value <= rhs.value) as u8, false),
+                BO::Gt => ((lhs.value > rhs.value) as u8, false),
+                BO::Geq => ((lhs.value >= rhs.value) as u8, false),
+                BO::And | BO::LogicalAnd => ((lhs.value != 0 && rhs.value != 0) as u8, false),
+                BO::Or | BO::LogicalOr => ((lhs.value != 0 || rhs.value != 0) as u8, false),
+            };
+            let exact = match op {
+                BO::Add => lhs.value as u16 + rhs.value as u16,
+                BO::Mul => lhs.value as u16 * rhs.value as u16,
+                _ => value as u16,
+            };
+            let peak = exact.max(lhs.peak).max(rhs.peak);
+            let parenthesize = |constant: Constant| {
+                if constant.source.contains(' ') {
+                    format!("({})", constant.source)
+                } else {
+                    constant.source
+                }
+            };
+            let variables = [lhs.variables.clone(), rhs.variables.clone()].concat();
+            Some(Constant {
+                value,
+                overflowed: overflowed || lhs.overflowed || rhs.overflowed,
+                peak,
+                source: format!("{} {} {}", parenthesize(lhs), op, parenthesize(rhs)),
+                variables,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// A short description of a statement, for the comments of `--explain`.
+fn describe(stmt: &Statement) -> String {
+    use crate::parser::Statement as S;
+    match stmt {
+        S::FunctionDefinition { name, .. } => format!("fn {}", name),
+        S::VariableDefinition { name, position, .. } => {
+            format!("let {} on line {}", name, position.line)
+        }
+        S::Assignment { name, position, .. } => {
+            format!("assign to {} on line {}", name, position.line)
+        }
+        S::Return(_) => "return".to_string(),
+        S::Print { .. } => "print".to_string(),
+        S::Expression(Expr::FunctionCall { callee, .. }) => format!("call {}", callee),
+        S::Expression(_) => "expression".to_string(),
+        S::Block(_) => "block".to_string(),
+        S::If { .. } => "if".to_string(),
+        S::While { .. } => "while".to_string(),
+        S::DoWhile { .. } => "do while".to_string(),
+        S::Break => "break".to_string(),
+        S::LabeledBlock { label, .. } => format!("block {}", label),
+        S::Exit(label) => format!("exit {}", label),
+        S::Match { .. } => "match".to_string(),
+    }
+}
+
+/// Checks that a builtin function was called with `N` arguments.
+fn builtin_args<'a, const N: usize>(
+    callee: &str,
+    args: &'a [Expr],
+) -> Result<&'a [Expr; N], String> {
+    args.try_into().map_err(|_| {
+        format!(
+            "Function {} takes {} argument{}, found {}",
+            callee,
+            N,
+            if N == 1 { "" } else { "s" },
+            args.len()
+        )
+    })
+}
+
+/// Whether a statement may leave the loop, block or function it is in early, with a `break`,
+/// `exit` or `return`. Breaks inside nested loops belong to those loops.
+fn exits(stmt: &Statement) -> bool {
+    use crate::parser::Statement as S;
+    match stmt {
+        S::Break | S::Exit(_) | S::Return(_) => true,
+        S::Block(statements)
+        | S::LabeledBlock {
+            body: statements, ..
+        } => statements.iter().any(exits),
+        S::If {
+            then_branch,
+            else_branch,
+            ..
+        } => exits(then_branch) || else_branch.as_deref().is_some_and(exits),
+        S::Match { arms, default, .. } => {
+            arms.iter().any(|arm| exits(&arm.body)) || default.as_deref().is_some_and(exits)
+        }
+        S::While { body, .. } | S::DoWhile { body, .. } => leaves(body),
+        _ => false,
+    }
+}
+
+/// Whether a statement contains an `exit` or `return`, which may leave the loops it is in.
+fn leaves(stmt: &Statement) -> bool {
+    use crate::parser::Statement as S;
+    match stmt {
+        S::Exit(_) | S::Return(_) => true,
+        S::Block(statements)
+        | S::LabeledBlock {
+            body: statements, ..
+        } => statements.iter().any(leaves),
+        S::If {
+            then_branch,
+            else_branch,
+            ..
+        } => leaves(then_branch) || else_branch.as_deref().is_some_and(leaves),
+        S::Match { arms, default, .. } => {
+            arms.iter().any(|arm| leaves(&arm.body)) || default.as_deref().is_some_and(leaves)
+        }
+        S::While { body, .. } | S::DoWhile { body, .. } => leaves(body),
+        _ => false,
+    }
+}
+
+/// The string an expression evaluates to, if it's a string literal or string literals added
+/// together. Adding them at compile time leaves one literal, so no buffer is copied at runtime.
+fn constant_string(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::String(s) => Some(s.clone()),
+        Expr::Binary {
+            lhs,
+            op: BinaryOp::Add,
+            rhs,
+            ..
+        } => Some(constant_string(lhs)? + &constant_string(rhs)?),
+        _ => None,
+    }
+}
+
+/// Whether an expression is a literal that is always true.
+fn is_constant_true(expr: &Expr) -> bool {
+    match expr {
+        Expr::Boolean(b) => *b,
+        Expr::Number(n) => *n != 0,
+        Expr::Char(c) => *c != '\0',
+        _ => false,
+    }
+}
+
+/// The brainfuck code emitted for a program, before and after optimization.
+pub struct Compilation {
+    pub unoptimized: String,
+    pub optimized: String,
+    /// Problems in the program that don't stop it from compiling.
+    pub warnings: Vec<String>,
+    /// Number of tape cells the program needs.
+    pub tape_size: usize,
+    /// Whether the program can stop itself by moving the pointer left of the first cell.
+    pub traps: bool,
 }
 
 pub fn compile(src: &str) -> Result<String, String> {
-    let tokens: Vec<Token> = tokenize(src).collect();
-    let program = parse(&tokens)?;
-    let mut compiler = Compiler::new();
+    Ok(compile_with_ir(src, &Options::default())?.optimized)
+}
+
+pub fn compile_with_ir(src: &str, options: &Options) -> Result<Compilation, String> {
+    let program = parse(&tokenize_with(src, options))?;
+    compile_program(&program, options)
+}
+
+/// Compiles several files into one program. The files run in order, and the functions and
+/// variables defined at the top level of a file can be used by the files after it.
+///
+/// # Arguments
+/// * `files` - The name and source code of each file
+pub fn compile_files(files: &[(&str, &str)], options: &Options) -> Result<Compilation, String> {
+    compile_program(&parse_files(files, options)?, options)
+}
+
+/// Parses several files into one program, with the name of the file in front of each error.
+fn parse_files(files: &[(&str, &str)], options: &Options) -> Result<Program, String> {
+    let mut statements = Vec::new();
+    for (file, (name, src)) in files.iter().enumerate() {
+        let program = parse(&in_file(tokenize_with(src, options), file)).map_err(|e| {
+            e.lines()
+                .map(|line| format!("{}: {}", name, line))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })?;
+        statements.extend(program.statements);
+    }
+    let mut program = Program::new(statements);
+    program.files = files.iter().map(|(name, _)| name.to_string()).collect();
+    Ok(program)
+}
+
+/// Marks the positions of `tokens` as being in the file with the index `file`.
+fn in_file(mut tokens: Vec<(Token, Span)>, file: usize) -> Vec<(Token, Span)> {
+    for (_, span) in &mut tokens {
+        span.start.file = file;
+        span.end.file = file;
+    }
+    tokens
+}
+
+/// What is known about a program compiled with [`compile_to`] once all of its code is written.
+pub struct Streamed {
+    /// Problems in the program that don't stop it from compiling.
+    pub warnings: Vec<String>,
+    /// Number of tape cells the program needs.
+    pub tape_size: usize,
+    /// Whether the program can stop itself by moving the pointer left of the first cell.
+    pub traps: bool,
+}
+
+/// Compiles `src` like [`compile_with_ir`], but writes the optimized code to `writer` after each
+/// top-level statement instead of building it up in memory. Code already written is not taken
+/// back if compiling fails later on.
+pub fn compile_to(
+    src: &str,
+    options: &Options,
+    writer: &mut impl Write,
+) -> Result<Streamed, String> {
+    let program = parse(&tokenize_with(src, options))?;
+    stream_program(&program, options, writer)
+}
+
+/// Compiles several files into one program like [`compile_files`], writing the optimized code
+/// to `writer` as it goes like [`compile_to`].
+pub fn compile_files_to(
+    files: &[(&str, &str)],
+    options: &Options,
+    writer: &mut impl Write,
+) -> Result<Streamed, String> {
+    stream_program(&parse_files(files, options)?, options, writer)
+}
+
+fn stream_program(
+    program: &Program,
+    options: &Options,
+    writer: &mut impl Write,
+) -> Result<Streamed, String> {
+    let program = with_prelude(program, options)?;
+    let mut compiler = Compiler::with_options(options.clone());
+    compiler.files = program.files.clone();
+    let mut optimizer = Optimizer::default();
+    let mut wrapper = options.line_width.map(LineWrapper::new);
+    let mut write = |code: String| {
+        let code = match &mut wrapper {
+            Some(wrapper) => wrapper.wrap(&code),
+            None => code,
+        };
+        writer
+            .write_all(code.as_bytes())
+            .map_err(|e| format!("Could not write the compiled code: {}", e))
+    };
+    for stmt in &program.statements {
+        compiler.compile(std::slice::from_ref(stmt))?;
+        // The last character is kept, since comments check what they follow
+        let emitted = compiler.output.len().saturating_sub(1);
+        for c in compiler.output.drain(..emitted) {
+            optimizer.push(c);
+        }
+        write(optimizer.take_final())?;
+    }
+    compiler.warn_unused_globals();
+    for c in compiler.output.drain(..) {
+        optimizer.push(c);
+    }
+    write(optimizer.finish())?;
+    if let Some(wrapper) = wrapper {
+        writer
+            .write_all(wrapper.finish().as_bytes())
+            .map_err(|e| format!("Could not write the compiled code: {}", e))?;
+    }
+    Ok(Streamed {
+        warnings: compiler.warnings,
+        tape_size: compiler.tape_size,
+        traps: compiler.traps,
+    })
+}
+
+/// Tokenizes `src` with the comment syntax chosen by `options`.
+fn tokenize_with(src: &str, options: &Options) -> Vec<(Token, Span)> {
+    locate(tokenize(src).with_comment_prefix(options.comment_prefix))
+}
+
+/// Puts the prelude chosen by `options` in front of `program` and checks the assignments in it.
+/// Functions the program defines at its top level replace the prelude functions of the same
+/// name, also where other prelude functions call them.
+fn with_prelude(program: &Program, options: &Options) -> Result<Program, String> {
+    // The standard prelude is written with `//` comments, while a custom one is written like
+    // the program
+    let tokens = match &options.prelude {
+        Prelude::Std => tokenize_located(STD_PRELUDE),
+        Prelude::None => Vec::new(),
+        Prelude::Custom(src) => tokenize_with(src, options),
+    };
+    let defined: HashSet<&str> = program
+        .statements
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Statement::FunctionDefinition { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    let mut statements: Vec<Statement> = parse(&in_file(tokens, PRELUDE_FILE))
+        .map_err(|e| format!("Could not parse the prelude: {}", e))?
+        .statements
+        .into_iter()
+        .filter(|stmt| match stmt {
+            Statement::FunctionDefinition { name, .. } => !defined.contains(name.as_str()),
+            _ => true,
+        })
+        .collect();
+    statements.extend(program.statements.iter().cloned());
+    let files = program.files.clone();
+    let mut program = Program::new(statements);
+    program.files = files;
+    check_assignments(&program)?;
+    Ok(program)
+}
+
+fn compile_program(program: &Program, options: &Options) -> Result<Compilation, String> {
+    let program = with_prelude(program, options)?;
+    let mut compiler = Compiler::with_options(options.clone());
+    compiler.files = program.files.clone();
     compiler.compile(&program.statements)?;
-    Ok(compiler.output.iter().collect())
+    compiler.warn_unused_globals();
+    let unoptimized: String = compiler.output.iter().collect();
+    let mut optimized = optimize(&unoptimized);
+    if let Some(width) = options.line_width {
+        optimized = wrap_lines(&optimized, width);
+    }
+    Ok(Compilation {
+        unoptimized,
+        optimized,
+        warnings: compiler.warnings,
+        tape_size: compiler.tape_size,
+        traps: compiler.traps,
+    })
+}
+
+/// Removes instructions that have no effect: adjacent instructions that undo each other (like
+/// `+-` or `<>`) and loops directly after the end of another loop, where the current cell is
+/// always zero so the loop can never run.
+pub(crate) fn optimize(code: &str) -> String {
+    let mut optimizer = Optimizer::default();
+    for c in code.chars() {
+        optimizer.push(c);
+    }
+    optimizer.finish()
+}
+
+/// Optimizes code one character at a time, like [`optimize`].
+#[derive(Default)]
+struct Optimizer {
+    out: Vec<char>,
+    /// How many loops deep into a loop that is being removed the code is.
+    skip_depth: usize,
+}
+
+impl Optimizer {
+    fn push(&mut self, c: char) {
+        if self.skip_depth > 0 {
+            match c {
+                '[' => self.skip_depth += 1,
+                ']' => self.skip_depth -= 1,
+                _ => (),
+            }
+            return;
+        }
+        match (self.out.last(), c) {
+            (Some('+'), '-') | (Some('-'), '+') | (Some('>'), '<') | (Some('<'), '>') => {
+                self.out.pop();
+            }
+            (Some(']'), '[') => self.skip_depth = 1,
+            _ => self.out.push(c),
+        }
+    }
+
+    /// Takes the optimized code that characters pushed later can no longer change.
+    fn take_final(&mut self) -> String {
+        // A run of moves and changes at the end may still be undone, and so may the end of a
+        // loop in front of it, since it removes a loop that follows
+        let mut end = self.out.len();
+        while end > 0 && "+-<>".contains(self.out[end - 1]) {
+            end -= 1;
+        }
+        if end > 0 && self.out[end - 1] == ']' {
+            end -= 1;
+        }
+        self.out.drain(..end).collect()
+    }
+
+    /// Takes the rest of the optimized code.
+    fn finish(self) -> String {
+        self.out.into_iter().collect()
+    }
+}
+
+/// Breaks every line of brainfuck code into lines of at most `width` characters. Comment lines
+/// are left as they are.
+fn wrap_lines(code: &str, width: usize) -> String {
+    let mut wrapper = LineWrapper::new(width);
+    let mut out = wrapper.wrap(code);
+    out.push_str(&wrapper.finish());
+    out
+}
+
+/// Breaks code into lines like [`wrap_lines`], a piece at a time.
+struct LineWrapper {
+    width: usize,
+    /// The number of characters on the current line so far.
+    column: usize,
+    in_comment: bool,
+}
+
+impl LineWrapper {
+    fn new(width: usize) -> Self {
+        Self {
+            width: width.max(1),
+            column: 0,
+            in_comment: false,
+        }
+    }
+
+    fn wrap(&mut self, code: &str) -> String {
+        let mut out = String::with_capacity(code.len() + code.len() / self.width);
+        for c in code.chars() {
+            if c == '\n' {
+                if self.column > 0 {
+                    out.push('\n');
+                }
+                self.column = 0;
+                self.in_comment = false;
+                continue;
+            }
+            if self.column == 0 && c == '#' {
+                self.in_comment = true;
+            } else if !self.in_comment && self.column == self.width {
+                out.push('\n');
+                self.column = 0;
+            }
+            out.push(c);
+            self.column += 1;
+        }
+        out
+    }
+
+    /// Ends the last line.
+    fn finish(self) -> String {
+        if self.column > 0 {
+            "\n".to_string()
+        } else {
+            String::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsStr,
+        fs::{read_dir, read_to_string, File},
+        io::{self, Write},
+    };
+
+    use super::*;
+    use crate::{
+        brainfuck::{validate, RunError},
+        interpreter::Interpreter,
+    };
+
+    /// Compiles `src` and runs it, returning the printed output.
+    fn run(src: &str) -> String {
+        let (output, result) = try_run(src);
+        result.expect("Error when running the compiled program");
+        output
+    }
+
+    /// Compiles `src` and runs it, returning the printed output and whether running succeeded.
+    fn try_run(src: &str) -> (String, Result<(), RunError>) {
+        let code = compile(src).expect("Could not compile source code");
+        let mut interpreter =
+            Interpreter::from_code(&code).expect("Compiled code has unmatched brackets");
+        let result = interpreter.run_to_completion();
+        (interpreter.output().to_string(), result)
+    }
+
+    #[test]
+    fn test_compiler() -> Result<(), io::Error> {
+        let dir = read_dir("tests/compiler")?.filter(|e| {
+            if let Ok(e) = e {
+                e.path().extension() == Some(OsStr::new("brang"))
+            } else {
+                false
+            }
+        });
+        for path in dir {
+            let path = path?;
+            let src = read_to_string(path.path())?;
+            let out = run(&src);
+            let expected_path = path.path().with_extension("out");
+            println!("Source path: {:?}", path);
+            println!("Expected path: {:?}", expected_path);
+            if let Ok(expected) = read_to_string(&expected_path) {
+                assert_eq!(out, expected);
+            } else {
+                let mut out_file = File::create(&expected_path)?;
+                println!("Expected file not found, creating it...");
+                write!(out_file, "{}", out)?;
+            };
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimize() {
+        assert_eq!(optimize("+>-<+-><<>>>+"), "+>->+");
+        assert_eq!(optimize("[-][-]>[->+<][>[-]<]+"), "[-]>[->+<]+");
+        let src = "let a = 2; let b = a + 3; if a == b { print(\"x\"); } else { print(\"y\"); }";
+        let compilation = compile_with_ir(src, &Options::default()).unwrap();
+        assert!(compilation.optimized.len() < compilation.unoptimized.len());
+        for code in [compilation.unoptimized, compilation.optimized] {
+            let mut interpreter = Interpreter::from_code(&code).unwrap();
+            interpreter.run_to_completion().unwrap();
+            assert_eq!(interpreter.output(), "y");
+        }
+    }
+
+    #[test]
+    fn test_tape_size() {
+        let src = "let x = 1; let y = x + 2; print_number(y);";
+        let compilation = compile_with_ir(src, &Options::default()).unwrap();
+        let mut interpreter = Interpreter::from_code(&compilation.optimized).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert!(interpreter.memory_snapshot().len() <= compilation.tape_size);
+        assert!(compilation.tape_size < 16);
+    }
+
+    #[test]
+    fn test_empty_program() {
+        assert_eq!(compile("").unwrap(), "");
+        assert_eq!(compile("// just a comment\n\n").unwrap(), "");
+    }
+
+    #[test]
+    fn test_empty_bodies() {
+        // `n` is only known at runtime
+        let unknown = "let n = 0; if n { n = 0; } ";
+        for body in [
+            "{}",
+            "{ {} {} }",
+            "if n {}",
+            "if n {} else {}",
+            "if n { print('a'); } else {}",
+            "if n {} else { print('b'); }",
+            "if n {} elif n {} else {}",
+            "while n {}",
+            "do {} while n;",
+            "for let i = 0; i < n; i = i + 1 {}",
+            "fn f() {} f();",
+            "outer: {}",
+            "let s = \"\"; print(s);",
+        ] {
+            let src = format!("{}{} print('.');", unknown, body);
+            let code = compile(&src).unwrap();
+            assert!(validate(&code).is_ok(), "{}: {}", body, code);
+            let expected = if body.contains("print('b')") {
+                "b."
+            } else {
+                "."
+            };
+            assert_eq!(run(&src), expected, "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_emit_loop() {
+        let mut compiler = Compiler::new();
+        let count = compiler.calloc(1);
+        let dest = compiler.calloc(1);
+        let start = compiler.output.len();
+        compiler.emit_loop(count, |c| c.emit(&format!("-{}+", dest)));
+        let code: String = compiler.output[start..].iter().collect();
+        assert_eq!(code, "<[->+<]");
+    }
+
+    #[test]
+    #[should_panic(expected = "Loop on cell 0 has a body with unbalanced brackets")]
+    fn test_emit_loop_unbalanced() {
+        let mut compiler = Compiler::new();
+        let count = compiler.calloc(1);
+        compiler.emit_loop(count, |c| c.emit("[-"));
+    }
+
+    #[test]
+    fn test_zero_size_allocations() {
+        let mut compiler = Compiler::new();
+        let index = compiler.calloc(0);
+        assert_eq!(compiler.malloc(0), index);
+        compiler.dealloc(0);
+        // Nothing is allocated or emitted, so the next cell handed out is the same one
+        assert!(compiler.output.is_empty());
+        assert_eq!(compiler.calloc(1), index);
+    }
+
+    #[test]
+    fn test_hello_world() -> Result<(), io::Error> {
+        let src = read_to_string("tests/compile/hello.brang")?;
+        let expected = read_to_string("tests/compile/hello.out")?;
+        assert_eq!(run(&src), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_files() {
+        let files = [
+            (
+                "lib.brang",
+                "let greeting = 'h'; fn twice(x) { return x * 2; }",
+            ),
+            (
+                "main.brang",
+                "greeting = 'H'; print(greeting); print_number(twice(21));",
+            ),
+        ];
+        let compilation = compile_files(&files, &Options::default()).unwrap();
+        let mut interpreter = Interpreter::from_code(&compilation.optimized).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(interpreter.output(), "H42");
+        let result = compile_files(&[("a.brang", "let x = 1;\nlet = 2;")], &Options::default());
+        assert!(result.is_err_and(|e| e.starts_with("a.brang: ")));
+        let files = [("b.brang", "let b = 1;"), ("a.brang", "let a = 2;")];
+        let compilation = compile_files(&files, &Options::default()).unwrap();
+        assert_eq!(
+            compilation.warnings,
+            [
+                "b.brang: Variable b at line 1, column 5 is never used",
+                "a.brang: Variable a at line 1, column 5 is never used",
+            ]
+        );
+        let result = compile_files(&[("c.brang", "x = 1;")], &Options::default());
+        assert!(result.is_err_and(
+            |e| e == "c.brang: Assignment to undeclared variable x at line 1, column 1"
+        ));
+    }
+
+    #[test]
+    fn test_prelude() {
+        let src = "print_number(min(3, 7)); print_number(max(3, 7));";
+        assert_eq!(run(src), "37");
+        let options = Options {
+            prelude: Prelude::None,
+            ..Options::default()
+        };
+        assert_eq!(
+            compile_with_ir(src, &options).err().unwrap(),
+            "Function min is not defined"
+        );
+        let options = Options {
+            prelude: Prelude::Custom("fn min(a, b) { return 0; }".to_string()),
+            ..Options::default()
+        };
+        let compilation = compile_with_ir("print_number(min(3, 7));", &options).unwrap();
+        let mut interpreter = Interpreter::from_code(&compilation.optimized).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(interpreter.output(), "0");
+        assert!(compile_with_ir("print_number(max(3, 7));", &options).is_err());
+        // A function of the program replaces the prelude function with the same name
+        let src =
+            "fn min(a, b) { return a + b; } print_number(min(3, 7)); print_number(max(3, 7));";
+        assert_eq!(run(src), "107");
+        assert_eq!(
+            compile("fn f() {} fn f() {}").err().unwrap(),
+            "Function f is already defined"
+        );
+    }
+
+    #[test]
+    fn test_max_inline_depth() {
+        let src = "fn a(x) { return x + 1; } fn b(x) { return a(x) * 2; } \
+                   fn c(x) { return b(x) + b(x); } print_number(c(1));";
+        assert_eq!(run(src), "8");
+        let options = Options {
+            max_inline_depth: Some(2),
+            ..Options::default()
+        };
+        assert_eq!(
+            compile_with_ir(src, &options).err().unwrap(),
+            "Calling function a nests calls deeper than 2 levels"
+        );
+        // Mutual recursion nests calls to both functions
+        let src = "fn even(n) { if n == 0 { return 1; } return odd(n - 1); } \
+                   fn odd(n) { if n == 0 { return 0; } return even(n - 1); } \
+                   print_number(even(4));";
+        assert_eq!(run(src), "1");
+        let options = Options {
+            max_inline_depth: Some(10),
+            ..Options::default()
+        };
+        assert!(compile_with_ir(src, &options).is_err());
+        // Every function calls the one before it twice, doubling the code at every level
+        let mut src = "fn f0(x) { return x + 1; }".to_string();
+        for i in 1..16 {
+            src.push_str(&format!(
+                " fn f{}(x) {{ return f{}(x) + f{}(x); }}",
+                i,
+                i - 1,
+                i - 1
+            ));
+        }
+        assert_eq!(
+            compile(&format!("{} print_number(f15(0));", src))
+                .err()
+                .unwrap(),
+            format!(
+                "Calling function f1 inlines more than {} calls in total",
+                MAX_INLINED_CALLS
+            )
+        );
+        assert!(compile(&format!("{} print_number(f8(0));", src)).is_ok());
+    }
+
+    #[test]
+    fn test_uninitialized_variables() {
+        let options = Options {
+            check_uninitialized: true,
+            ..Options::default()
+        };
+        let err = compile_with_ir("let x; print(x);", &options).err().unwrap();
+        assert_eq!(err, "Variable x is read before it is assigned a value");
+        assert!(compile_with_ir("let x; x = 3; print(x);", &options).is_ok());
+        assert!(compile_with_ir("let x; let y = x = 3; print(y);", &options).is_ok());
+        assert!(compile_with_ir("let x = 0; print(x);", &options).is_ok());
+        assert_eq!(run("let x; print(x);"), "0");
+    }
+
+    #[test]
+    fn test_check_bool() {
+        let options = Options {
+            debug_checks: true,
+            ..Options::default()
+        };
+        for (value, fails) in [(0, false), (1, false), (2, true), (255, true)] {
+            let mut compiler = Compiler::with_options(options.clone());
+            let cell = compiler.calloc(1);
+            compiler.set(cell, value);
+            compiler.check_bool(cell);
+            compiler.print_str("ok");
+            let code = compiler.output.iter().collect::<String>();
+            let mut interpreter = Interpreter::from_code(&code).unwrap();
+            let result = interpreter.run_to_completion();
+            if fails {
+                assert!(matches!(result, Err(RunError::PointerUnderflow(_))));
+                assert_eq!(interpreter.output(), "Expected a boolean to be 0 or 1\n");
+            } else {
+                assert!(result.is_ok());
+                assert_eq!(interpreter.output(), "ok");
+            }
+        }
+        let src = "let a = 3; print(a == 3); print(!a);";
+        let compilation = compile_with_ir(src, &options).unwrap();
+        let mut interpreter = Interpreter::from_code(&compilation.optimized).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(interpreter.output(), "truefalse");
+        // Every operator giving a boolean passes the check with a value only known at runtime
+        let src = "let i = 0; while i < 250 { i = i + 1; } \
+                   print_number(i != 1); print_number(i & 3); print_number(i | 0); \
+                   print_number(i && 1); print_number(0 || i); print_number((bool) i);";
+        let compilation = compile_with_ir(src, &options).unwrap();
+        let mut interpreter = Interpreter::from_code(&compilation.optimized).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(interpreter.output(), "111111");
+    }
+
+    #[test]
+    fn test_variable_cell() {
+        let mut compiler = Compiler::new();
+        let code = compiler.compile_chunk("let x = 7;").unwrap();
+        let mut interpreter = Interpreter::from_code(&code).unwrap();
+        interpreter.run_to_completion().unwrap();
+        let x = compiler.variable("x").unwrap().index;
+        assert_eq!(interpreter.memory_snapshot()[x], 7);
+    }
+
+    #[test]
+    fn test_print_number() {
+        for (n, expected) in [
+            (0, "0"),
+            (5, "5"),
+            (9, "9"),
+            (10, "10"),
+            (42, "42"),
+            (99, "99"),
+            (100, "100"),
+            (101, "101"),
+            (110, "110"),
+            (255, "255"),
+        ] {
+            assert_eq!(run(&format!("print_number({});", n)), expected);
+        }
+    }
+
+    #[test]
+    fn test_print_hex() {
+        for (n, expected) in [(0, "00"), (9, "09"), (10, "0a"), (171, "ab"), (255, "ff")] {
+            assert_eq!(run(&format!("print_hex({});", n)), expected);
+        }
+        assert_eq!(run("let x = 31; print_hex(x); print_number(x);"), "1f31");
+    }
+
+    #[test]
+    fn test_print_radix() {
+        let options = Options {
+            print_radix: Radix::Hex,
+            ..Options::default()
+        };
+        let compilation =
+            compile_with_ir("print_number(255); print(' '); print(10);", &options).unwrap();
+        let mut interpreter = Interpreter::from_code(&compilation.optimized).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(interpreter.output(), "ff 0a");
+    }
+
+    #[test]
+    fn test_cast() {
+        assert_eq!(run("print((char) 72); print((char) 'h' - 32);"), "HH");
+        assert_eq!(run("let c = (char) 98; print(c); print((u8) c);"), "b98");
+        assert_eq!(
+            run("let x = 7; print((bool) x); print(' '); print((bool) (x - 7));"),
+            "true false"
+        );
+        assert_eq!(
+            compile("let s = \"hi\"; let x = (u8) s;").unwrap_err(),
+            "Can't convert s to u8"
+        );
+        assert!(compile("let x = (u16) 30;").is_err());
+    }
+
+    #[test]
+    fn test_print_number_leaves_value_unchanged() {
+        assert_eq!(
+            run("let x = 120; print_number(x); print_number(x);"),
+            "120120"
+        );
+    }
+
+    #[test]
+    fn test_print_char_and_number() {
+        let src = "let c: char = 'A'; let n = 65; print(c); print(n);";
+        assert_eq!(run(src), "A65");
+        let src = "let c: char = 66; let n = 'B'; let m: u8 = 'B'; print(c); print(n); print(m);";
+        assert_eq!(run(src), "BB66");
+        assert_eq!(run("let c = 'a'; print(c + 2); print('a' + 2);"), "cc");
+    }
+
+    #[test]
+    fn test_elif() {
+        let src = "let x = 0;
+            while x != 4 {
+                if x == 0 { print(\"zero \"); }
+                elif x == 1 { print(\"one \"); }
+                elif x == 2 { print(\"two \"); }
+                else { print(\"many\"); }
+                x = x + 1;
+            }";
+        assert_eq!(run(src), "zero one two many");
+    }
+
+    #[test]
+    fn test_else_branch() {
+        // The branches allocate cells and move the pointer far from the condition cell
+        let branches = "{ let a = 1; let b = a * 20; print_number(b); } \
+                        else { let c = 2; { let d = c + 3; print_number(d); } }";
+        for (condition, expected) in [
+            ("x == 1", "20"),
+            ("x == 2", "5"),
+            ("x", "20"),
+            ("!x", "5"),
+            ("x < 1", "5"),
+        ] {
+            let src = format!("let x = 1; if {} {} print('.');", condition, branches);
+            let mut compiler = Compiler::new();
+            let code = compiler.compile_chunk(&src).unwrap();
+            let mut interpreter = Interpreter::from_code(&code).unwrap();
+            interpreter.run_to_completion().unwrap();
+            assert_eq!(
+                interpreter.output(),
+                format!("{}.", expected),
+                "if {}",
+                condition
+            );
+            assert_eq!(
+                interpreter.pointer() as isize,
+                compiler.ptr,
+                "if {}",
+                condition
+            );
+        }
+    }
+
+    #[test]
+    fn test_while() {
+        let src = "let i = 3; while i != 0 { print_number(i); i = i - 1; }";
+        assert_eq!(run(src), "321");
+    }
+
+    #[test]
+    fn test_do_while_runs_body_once() {
+        assert_eq!(run("do { print(\"x\"); } while (false);"), "x");
+        let src = "let i = 3; do { print_number(i); i = i - 1; } while (i != 0);";
+        assert_eq!(run(src), "321");
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_eq!(run("print_number(pow(2, 3));"), "8");
+        assert_eq!(run("print_number(pow(7, 0));"), "1");
+        assert_eq!(run("print_number(pow(0, 0));"), "1");
+        assert_eq!(
+            run("let b = 3; print_number(pow(b, 2)); print_number(b);"),
+            "93"
+        );
+        // Values are bytes, so powers wrap around
+        assert_eq!(run("print_number(pow(2, 8));"), "0");
+        assert_eq!(run("print_number(pow(3, 5));"), "243");
+        assert_eq!(run("print_number(pow(3, 6));"), "217");
+        assert_eq!(
+            compile("print_number(pow(2));").unwrap_err(),
+            "Function pow takes 2 arguments, found 1"
+        );
+    }
+
+    #[test]
+    fn test_string_variables() {
+        assert_eq!(
+            run("let s = \"hi\"; print(s); s = \"yo\"; print(s);"),
+            "hiyo"
+        );
+        // Shorter strings fit in the buffer of longer ones
+        assert_eq!(
+            run("let s = \"hello\"; s = \"hi\"; print(s); print(s);"),
+            "hihi"
+        );
+        assert!(compile("let s = \"hi\"; s = \"hello\";").is_err());
+        assert!(compile("let s: str;").is_err());
+    }
+
+    #[test]
+    fn test_many_string_literals() {
+        let literals: Vec<String> = (0..100).map(|i| format!("line {} ", i)).collect();
+        let src: String = literals
+            .iter()
+            .map(|literal| format!("print(\"{}\");", literal))
+            .collect();
+        let start = std::time::Instant::now();
+        let code = compile(&src).unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        let mut interpreter = Interpreter::from_code(&code).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(interpreter.output(), literals.concat());
+    }
+
+    #[test]
+    fn test_string_bytes_above_16() {
+        assert_eq!(
+            run(
+                "let a = \"HELLO WORLD\"; let b = \"XYZZY\"; print(a); print(b); \
+                 a = \"QUICK BROWN\"; print(a); print(b);"
+            ),
+            "HELLO WORLDXYZZYQUICK BROWNXYZZY"
+        );
+    }
+
+    #[test]
+    fn test_write_str() {
+        let mut compiler = Compiler::new();
+        let index = compiler.calloc(7);
+        compiler.write_str(index, "ABCDEF");
+        let code: String = compiler.output.iter().collect();
+        let mut interpreter = Interpreter::from_code(&code).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(
+            &interpreter.memory_snapshot()[index..index + 7],
+            b"ABCDEF\0"
+        );
+    }
+
+    #[test]
+    fn test_strings_are_copied() {
+        let src = "let a = \"hi\"; let b = a; a = \"yo\"; print(a); print(b);";
+        assert_eq!(run(src), "yohi");
+        let src = "let a = \"hi\"; let b = \"abc\"; b = a; a = \"yo\"; print(b); print(a);";
+        assert_eq!(run(src), "hiyo");
+        assert!(compile("let a = \"abc\"; let b = \"hi\"; b = a;").is_err());
+    }
+
+    #[test]
+    fn test_string_type_errors() {
+        assert_eq!(
+            compile("let s = \"hi\"; let x = s + 1;").unwrap_err(),
+            "Variable s is a string and can't be used as a single value"
+        );
+        assert!(compile("let s = \"hi\"; if s { }").is_err());
+        assert!(compile("print(\"hi\" + 1);").is_err());
+    }
+
+    #[test]
+    fn test_print_bool() {
+        assert_eq!(run("print(true); print(false);"), "truefalse");
+        assert_eq!(run("let a = 3; print(a == 3); print(a != 3);"), "truefalse");
+        assert_eq!(run("let b = 2 == 2; print(b); print(!b);"), "truefalse");
+        assert_eq!(run("let b: bool = 7; print(b);"), "true");
+    }
+
+    #[test]
+    fn test_negation() {
+        assert_eq!(run("let x = -1; print_number(x);"), "255");
+        assert_eq!(
+            run("let x = -3; print_number(x); print_number(-0);"),
+            "2530"
+        );
+        // Folded and generated negation agree
+        let unknown = "let y = 3; if y { y = 3; } ";
+        assert_eq!(
+            run(&format!(
+                "{}print_number(-y); print_number(-(y + 1));",
+                unknown
+            )),
+            "253252"
+        );
+        assert_eq!(run("print_number(-(3 + 1)); print_number(2 - -3);"), "2525");
+        let mut compiler = Compiler::new();
+        compiler.compile_chunk("let x = -3; let t = !0;").unwrap();
+        assert_eq!(compiler.variables["x"].constant, Some(253));
+        assert_eq!(compiler.variables["t"].constant, Some(1));
+        assert!(
+            compile_with_ir("let x = -1; print_number(x);", &Options::default())
+                .unwrap()
+                .warnings
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_boolean_literals() {
+        let src = "let done = false; \
+                   if true { print(done); } \
+                   if false { print('x'); } else { print(!done); } \
+                   if done { print('y'); } \
+                   print_number(true); print_number(false + 2);";
+        assert_eq!(run(src), "falsetrue12");
+        // Known booleans are folded like numbers
+        let mut compiler = Compiler::new();
+        compiler.compile_chunk("let done = true;").unwrap();
+        assert_eq!(compiler.variables["done"].constant, Some(1));
+        assert_eq!(
+            run("let i = 0; let go = true; while go { i = i + 1; go = i < 3; } print_number(i);"),
+            "3"
+        );
+    }
+
+    #[test]
+    fn test_print_format() {
+        assert_eq!(
+            run("let a = 12; let c = 'z'; print(\"a={}, c={}, {}\\n\", a, c, a == 12);"),
+            "a=12, c=z, true\n"
+        );
+        assert_eq!(run("let s: str = \"hi\"; print(\"<{}>\", s);"), "<hi>");
+    }
+
+    #[test]
+    fn test_unused_variable_warning() {
+        let warnings = |src| compile_with_ir(src, &Options::default()).unwrap().warnings;
+        assert_eq!(
+            warnings("let used = 1;\nlet unused = 5;\nprint(used);"),
+            ["Variable unused at line 2, column 5 is never used"]
+        );
+        assert_eq!(
+            warnings("{ let x = 1; x = 2; }"),
+            ["Variable x at line 1, column 7 is never used"]
+        );
+        assert!(warnings("let _ignored = 5;").is_empty());
+        assert!(warnings("let s = \"hi\"; let t = s; print(t);").is_empty());
+        assert_eq!(warnings("do { let y = 1; } while false;").len(), 1);
+    }
+
+    #[test]
+    fn test_functions() {
+        let src = "fn add(a, b) { return a + b; } print_number(add(2, 3));";
+        assert_eq!(run(src), "5");
+        let src = "fn greet() { print(\"hi\"); } greet(); greet();";
+        assert_eq!(run(src), "hihi");
+        // Functions don't see the variables of their caller
+        assert!(compile("let x = 1; fn f() { return x; } f();").is_err());
+        assert!(compile("fn f(a) { return a; } f(1, 2);").is_err());
+        assert!(compile("return 1;").is_err());
+    }
+
+    #[test]
+    fn test_early_return() {
+        let src = "fn sign(n) { if n == 0 { return 0; } print(\"!\"); return 1; } \
+                   print_number(sign(0)); print_number(sign(5));";
+        assert_eq!(run(src), "0!1");
+        let src = "fn first_over(n) { let i = 0; while true { i = i + 1; if i * i == n { return i; } } } \
+                   print_number(first_over(49));";
+        assert_eq!(run(src), "7");
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        let src = "fn fact(n) { if n == 0 { return 1; } return n * fact(n - 1); }";
+        assert_eq!(run(&format!("{} print_number(fact(5));", src)), "120");
+        let (_, result) = try_run(&format!(
+            "{} print_number(fact({}));",
+            src,
+            MAX_RECURSION_DEPTH - 1
+        ));
+        assert!(result.is_ok());
+        let (output, result) = try_run(&format!(
+            "{} print_number(fact({}));",
+            src, MAX_RECURSION_DEPTH
+        ));
+        assert_eq!(
+            output,
+            format!(
+                "Function fact recursed deeper than {} calls\n",
+                MAX_RECURSION_DEPTH
+            )
+        );
+        assert!(matches!(result, Err(RunError::PointerUnderflow(_))));
+    }
+
+    #[test]
+    fn test_labeled_block() {
+        let src = "let x = 3; outer: { print(\"a\"); if x == 3 { exit outer; } print(\"b\"); } print(\"c\");";
+        assert_eq!(run(src), "ac");
+        let src = "outer: { inner: { exit outer; print(\"a\"); } print(\"b\"); } print(\"c\");";
+        assert_eq!(run(src), "c");
+        let src = "outer: { inner: { exit inner; } print(\"b\"); }";
+        assert_eq!(run(src), "b");
+        // A break inside a labeled block leaves the loop around it
+        let src =
+            "let i = 0; while true { i = i + 1; body: { if i == 3 { break; } } print_number(i); }";
+        assert_eq!(run(src), "12");
+        let src = "let i = 0; found: { while true { i = i + 1; if i == 4 { exit found; } } } print_number(i);";
+        assert_eq!(run(src), "4");
+        assert!(compile("exit nowhere;").is_err());
+        assert!(compile("outer: { fn f() { exit outer; } f(); }").is_err());
+    }
+
+    #[test]
+    fn test_div_and_modulo() {
+        assert_eq!(
+            run("print_number(17 / 5); print(' '); print_number(17 % 5);"),
+            "3 2"
+        );
+        assert_eq!(
+            run("print_number(255 / 1); print(' '); print_number(4 % 8);"),
+            "255 4"
+        );
+        assert_eq!(run("let x = 10; x %= 3; print_number(x);"), "1");
+        assert_eq!(run("let x = 9; let y = 3; x %= y; print_number(x);"), "0");
+    }
+
+    #[test]
+    fn test_break() {
+        let src = "let i = 0; while true { i = i + 1; if i == 4 { break; } print_number(i); }";
+        assert_eq!(run(src), "123");
+        let src = "let i = 0; do { i = i + 1; if i == 2 { break; } print_number(i); } while true;";
+        assert_eq!(run(src), "1");
+        // A break only leaves the innermost loop
+        let src = "let i = 0; while i != 2 { i = i + 1; while true { print_number(i); break; } }";
+        assert_eq!(run(src), "12");
+        assert!(compile("break;").is_err());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let pairs = [
+            (0, 0),
+            (0, 1),
+            (1, 0),
+            (5, 5),
+            (3, 200),
+            (255, 254),
+            (255, 0),
+        ];
+        for (a, b) in pairs {
+            let expected = [a < b, a <= b, a > b, a >= b, a == b, a != b]
+                .map(|holds| if holds { "t" } else { "f" })
+                .concat();
+            let src = format!("let a = {}; let b = {};", a, b);
+            let printed = ["<", "<=", ">", ">=", "==", "!="]
+                .map(|op| {
+                    format!(
+                        "{{ let c = a {} b; if c {{ print('t'); }} else {{ print('f'); }} }}",
+                        op
+                    )
+                })
+                .concat();
+            assert_eq!(
+                run(&format!("{} {}", src, printed)),
+                expected,
+                "{:?}",
+                (a, b)
+            );
+            let branched = ["<", "<=", ">", ">=", "==", "!="]
+                .map(|op| format!("if a {} b {{ print('t'); }} else {{ print('f'); }}", op))
+                .concat();
+            assert_eq!(
+                run(&format!("{} {}", src, branched)),
+                expected,
+                "{:?}",
+                (a, b)
+            );
+        }
+        let src =
+            "let i = 0; while i < 5 { print_number(i); i = i + 2; } while i >= 1 { i = i - 3; }";
+        assert_eq!(run(src), "024");
+    }
+
+    #[test]
+    fn test_unroll_loop() {
+        let unrolled = "for let i = 0; i < 3; i = i + 1 { print((char) ('a' + i)); }";
+        // Assigning in an if makes the bound unknown, so the loop stays a loop
+        let looped = "let n = 3; if n { n = 3; } \
+                      for let i = 0; i < n; i = i + 1 { print((char) ('a' + i)); }";
+        assert_eq!(run(unrolled), "abc");
+        assert_eq!(run(looped), "abc");
+        let loops = |src| compile(src).unwrap().matches('[').count();
+        assert!(loops(unrolled) < loops(looped));
+        assert_eq!(
+            run("let i = 5; do { print_number(i); i = i - 2; } while i > 1;"),
+            "53"
+        );
+        // Too many iterations to unroll
+        let src = "let n = 0; for let i = 0; i < 40; i = i + 1 { n = n + 2; } print_number(n);";
+        assert_eq!(run(src), "80");
+        // Warnings in the body are reported once, not once per copy
+        let warnings = compile_with_ir(
+            "let i = 0; while i < 3 { let unused = 1; i = i + 1; }",
+            &Options::default(),
+        )
+        .unwrap()
+        .warnings;
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_unroll_nested_loops() {
+        // Each loop is short enough to unroll, but all of them together are not
+        let src = "let n = 0; \
+                   for let a = 0; a < 6; a = a + 1 { for let b = 0; b < 6; b = b + 1 { \
+                   for let c = 0; c < 6; c = c + 1 { for let d = 0; d < 6; d = d + 1 { \
+                   n = n + 1; } } } } print_number(n);";
+        let code = compile(src).unwrap();
+        assert!(code.len() < 10_000, "{} bytes", code.len());
+        let mut interpreter = Interpreter::from_code(&code).unwrap();
+        interpreter.run_to_completion().unwrap();
+        // 1296 wraps around to 16
+        assert_eq!(interpreter.output(), "16");
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let src = "fn noisy(x) { print('!'); return x; } \
+                   let a = 0; \
+                   if a && noisy(1) { print('y'); } print('.'); \
+                   a = 1; \
+                   if a && noisy(1) { print('y'); } print('.'); \
+                   if a || noisy(1) { print('y'); } print('.'); \
+                   a = 0; \
+                   if a || noisy(0) { print('y'); } print('.');";
+        assert_eq!(run(src), ".!y.y.!.");
+        assert_eq!(
+            run("let a = 3; let b = 0; print(\"{} {} {} {}\", a && b, a || b, b || b, a && 2);"),
+            "false true false true"
+        );
+        assert_eq!(run("let a = 2; print_number(a && 7);"), "1");
+    }
+
+    #[test]
+    fn test_fused_conditions() {
+        let program = parse(&tokenize_located("print(a <= b);")).unwrap();
+        let [Statement::Print { args, .. }] = program.statements.as_slice() else {
+            panic!("Expected a print statement");
+        };
+        let condition = &args[0];
+        let compile_condition = |fused: bool| {
+            let mut compiler = Compiler::new();
+            compiler.compile_chunk("let a = 3; let b = 4;").unwrap();
+            // Known values would be folded instead
+            compiler.forget_constants();
+            let start = compiler.output.len();
+            let dest = compiler.calloc(1);
+            if fused {
+                compiler.condition(condition, dest).unwrap();
+            } else {
+                compiler.evaluate_expression(condition, dest).unwrap();
+            }
+            compiler.output.len() - start
+        };
+        assert!(compile_condition(true) < compile_condition(false));
+    }
+
+    #[test]
+    fn test_binary_lhs_in_dest() {
+        // The loop keeps the operands from being known at compile time, so nothing is folded
+        let operands = "let i = 0; while i < 250 { i = i + 1; } let a = i - 243; let b = i - 247; ";
+        assert_eq!(
+            run(&format!(
+                "{}print(\"{{}} {{}} {{}} {{}} {{}} {{}} \", a + b, a - b, a * b, a / b, a % b, b - a); \
+                 print(\"{{}} {{}} {{}} \", a == b, a > b, (a + b) * (a - b)); \
+                 a = 1 + a * (b + a); print_number(a);",
+                operands
+            )),
+            "10 4 21 2 1 252 false true 40 71"
+        );
+        // The left operand is evaluated right into the destination, so only the right one takes
+        // a cell of its own
+        let cells = |expr: &str| {
+            let mut compiler = Compiler::new();
+            compiler.compile_chunk(operands).unwrap();
+            compiler.tape_size = 0;
+            compiler
+                .compile_chunk(&format!("let c = {};", expr))
+                .unwrap();
+            compiler.tape_size
+        };
+        assert_eq!(cells("a + b"), cells("a") + 1);
+        assert_eq!(cells("(a + b) - a"), cells("a") + 2);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        assert_eq!(
+            compile("let x = 5;\nlet y = x / 0;").unwrap_err(),
+            "Division by zero in (x / 0) at line 2, column 11"
+        );
+        assert_eq!(
+            compile("let x = 5;\nlet z = 0;\nprint(x % (z * 2));").unwrap_err(),
+            "Division by zero in (x % (z * 2)) at line 3, column 9"
+        );
+        assert!(compile("let x = 5;\nx %= 0;").is_err());
+        // Divisors only known at runtime are left to the generated code
+        assert_eq!(
+            run("let x = 5; let z = 0; if z { z = 1; } print_number(x / z);"),
+            "0"
+        );
+    }
+
+    #[test]
+    fn test_constant_propagation() {
+        let code = |src| compile_with_ir(src, &Options::default()).unwrap().optimized;
+        assert_eq!(
+            code("let a = 5; let b = a + a; print_number(b);"),
+            code("let a = 5; let b = 10; print_number(b);")
+        );
+        assert_eq!(
+            run("let a = 5; let b = a * 2; a = b + 1; print_number(a);"),
+            "11"
+        );
+        assert_eq!(
+            run("let i = 0; let n = 3; while i != n { i = i + 1; } print_number(i);"),
+            "3"
+        );
+        assert_eq!(
+            run("let i = 2; do { i = i - 1; } while i != 0; print_number(i + 7);"),
+            "7"
+        );
+        assert_eq!(
+            run("fn zero() { return 0; } \
+                 let a = 1; if zero() == 0 { a = 2; } print_number(a);"),
+            "2"
+        );
+        // Reading a variable only through its known value still counts as using it
+        let warnings = compile_with_ir("let a = 5; print_number(a);", &Options::default())
+            .unwrap()
+            .warnings;
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    // Loops are only tracked in debug builds
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_loop_balance() {
+        let mut compiler = Compiler::new();
+        compiler.emit("0[-1+0]");
+        assert!(compiler.check_loop_balance().is_ok());
+        compiler.emit("2[1[-]2]");
+        assert!(compiler.check_loop_balance().is_ok());
+        compiler.emit("0[-2]");
+        assert_eq!(
+            compiler.check_loop_balance().unwrap_err(),
+            "Internal error: loop ending at output position 21 ends on cell 2 but started on \
+             cell 0"
+        );
+        // Everything the compiler generates is balanced
+        let src = read_to_string("tests/determinism/scopes.brang").unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile_chunk(&src).unwrap();
+        assert!(compiler.loop_starts.is_empty());
+    }
+
+    #[test]
+    fn test_saturating_arithmetic() {
+        assert_eq!(run("print_number(200 +| 100);"), "255");
+        assert_eq!(run("print_number(200 +% 100);"), "44");
+        assert_eq!(run("print_number(100 +| 100);"), "200");
+        assert_eq!(
+            run("let a = 3; print_number(a -| 5); print_number(a -% 5);"),
+            "0254"
+        );
+        assert_eq!(run("let a = 9; print_number(a -| 5);"), "4");
+    }
+
+    #[test]
+    fn test_compile_to() {
+        let mut sources = vec![read_to_string("tests/compile/hello.brang").unwrap()];
+        for dir in ["tests/compiler", "tests/determinism"] {
+            for entry in read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension() == Some(OsStr::new("brang")) {
+                    sources.push(read_to_string(path).unwrap());
+                }
+            }
+        }
+        let explained = Options {
+            explain: true,
+            line_width: Some(40),
+            ..Options::default()
+        };
+        for options in [Options::default(), explained] {
+            for src in &sources {
+                let compilation = compile_with_ir(src, &options).unwrap();
+                let mut streamed = Vec::new();
+                let summary = compile_to(src, &options, &mut streamed).unwrap();
+                assert_eq!(String::from_utf8(streamed).unwrap(), compilation.optimized);
+                assert_eq!(summary.warnings, compilation.warnings);
+                assert_eq!(summary.tape_size, compilation.tape_size);
+                assert_eq!(summary.traps, compilation.traps);
+            }
+        }
+    }
+
+    #[test]
+    fn test_line_width() {
+        let src = read_to_string("tests/compile/hello.brang").unwrap();
+        let options = Options {
+            line_width: Some(80),
+            ..Options::default()
+        };
+        let wrapped = compile_with_ir(&src, &options).unwrap().optimized;
+        assert!(wrapped.lines().count() > 1);
+        assert!(wrapped.lines().all(|line| line.chars().count() <= 80));
+        let output = |code: &str| {
+            let mut interpreter = Interpreter::from_code(code).unwrap();
+            interpreter.run_to_completion().unwrap();
+            interpreter.output().to_string()
+        };
+        assert_eq!(output(&wrapped), output(&compile(&src).unwrap()));
+    }
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(run("print_number(clamp(200 +% 100, 10, 200));"), "44");
+        assert_eq!(run("print_number(clamp(200 +| 100, 10, 200));"), "200");
+        assert_eq!(
+            run("let lo = 10; let hi = 200; let x = 5; \
+                 print_number(clamp(x, lo, hi)); print(' '); \
+                 print_number(clamp(x + 50, lo, hi)); print(' '); \
+                 print_number(clamp(x - 10, lo, hi)); print(' '); \
+                 print_number(clamp(x, 7, 3)); print(' '); \
+                 print_number(x);"),
+            "10 55 200 3 5"
+        );
+        assert!(compile("clamp(1, 2);").is_err());
+    }
+
+    #[test]
+    fn test_constant_overflow() {
+        let compilation = compile_with_ir("print_number(200 + 100);", &Options::default()).unwrap();
+        assert_eq!(
+            compilation.warnings,
+            ["Constant expression 200 + 100 overflows u8 and wraps around to 44"]
+        );
+        let mut interpreter = Interpreter::from_code(&compilation.optimized).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(interpreter.output(), "44");
+        let warnings = |src| compile_with_ir(src, &Options::default()).unwrap().warnings;
+        assert_eq!(
+            warnings("print_number(2 * (1 - 3));"),
+            ["Constant expression 2 * (1 - 3) overflows u8 and wraps around to 252"]
+        );
+        assert!(warnings("print_number(200 +| 100 - 5);").is_empty());
+        assert!(warnings("print_number(200 +% 100); print_number(3 -% 5);").is_empty());
+    }
+
+    #[test]
+    fn test_max_cell_value() {
+        let warnings = |src, max_cell_value| {
+            let options = Options {
+                max_cell_value,
+                ..Options::default()
+            };
+            compile_with_ir(src, &options).unwrap().warnings
+        };
+        assert_eq!(
+            warnings("let x = 100 * 3; print_number(x);", Some(255)),
+            ["Constant expression 100 * 3 overflows u8 and wraps around to 44"]
+        );
+        assert!(warnings("let x = 10 * 3; print_number(x);", Some(255)).is_empty());
+        let src = "let y = 10; let x = y * 30; print_number(x);";
+        assert!(warnings(src, None).is_empty());
+        assert_eq!(
+            warnings(src, Some(255)),
+            ["Constant expression y * 30 overflows u8 and wraps around to 44"]
+        );
+        assert_eq!(
+            warnings("let x = 10 * 6 - 20; print_number(x);", Some(50)),
+            ["Constant expression (10 * 6) - 20 reaches 60, above the maximum cell value 50"]
+        );
+    }
+
+    #[test]
+    fn test_emit_delta() {
+        for (delta, expected) in [(100, 100), (-100, 156), (200, 200), (5, 5), (-3, 253)] {
+            let mut compiler = Compiler::new();
+            let cell = compiler.calloc(1);
+            let start = compiler.output.len();
+            compiler.set_ptr(cell);
+            compiler.emit_delta(delta);
+            let code: String = compiler.output[start..].iter().collect();
+            if expected > 16 && expected < 240 {
+                assert!(code.contains('['), "{} was not factorized: {}", delta, code);
+                assert!(code.len() < 50, "{}", code);
+            }
+            let mut interpreter = Interpreter::from_code(&code).unwrap();
+            interpreter.run_to_completion().unwrap();
+            assert_eq!(interpreter.memory_snapshot()[cell], expected);
+        }
+    }
+
+    #[test]
+    fn test_string_literals_in_sibling_blocks() {
+        let tape_size = |src| compile_with_ir(src, &Options::default()).unwrap().tape_size;
+        let once = "{ print(\"hello\"); }";
+        let twice = "{ print(\"hello\"); } { print(\"hello\"); }";
+        assert_eq!(tape_size(twice), tape_size(once));
+        assert_eq!(run(twice), "hellohello");
+    }
+
+    #[test]
+    fn test_explain() {
+        let src = "let x = 5;\nwhile x != 0 { x = x - 1; print_number(x); }";
+        let options = Options {
+            explain: true,
+            ..Options::default()
+        };
+        let compilation = compile_with_ir(src, &options).unwrap();
+        for line in [
+            "# let x on line 1\n",
+            "# while\n",
+            "# assign to x on line 2\n",
+        ] {
+            assert!(
+                compilation.optimized.contains(line),
+                "{}",
+                compilation.optimized
+            );
+        }
+        let mut interpreter = Interpreter::from_code(&compilation.optimized).unwrap();
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(interpreter.output(), run(src));
+        assert_eq!(run(src), "43210");
+    }
+
+    #[test]
+    fn test_for_loop() {
+        assert_eq!(
+            run("for let i = 0; i != 4; i = i + 1 { print_number(i); }"),
+            "0123"
+        );
+        let src =
+            "let i = 9; for i = 1; i != 9; i = i * 2 { if i == 4 { break; } print_number(i); }";
+        assert_eq!(run(src), "12");
+        // The loop variable is local to the loop
+        assert_eq!(
+            compile("for let i = 0; i != 2; i = i + 1 { } print(i);").unwrap_err(),
+            "Variable i is not defined"
+        );
+    }
+
+    #[test]
+    fn test_infinite_loop_warning() {
+        let warnings = |src| compile_with_ir(src, &Options::default()).unwrap().warnings;
+        assert_eq!(warnings("while true { }").len(), 1);
+        assert_eq!(warnings("do { } while 1;").len(), 1);
+        assert!(warnings("while true { break; }").is_empty());
+        assert!(warnings("while true { if false { break; } }").is_empty());
+        assert!(warnings("let x = 1; while x { x = 0; }").is_empty());
+        assert_eq!(warnings("while true { while true { break; } }").len(), 1);
+    }
+
+    #[test]
+    fn test_assignment_expression() {
+        assert_eq!(
+            run("let x = 0; print_number(x = 7); print_number(x);"),
+            "77"
+        );
+        let src = "let x = 0; let y = 0; x = (y = 5) + 1; print_number(x); print_number(y);";
+        assert_eq!(run(src), "65");
+        let src = "let x = 0; let y = 0; x = y = 4; print_number(x); print_number(y);";
+        assert_eq!(run(src), "44");
+    }
+
+    #[test]
+    fn test_block_variables_do_not_alias_temporaries() {
+        let src = "if true { let a = 1; } let b = 2; let c = 3; print_number(c); print_number(b);";
+        assert_eq!(run(src), "32");
+    }
+
+    #[test]
+    fn test_sentinel() {
+        let src = "let x = 3; let y = x * 4; while x { print_number(y - x); x = x - 1; }";
+        let options = Options {
+            sentinel: true,
+            ..Options::default()
+        };
+        let code = compile_with_ir(src, &options).unwrap().optimized;
+        let mut interpreter = Interpreter::from_code(&code).unwrap().with_sentinel(true);
+        interpreter.run_to_completion().unwrap();
+        assert_eq!(interpreter.output(), "91011");
+        // Without the option, the first variable lives in the sentinel
+        let code = compile(src).unwrap();
+        let mut interpreter = Interpreter::from_code(&code).unwrap().with_sentinel(true);
+        assert!(matches!(
+            interpreter.run_to_completion(),
+            Err(RunError::SentinelWrite(_))
+        ));
+    }
+
+    #[test]
+    fn test_match() {
+        let src = |x| {
+            format!(
+                "fn pick(x) {{ match x {{ 1 => {{ print(\"one\"); }}, 2 => {{ print(\"two\"); }}, \
+                 _ => {{ print(\"other\"); }} }} }} \
+                 let x = {}; if x {{ x = x; }} pick(x);",
+                x
+            )
+        };
+        assert_eq!(run(&src(1)), "one");
+        assert_eq!(run(&src(2)), "two");
+        assert_eq!(run(&src(7)), "other");
+        // The value is evaluated once, and nested matches get values of their own
+        let src =
+            "let n = 0; match n = n + 1 { 1 => match n + 1 { 2 => print(n); } 2 => print(9); }";
+        assert_eq!(run(src), "1");
+        assert_eq!(run("match 3 { 1 => print(1); }"), "");
+    }
+
+    #[test]
+    fn test_while_reuses_condition_cell() {
+        let mut compiler = Compiler::new();
+        let mut code = compiler.compile_chunk("let n = 0; let i = 200;").unwrap();
+        let stack_ptr = compiler.stack_ptr;
+        // Too many iterations to unroll, so the condition is evaluated at runtime
+        for body in [
+            "while i < 250 { n = n + 3; i = i + 1; }",
+            "while true { if i == 0 { break; } n = n + 1; i = i - 1; }",
+        ] {
+            code += &compiler.compile_chunk(body).unwrap();
+            assert_eq!(compiler.stack_ptr, stack_ptr, "{}", body);
+        }
+        code += &compiler.compile_chunk("print_number(n);").unwrap();
+        let mut interpreter = Interpreter::from_code(&code).unwrap();
+        interpreter.run_to_completion().unwrap();
+        // 50 * 3 + 250 wraps around to 144
+        assert_eq!(interpreter.output(), "144");
+    }
+
+    #[test]
+    fn test_constant_string_concatenation() {
+        let src = "let s = \"ab\" + \"c\" + \"de\"; print(s); s = \"x\" + \"y\"; print(s);";
+        assert_eq!(run(src), "abcdexy");
+        // The strings are added at compile time, so the code is the same as for one literal
+        let literal = "let s = \"abcde\"; print(s); s = \"xy\"; print(s);";
+        assert_eq!(compile(src).unwrap(), compile(literal).unwrap());
+        let mut compiler = Compiler::new();
+        compiler.compile_chunk("let s = \"ab\" + \"c\";").unwrap();
+        assert_eq!(compiler.variables["s"].capacity, 3);
+        assert_eq!(compiler.stack_ptr, 5);
+        assert_eq!(
+            run("print(\"a\" + \"b\"); print(\"{}!\", \"c\" + \"d\");"),
+            "abcd!"
+        );
+        assert!(compile("let s = \"a\" + 1;").is_err());
+    }
+
+    #[test]
+    fn test_bitwise_and_or() {
+        // The values are only known at runtime, so nothing is folded
+        let values = "let a = 255; let b = 1; let z = 0; if z { a = 0; b = 0; z = 1; } ";
+        let src = format!(
+            "{}print_number(a & b); print_number(a & z); print_number(a | z); \
+             print_number(z | z); print_number(a | b); print_number(z & (b = 0)); print_number(b);",
+            values
+        );
+        assert_eq!(run(&src), "1010100");
+        assert_eq!(run("print_number(1 & 1); print_number(0 | 0);"), "10");
+    }
 }
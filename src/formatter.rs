@@ -0,0 +1,480 @@
+use std::{iter::Peekable, slice::Iter};
+
+use crate::{
+    parser::{Anchor, Expr, FormatPiece, Program, Statement},
+    tokenizer::Position,
+};
+
+/// Spaces per level of indentation.
+const INDENT: usize = 4;
+/// How tightly unary operators and casts bind, tighter than any binary operator.
+const UNARY_PRECEDENCE: u8 = 9;
+
+/// Writes `program` back as source code, one statement per line with nested statements indented
+/// and spaces around binary operators. Parentheses are only written where precedence needs
+/// them.
+///
+/// If `program` was parsed with its comments, they are written where they were: on lines of their
+/// own in front of a statement, before the `}` of a block or at the end, or after the statement
+/// or `{` on their line.
+///
+/// # Returns
+/// The formatted source code, or an error if a comment is somewhere it can't be put back, like
+/// in the middle of an expression
+pub(crate) fn format_program(program: &Program) -> Result<String, String> {
+    let mut formatter = Formatter {
+        out: String::new(),
+        depth: 0,
+        comments: program.comments.iter().peekable(),
+        anchors: program.anchors.iter().peekable(),
+        anchored: !program.anchors.is_empty(),
+        line_end: Some(Position {
+            file: 0,
+            line: 1,
+            column: 0,
+        }),
+    };
+    for stmt in &program.statements {
+        formatter.line(stmt)?;
+    }
+    formatter.own_lines(Position {
+        file: 0,
+        line: usize::MAX,
+        column: 0,
+    })?;
+    Ok(formatter.out)
+}
+
+struct Formatter<'a> {
+    out: String,
+    /// How many blocks the statement being written is nested in.
+    depth: usize,
+    /// The comments not written yet.
+    comments: Peekable<Iter<'a, (Position, String)>>,
+    /// The places in the source code not reached yet where comments can be put back.
+    anchors: Peekable<Iter<'a, Anchor>>,
+    /// Whether the program was parsed with its anchors.
+    anchored: bool,
+    /// Where the last line written ends in the source code, or `None` while a line is being
+    /// written.
+    line_end: Option<Position>,
+}
+
+impl Formatter<'_> {
+    /// Writes `stmt` on lines of its own at the current depth, with the comments around it.
+    fn line(&mut self, stmt: &Statement) -> Result<(), String> {
+        let span = match self.anchors.peek() {
+            Some(Anchor::Statement(span)) => {
+                self.anchors.next();
+                Some(*span)
+            }
+            _ => None,
+        };
+        if let Some(span) = span {
+            self.own_lines(span.start)?;
+        }
+        self.line_end = None;
+        self.out.push_str(&" ".repeat(self.depth * INDENT));
+        self.statement(stmt)?;
+        if let Some(span) = span {
+            self.trailing(span.end)?;
+        }
+        self.out.push('\n');
+        Ok(())
+    }
+
+    /// Writes the statements of a block between braces, one level deeper than the braces.
+    fn block(&mut self, statements: &[Statement]) -> Result<(), String> {
+        self.out.push('{');
+        let written = self.out.len();
+        if let Some(Anchor::Open(open)) = self.anchors.peek() {
+            let open = *open;
+            self.anchors.next();
+            self.trailing(open)?;
+        }
+        let close = match self.anchors.peek() {
+            Some(Anchor::Close(close)) if statements.is_empty() => Some(*close),
+            _ => None,
+        };
+        let commented = close.is_some_and(|close| self.comment_before(close));
+        if statements.is_empty() && self.out.len() == written && !commented {
+            self.anchors
+                .next_if(|anchor| matches!(anchor, Anchor::Close(_)));
+            self.out.push('}');
+            return Ok(());
+        }
+        self.out.push('\n');
+        self.depth += 1;
+        for stmt in statements {
+            self.line(stmt)?;
+        }
+        if let Some(Anchor::Close(close)) = self.anchors.peek() {
+            let close = *close;
+            self.anchors.next();
+            self.own_lines(close)?;
+        }
+        self.depth -= 1;
+        self.out.push_str(&" ".repeat(self.depth * INDENT));
+        self.out.push('}');
+        self.line_end = None;
+        Ok(())
+    }
+
+    /// Whether a comment that hasn't been written starts before `position`.
+    fn comment_before(&mut self, position: Position) -> bool {
+        self.comments.peek().is_some_and(|(pos, _)| *pos < position)
+    }
+
+    /// Fails if a comment that hasn't been written starts before `position`, since it's inside
+    /// code written on one line.
+    fn check_placed(&mut self, position: Position) -> Result<(), String> {
+        match self.comments.peek() {
+            Some((pos, _)) if *pos < position => Err(format!(
+                "Could not keep the comment at {} in its place",
+                pos
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the comments that start before `position` on lines of their own at the current
+    /// depth. They can only be written between lines.
+    fn own_lines(&mut self, position: Position) -> Result<(), String> {
+        if self.line_end.is_none() {
+            return self.check_placed(position);
+        }
+        let indent = " ".repeat(self.depth * INDENT);
+        while let Some((_, text)) = self.comments.next_if(|(pos, _)| *pos < position) {
+            self.out.push_str(&indent);
+            self.out.push_str(&comment(text));
+            self.out.push('\n');
+        }
+        Ok(())
+    }
+
+    /// Writes the comments after `end` on the same line at the end of the current line, where
+    /// `end` is where the code just written ends in the source code.
+    fn trailing(&mut self, end: Position) -> Result<(), String> {
+        self.check_placed(end)?;
+        while let Some((_, text)) = self.comments.next_if(|(pos, _)| pos.line == end.line) {
+            self.out.push(' ');
+            self.out.push_str(&comment(text));
+        }
+        self.line_end = Some(end);
+        Ok(())
+    }
+
+    /// Writes `stmt` from the current position, without a line break after it.
+    fn statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        use Statement as S;
+        match stmt {
+            S::FunctionDefinition { name, params, body } => {
+                self.out
+                    .push_str(&format!("fn {}({}) ", name, params.join(", ")));
+                self.statement(body)?;
+            }
+            S::VariableDefinition {
+                name,
+                ty,
+                initializer,
+                ..
+            } => {
+                self.out.push_str(&format!("let {}", name));
+                if let Some(ty) = ty {
+                    self.out.push_str(&format!(": {}", ty));
+                }
+                if let Some(init) = initializer {
+                    self.out.push_str(&format!(" = {}", expression(init)));
+                }
+                self.out.push(';');
+            }
+            S::Assignment { name, value, .. } => {
+                self.out
+                    .push_str(&format!("{} = {};", name, expression(value)));
+            }
+            S::Return(Some(value)) => self.out.push_str(&format!("return {};", expression(value))),
+            S::Return(None) => self.out.push_str("return;"),
+            S::Print { format, args } => self.out.push_str(&print(format, args)),
+            S::Expression(expr) => self.out.push_str(&format!("{};", expression(expr))),
+            S::Block(statements) => match self.for_loop(statements) {
+                Some((init, condition, step, body)) => {
+                    self.out.push_str("for ");
+                    self.statement(init)?;
+                    self.out
+                        .push_str(&format!(" {}; {} ", expression(condition), step));
+                    self.statement(body)?;
+                }
+                None => self.block(statements)?,
+            },
+            S::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.out.push_str(&format!("if {} ", expression(condition)));
+                self.statement(then_branch)?;
+                if let Some(else_branch) = else_branch {
+                    self.out.push_str(" else ");
+                    self.statement(else_branch)?;
+                }
+            }
+            S::While { condition, body } => {
+                self.out
+                    .push_str(&format!("while {} ", expression(condition)));
+                self.statement(body)?;
+            }
+            S::DoWhile { body, condition } => {
+                self.out.push_str("do ");
+                self.statement(body)?;
+                self.out
+                    .push_str(&format!(" while {};", expression(condition)));
+            }
+            S::Break => self.out.push_str("break;"),
+            S::LabeledBlock { label, body } => {
+                self.out.push_str(&format!("{}: ", label));
+                self.block(body)?;
+            }
+            S::Exit(label) => self.out.push_str(&format!("exit {};", label)),
+            S::Match {
+                value,
+                arms,
+                default,
+            } => {
+                self.out
+                    .push_str(&format!("match {} {{\n", expression(value)));
+                self.depth += 1;
+                let arms = arms
+                    .iter()
+                    .map(|arm| (expression(&arm.pattern), &arm.body))
+                    .chain(default.iter().map(|body| ("_".to_string(), body.as_ref())));
+                for (pattern, body) in arms {
+                    self.out.push_str(&" ".repeat(self.depth * INDENT));
+                    self.out.push_str(&format!("{} => ", pattern));
+                    self.statement(body)?;
+                    self.out.push('\n');
+                }
+                self.depth -= 1;
+                self.out.push_str(&" ".repeat(self.depth * INDENT));
+                self.out.push('}');
+            }
+        }
+        Ok(())
+    }
+
+    /// The parts of `statements` as a for loop, if they are one. Without anchors, any block
+    /// shaped like the ones for loops are parsed into is taken for one.
+    fn for_loop<'s>(
+        &mut self,
+        statements: &'s [Statement],
+    ) -> Option<(&'s Statement, &'s Expr, String, &'s Statement)> {
+        if self.anchored && self.anchors.next_if_eq(&&Anchor::For).is_none() {
+            return None;
+        }
+        for_loop(statements)
+    }
+}
+
+/// The parts of a for loop, if `statements` is what the parser desugars one into:
+/// `{ init; while condition { body step; } }`. The step is returned as source code, since it's
+/// written without its semicolon.
+fn for_loop(statements: &[Statement]) -> Option<(&Statement, &Expr, String, &Statement)> {
+    use Statement as S;
+    let [init, S::While { condition, body }] = statements else {
+        return None;
+    };
+    if !matches!(init, S::VariableDefinition { .. } | S::Assignment { .. }) {
+        return None;
+    }
+    let S::Block(body) = body.as_ref() else {
+        return None;
+    };
+    match body.as_slice() {
+        [body, S::Assignment { name, value, .. }] => Some((
+            init,
+            condition,
+            format!("{} = {}", name, expression(value)),
+            body,
+        )),
+        _ => None,
+    }
+}
+
+/// A print statement. A single value is printed as it is, unless it's a string literal, which
+/// would be read back as a format string.
+fn print(format: &[FormatPiece], args: &[Expr]) -> String {
+    match (format, args) {
+        ([FormatPiece::Placeholder], [arg]) if !matches!(arg, Expr::String(_)) => {
+            format!("print({});", expression(arg))
+        }
+        _ => {
+            let mut source = String::from("print(\"");
+            for piece in format {
+                match piece {
+                    FormatPiece::Text(text) => {
+                        let text = text.replace('{', "{{").replace('}', "}}");
+                        source.push_str(&escape(&text, '"'));
+                    }
+                    FormatPiece::Placeholder => source.push_str("{}"),
+                }
+            }
+            source.push('"');
+            for arg in args {
+                source.push_str(&format!(", {}", expression(arg)));
+            }
+            source.push_str(");");
+            source
+        }
+    }
+}
+
+/// Writes an expression with only the parentheses precedence needs.
+fn expression(expr: &Expr) -> String {
+    match expr {
+        Expr::Unary { op, rhs } => format!("{}{}", op, operand(rhs, UNARY_PRECEDENCE)),
+        Expr::Binary { lhs, op, rhs, .. } => {
+            // Operators are left-associative, so an operation of the same precedence on the
+            // right needs parentheses
+            let precedence = op.precedence();
+            format!(
+                "{} {} {}",
+                operand(lhs, precedence),
+                op,
+                operand(rhs, precedence + 1)
+            )
+        }
+        Expr::Number(n) => n.to_string(),
+        Expr::Char(c) => format!("'{}'", escape(&c.to_string(), '\'')),
+        Expr::Boolean(b) => b.to_string(),
+        Expr::String(s) => format!("\"{}\"", escape(s, '"')),
+        Expr::Identifier(name) => name.clone(),
+        Expr::FunctionCall { callee, args } => {
+            let args: Vec<String> = args.iter().map(expression).collect();
+            format!("{}({})", callee, args.join(", "))
+        }
+        Expr::Assignment { name, value, .. } => format!("{} = {}", name, expression(value)),
+        Expr::Cast { ty, value } => format!("({}) {}", ty, operand(value, UNARY_PRECEDENCE)),
+    }
+}
+
+/// Writes an operand, in parentheses if it binds looser than `min_precedence`.
+fn operand(expr: &Expr, min_precedence: u8) -> String {
+    let precedence = match expr {
+        // Assignment binds loosest of all
+        Expr::Assignment { .. } => 0,
+        Expr::Binary { op, .. } => op.precedence(),
+        Expr::Unary { .. } | Expr::Cast { .. } => UNARY_PRECEDENCE,
+        _ => UNARY_PRECEDENCE + 1,
+    };
+    if precedence < min_precedence {
+        format!("({})", expression(expr))
+    } else {
+        expression(expr)
+    }
+}
+
+/// A comment with the text `text`, as a line comment unless the text spans several lines.
+fn comment(text: &str) -> String {
+    if text.contains('\n') {
+        format!("/*{}*/", text)
+    } else {
+        format!("//{}", text.trim_end())
+    }
+}
+
+/// Escapes `text` for a literal delimited by `quote`, the way the tokenizer reads escapes back.
+fn escape(text: &str, quote: char) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            // Strings have no escape for it, but the character can appear in them as it is
+            '\0' if quote == '\'' => escaped.push_str("\\0"),
+            '\\' => escaped.push_str("\\\\"),
+            c if c == quote => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::compile,
+        parser::{parse, parse_with_comments},
+        tokenizer::tokenize_located,
+    };
+
+    fn format_src(src: &str) -> String {
+        format_program(&parse(&tokenize_located(src)).unwrap()).unwrap()
+    }
+
+    const SAMPLE: &str = r#"
+        fn clamp(x, lo, hi) { if x < lo { return lo; } elif x > hi { return hi; } return x; }
+        let greeting: str = "say \"hi\"\n"; let c = '\''; let n = 3;
+        for let i = 0; i < n; i = i + 1 { print("{} {{ok}}\t", clamp(i * 2, 1, 4)); }
+        while n != 0 n = n - 1;
+        outer: { do { exit outer; } while true; }
+        match (char) n + 1 { 0 => print("zero"); 'a' => { } _ => { print(!(n == 2)); } }
+        print(greeting); print("{}", "{"); print(c); print(-(n - 1) * (2 - (1 - 1)));
+    "#;
+
+    #[test]
+    fn test_idempotent() {
+        let formatted = format_src(SAMPLE);
+        assert_eq!(format_src(&formatted), formatted);
+        assert_eq!(compile(&formatted).unwrap(), compile(SAMPLE).unwrap());
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(
+            format_src("fn f(a,b){return a*(b+1)-(a-b);}let x=f(1,2)+3*4;"),
+            "fn f(a, b) {\n    return a * (b + 1) - (a - b);\n}\nlet x = f(1, 2) + 3 * 4;\n"
+        );
+        assert_eq!(
+            format_src("for let i = 0; i < 3; i = i + 1 { if i { } else { break; } }"),
+            "for let i = 0; i < 3; i = i + 1 {\n    if i {} else {\n        break;\n    }\n}\n"
+        );
+        assert_eq!(
+            format_src("let x = -(1 + 2); x = (y = 1) + !x; print((u8) (x | 1));"),
+            "let x = -(1 + 2);\nx = (y = 1) + !x;\nprint((u8) (x | 1));\n"
+        );
+        assert_eq!(
+            format_src("x = x+%200-%(y+%1);"),
+            "x = x +% 200 -% (y +% 1);\n"
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let format = |src: &str| format_program(&parse_with_comments(&tokenize_located(src))?);
+        let src = "// header\nlet x = 1; // trailing note\nwhile x {\n    x = x - 1;\n    \
+                   // TODO: explain\n}\n// end of file\n";
+        assert_eq!(format(src).unwrap(), src);
+        let src = "// Doubles\nfn f(x) { /* a\n  b */\n  return x * 2; /* twice */ }\n\
+                   if f(1) { // Empty\n} else {\n// First\n print(1); }\n\
+                   for let i = 0; i < 2; i = i + 1 { }\n{ let i = 0; while i < 2 { i = i + 1; } }";
+        let formatted = format(src).unwrap();
+        assert_eq!(
+            formatted,
+            "// Doubles\nfn f(x) { /* a\n  b */\n    return x * 2; // twice\n}\n\
+             if f(1) { // Empty\n} else {\n    // First\n    print(1);\n}\n\
+             for let i = 0; i < 2; i = i + 1 {}\n\
+             {\n    let i = 0;\n    while i < 2 {\n        i = i + 1;\n    }\n}\n"
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+        // Comments inside code that is written on one line can't be kept
+        assert_eq!(
+            format("if x { } else\n// Else\nprint(0);"),
+            Err("Could not keep the comment at line 2, column 1 in its place".to_string())
+        );
+        assert!(format("print(1 + /* one */ 1);").is_err());
+    }
+}
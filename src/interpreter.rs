@@ -1,28 +1,145 @@
 use core::fmt;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
-pub fn run(filepath: &str) {
-    let mut interpreter = Interpreter::new(filepath);
+use crate::brainfuck::{has_traps, match_brackets, tape_size, RunError, DEFAULT_TAPE_SIZE};
 
-    while interpreter.step() {
+/// Default width of the dashboard printed by [`run`], in characters.
+pub const DEFAULT_WIDTH: usize = 148;
+/// Default number of characters per memory cell in the dashboard, divided by two.
+pub const DEFAULT_DELTA: usize = 4;
+
+/// How [`run`] and [`dump`] set up the interpreter.
+pub struct Settings {
+    /// Width of the printed state in characters.
+    pub width: usize,
+    /// Characters per memory cell divided by two.
+    pub delta: usize,
+    pub color: bool,
+    pub max_output: Option<usize>,
+    pub wrap: bool,
+    pub skip_whitespace: bool,
+    /// Print the state of the interpreter to stderr when the program fails.
+    pub dump_on_error: bool,
+    /// Stop the program when it changes the first cell.
+    pub sentinel: bool,
+}
+
+pub fn run(filepath: &str, settings: &Settings, breakpoints: bool) -> Result<(), RunError> {
+    let mut interpreter = open(filepath, settings)?.with_breakpoints(breakpoints);
+    loop {
+        if interpreter.at_breakpoint() {
+            println!(
+                "{}\n\nBreakpoint at instruction {}, press enter to continue",
+                interpreter, interpreter.instruction_ptr
+            );
+            std::io::stdin().read_line(&mut String::new())?;
+        }
+        if !interpreter
+            .step()
+            .inspect_err(|_| dump_on_error(&interpreter, settings))?
+        {
+            break;
+        }
         println!("{}\n", interpreter);
         std::thread::sleep(std::time::Duration::from_millis(20));
     }
+    Ok(())
+}
+
+/// Runs the program at `filepath` to completion without animating it, then prints the final
+/// tape, pointer and output.
+pub fn dump(filepath: &str, settings: &Settings) -> Result<(), RunError> {
+    let mut interpreter = open(filepath, settings)?;
+    interpreter
+        .run_to_completion()
+        .inspect_err(|_| dump_on_error(&interpreter, settings))?;
+    println!("{}", interpreter);
+    Ok(())
+}
+
+/// Prints the state of `interpreter` to stderr if the settings ask for it, after it failed.
+fn dump_on_error(interpreter: &Interpreter, settings: &Settings) {
+    if settings.dump_on_error {
+        eprintln!(
+            "{}\n\nStopped at instruction {}",
+            interpreter, interpreter.instruction_ptr
+        );
+    }
+}
+
+fn open(filepath: &str, settings: &Settings) -> Result<Interpreter, RunError> {
+    let mut interpreter = Interpreter::new(filepath)?
+        .with_display_size(settings.width, settings.delta)
+        .with_color(settings.color)
+        .with_output_limit(settings.max_output)
+        .with_whitespace_skipped(settings.skip_whitespace)
+        .with_sentinel(settings.sentinel);
+    if settings.wrap {
+        if interpreter.traps {
+            return Err(RunError::WrappedTraps);
+        }
+        let size = interpreter.tape_size;
+        interpreter = interpreter.with_wrapping_tape(size);
+    }
+    Ok(interpreter)
+}
+
+/// Where [`Interpreter::run_until_input`] stopped.
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+pub(crate) enum Pause {
+    /// The next instruction reads input, but none has been provided.
+    NeedsInput,
+    /// There are no instructions left to run.
+    Finished,
+    /// A `#` was reached with breakpoints enabled. Running again continues after it.
+    Breakpoint,
 }
 
-struct Interpreter {
+pub(crate) struct Interpreter {
+    /// The tape. It is grown by doubling its length, so it may be longer than the part reached.
     memory: Vec<u8>,
+    /// The number of cells from the first one to the rightmost one reached so far.
+    reached: usize,
     memory_ptr: usize,
+    /// If set, the tape is circular with this many cells. The tape is still only grown once
+    /// cells are reached.
+    wrap_at: Option<usize>,
+    /// The number of cells the loaded program says it needs, or the default tape size.
+    tape_size: usize,
+    /// Whether the loaded program stops itself by moving left of the first cell.
+    traps: bool,
     instructions: Vec<char>,
     instruction_ptr: usize,
     brackets: Vec<usize>,
     output: String,
+    /// The number of bytes printed so far. Bytes above 127 take two bytes in `output`.
+    printed: usize,
+    /// Bytes provided with [`Interpreter::provide_input`] that haven't been read yet. Input is
+    /// read from stdin once they run out, unless `stdin_input` is unset.
+    input: VecDeque<u8>,
+    /// Whether `,` reads from stdin once the provided input runs out. It is unset when the
+    /// program itself came from stdin, so the program never reads its own source.
+    stdin_input: bool,
+    /// The most bytes of output the program may print before it is stopped.
+    max_output: Option<usize>,
+    /// Whether `,` skips whitespace instead of reading it.
+    skip_whitespace: bool,
+    /// Whether a `#` pauses [`Interpreter::run_until_input`] instead of being skipped.
+    breakpoints: bool,
+    /// Whether the first cell is reserved, so changing it stops the program.
+    sentinel: bool,
+    width: usize,
+    delta: usize,
+    color: bool,
 }
 
 fn getchar() -> Option<char> {
     std::io::stdin()
+        .lock()
         .bytes()
         .next()
         .and_then(|res| res.ok())
@@ -30,53 +147,279 @@ fn getchar() -> Option<char> {
 }
 
 impl Interpreter {
-    fn new(filepath: &str) -> Self {
-        let mut file = File::open(Path::new(filepath)).expect("could not open file");
+    /// Loads the program at `filepath`. A path of `-` reads the program and its input from
+    /// stdin, as described in [`Interpreter::from_stream`].
+    fn new(filepath: &str) -> Result<Self, RunError> {
         let mut code = String::new();
-        file.read_to_string(&mut code)
-            .expect("could not read file to string");
-        let instructions: Vec<char> = code.chars().collect();
+        if filepath == "-" {
+            std::io::stdin().read_to_string(&mut code)?;
+            return Self::from_stream(&code);
+        }
+        let mut file = File::open(Path::new(filepath))?;
+        file.read_to_string(&mut code)?;
+        Self::from_code(&code)
+    }
+
+    /// Creates an interpreter for a stream holding both a program and its input, separated by
+    /// the first `!`. The whole stream is read before the program starts, so `,` reads what
+    /// follows the `!` and fails once that runs out, instead of reading more of the stream.
+    pub(crate) fn from_stream(stream: &str) -> Result<Self, RunError> {
+        let (code, input) = stream.split_once('!').unwrap_or((stream, ""));
+        let mut interpreter = Self::from_code(code)?;
+        interpreter.input.extend(input.bytes());
+        interpreter.stdin_input = false;
+        Ok(interpreter)
+    }
+
+    /// Creates an interpreter running `code`, checking up front that its brackets are matched.
+    pub(crate) fn from_code(code: &str) -> Result<Self, RunError> {
+        let mut interpreter = Self::empty();
+        if let Some(size) = tape_size(code)? {
+            // The program says how many cells it needs, so the tape never has to grow
+            interpreter = interpreter.with_initial_tape_size(size);
+            interpreter.tape_size = size;
+        }
+        interpreter.traps = has_traps(code);
+        interpreter.load(code);
+        match_brackets(&interpreter.instructions)?;
+        Ok(interpreter)
+    }
+
+    /// Creates an interpreter with no instructions and a zeroed tape.
+    pub(crate) fn empty() -> Self {
         Self {
             memory: vec![0; 1],
+            reached: 1,
             memory_ptr: 0,
-            instructions,
+            wrap_at: None,
+            tape_size: DEFAULT_TAPE_SIZE,
+            traps: false,
+            instructions: Vec::new(),
             instruction_ptr: 0,
             brackets: Vec::new(),
             output: String::new(),
+            printed: 0,
+            input: VecDeque::new(),
+            stdin_input: true,
+            max_output: None,
+            skip_whitespace: false,
+            breakpoints: false,
+            sentinel: false,
+            width: DEFAULT_WIDTH,
+            delta: DEFAULT_DELTA,
+            color: false,
+        }
+    }
+
+    /// Sets the dimensions used when displaying the interpreter. Instructions wrap after `width`
+    /// characters and memory wraps after `width / (delta * 2)` cells.
+    pub(crate) fn with_display_size(mut self, width: usize, delta: usize) -> Self {
+        self.width = width.max(1);
+        self.delta = delta.max(1);
+        self
+    }
+
+    /// Sets whether the current cell and instruction are highlighted with ANSI escape codes when
+    /// displaying the interpreter.
+    pub(crate) fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Allocates `size` cells for the tape up front, instead of growing it as cells are reached.
+    pub(crate) fn with_initial_tape_size(mut self, size: usize) -> Self {
+        self.memory.resize(size.max(self.memory.len()), 0);
+        self
+    }
+
+    /// Makes the tape circular with `size` cells, so moving left of the first cell lands on the
+    /// last one and moving right of the last cell lands on the first one.
+    pub(crate) fn with_wrapping_tape(mut self, size: usize) -> Self {
+        self.wrap_at = Some(size.max(1));
+        self
+    }
+
+    /// Sets how many bytes of output the program may print before running it fails, so a program
+    /// printing forever can't use up all memory.
+    pub(crate) fn with_output_limit(mut self, max_output: Option<usize>) -> Self {
+        self.max_output = max_output;
+        self
+    }
+
+    /// Makes `,` skip ASCII whitespace and read the first byte after it, for programs reading
+    /// numbers or words.
+    pub(crate) fn with_whitespace_skipped(mut self, skip_whitespace: bool) -> Self {
+        self.skip_whitespace = skip_whitespace;
+        self
+    }
+
+    /// Makes every bare `#` a breakpoint, pausing [`Interpreter::run_until_input`] when it is
+    /// reached. Otherwise `#` does nothing.
+    pub(crate) fn with_breakpoints(mut self, breakpoints: bool) -> Self {
+        self.breakpoints = breakpoints;
+        self
+    }
+
+    /// Reserves the first cell as a sentinel, so changing it with `+`, `-` or `,` fails. Code
+    /// compiled with the `sentinel` option never uses the first cell.
+    pub(crate) fn with_sentinel(mut self, sentinel: bool) -> Self {
+        self.sentinel = sentinel;
+        self
+    }
+
+    /// Appends brainfuck code to the instructions. The tape, pointers and output are kept, so
+    /// execution resumes from where the previously loaded code finished. Everything after a `#`
+    /// to the end of its line is a comment and is skipped. A `#` with nothing after it on its
+    /// line is kept as a possible breakpoint, while one starting a comment, like the ones the
+    /// compiler writes, is not.
+    pub(crate) fn load(&mut self, code: &str) {
+        for line in code.split_inclusive('\n') {
+            match line.split_once('#') {
+                Some((code, comment)) => {
+                    self.instructions.extend(code.chars());
+                    if comment.trim().is_empty() {
+                        self.instructions.push('#');
+                    }
+                }
+                None => self.instructions.extend(line.chars()),
+            }
+        }
+    }
+
+    /// Steps through the loaded instructions until there are none left.
+    pub(crate) fn run_to_completion(&mut self) -> Result<(), RunError> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Runs until the program finishes, is about to read input that hasn't been provided, or
+    /// reaches a breakpoint, letting the caller provide input or inspect the state in between.
+    #[cfg(test)]
+    pub(crate) fn run_until_input(&mut self) -> Result<Pause, RunError> {
+        loop {
+            if self.at_breakpoint() {
+                self.step()?;
+                return Ok(Pause::Breakpoint);
+            }
+            if self.instructions.get(self.instruction_ptr) == Some(&',') && !self.has_input() {
+                return Ok(Pause::NeedsInput);
+            }
+            if !self.step()? {
+                return Ok(Pause::Finished);
+            }
+        }
+    }
+
+    /// Whether a byte that `,` would read has been provided.
+    #[cfg(test)]
+    fn has_input(&self) -> bool {
+        self.input
+            .iter()
+            .any(|byte| !(self.skip_whitespace && byte.is_ascii_whitespace()))
+    }
+
+    /// Reads the next byte of input, from the provided bytes if there are any left and from stdin
+    /// otherwise.
+    fn read_byte(&mut self) -> Result<u8, RunError> {
+        loop {
+            let byte = match self.input.pop_front() {
+                Some(byte) => byte,
+                None if !self.stdin_input => {
+                    return Err(RunError::InputExhausted(self.instruction_ptr))
+                }
+                None => loop {
+                    if let Some(c) = getchar() {
+                        break c as u8;
+                    }
+                },
+            };
+            if !(self.skip_whitespace && byte.is_ascii_whitespace()) {
+                return Ok(byte);
+            }
         }
     }
 
-    fn step(&mut self) -> bool {
+    /// Whether the next instruction is a `#` and breakpoints are enabled.
+    fn at_breakpoint(&self) -> bool {
+        self.breakpoints && self.instructions.get(self.instruction_ptr) == Some(&'#')
+    }
+
+    /// Queues a byte to be read by the next `,` instead of reading from stdin.
+    #[cfg(test)]
+    pub(crate) fn provide_input(&mut self, byte: u8) {
+        self.input.push_back(byte);
+    }
+
+    pub(crate) fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// The cells of the tape that have been reached so far.
+    pub(crate) fn memory_snapshot(&self) -> &[u8] {
+        &self.memory[..self.reached]
+    }
+
+    /// The index of the cell the memory pointer is at.
+    #[cfg(test)]
+    pub(crate) fn pointer(&self) -> usize {
+        self.memory_ptr
+    }
+
+    /// Runs the next instruction.
+    ///
+    /// # Returns
+    /// Whether there was an instruction left to run
+    fn step(&mut self) -> Result<bool, RunError> {
         if self.instruction_ptr == self.instructions.len() {
-            return false;
+            return Ok(false);
         }
         let instruction = self.instructions[self.instruction_ptr];
         match instruction {
+            '>' if self.wrap_at == Some(self.memory_ptr + 1) => self.memory_ptr = 0,
             '>' => {
-                if self.memory.len() - 1 == self.memory_ptr {
-                    self.memory.push(0);
-                }
                 self.memory_ptr += 1;
+                if self.memory_ptr == self.memory.len() {
+                    self.memory.resize(self.memory.len() * 2, 0);
+                }
+                self.reached = self.reached.max(self.memory_ptr + 1);
+            }
+            '<' => match (self.memory_ptr.checked_sub(1), self.wrap_at) {
+                (Some(ptr), _) => self.memory_ptr = ptr,
+                (None, Some(size)) => {
+                    self.memory.resize(size.max(self.memory.len()), 0);
+                    self.memory_ptr = size - 1;
+                    self.reached = self.reached.max(size);
+                }
+                (None, None) => return Err(RunError::PointerUnderflow(self.instruction_ptr)),
+            },
+            '+' | '-' | ',' if self.sentinel && self.memory_ptr == 0 => {
+                return Err(RunError::SentinelWrite(self.instruction_ptr))
             }
-            '<' => self.memory_ptr -= 1,
             '+' => self.memory[self.memory_ptr] = self.memory[self.memory_ptr].wrapping_add(1),
             '-' => self.memory[self.memory_ptr] = self.memory[self.memory_ptr].wrapping_sub(1),
-            ',' => {
-                let c = loop {
-                    if let Some(c) = getchar() {
-                        break c;
+            ',' => self.memory[self.memory_ptr] = self.read_byte()?,
+            '.' => {
+                if let Some(max_output) = self.max_output {
+                    if self.printed >= max_output {
+                        return Err(RunError::OutputLimit(max_output));
                     }
-                };
-                self.memory[self.memory_ptr] = c as u8;
+                }
+                self.output.push(self.memory[self.memory_ptr] as char);
+                self.printed += 1;
             }
-            '.' => self.output.push(self.memory[self.memory_ptr] as char),
             '[' => {
                 if self.memory[self.memory_ptr] != 0 {
                     self.brackets.push(self.instruction_ptr);
                 } else {
+                    // Code added with `load` hasn't had its brackets matched
+                    let start = self.instruction_ptr;
                     let mut depth = 0;
                     loop {
                         self.instruction_ptr += 1;
+                        if self.instruction_ptr == self.instructions.len() {
+                            self.instruction_ptr = start;
+                            return Err(RunError::UnmatchedBracket(start));
+                        }
                         if self.instructions[self.instruction_ptr] == ']' {
                             if depth == 0 {
                                 break;
@@ -90,41 +433,48 @@ impl Interpreter {
                 }
             }
             ']' => {
+                let Some(&start) = self.brackets.last() else {
+                    return Err(RunError::UnmatchedBracket(self.instruction_ptr));
+                };
                 if self.memory[self.memory_ptr] != 0 {
-                    self.instruction_ptr = *self
-                        .brackets
-                        .last()
-                        .expect("found unmatched closing square bracket");
+                    self.instruction_ptr = start;
                 } else {
                     self.brackets.pop();
                 }
             }
-            _ => self.instruction_ptr += 1,
+            _ => (),
         }
         self.instruction_ptr += 1;
-        true
+        Ok(true)
     }
 }
 
 impl fmt::Display for Interpreter {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        const WIDTH: usize = 148;
-        const DELTA: usize = 4;
+        const HIGHLIGHT: &str = "\x1b[1;33m";
+        const RESET: &str = "\x1b[0m";
+        let (highlight, reset) = if self.color {
+            (HIGHLIGHT, RESET)
+        } else {
+            ("", "")
+        };
+        let width = self.width;
+        let cells_per_line = (width / (self.delta * 2)).max(1);
 
-        // let left_limit = if self.memory_ptr <= WIDTH / (DELTA * 2) {
+        // let left_limit = if self.memory_ptr <= cells_per_line {
         //     0
         // } else {
-        //     self.memory_ptr - WIDTH / (DELTA * 2)
+        //     self.memory_ptr - cells_per_line
         // };
 
         write!(f, "Memory:")?;
 
-        for (i, m) in self.memory.iter().enumerate() {
-            if i % (WIDTH / (DELTA * 2)) == 0 {
+        for (i, m) in self.memory_snapshot().iter().enumerate() {
+            if i % cells_per_line == 0 {
                 writeln!(f)?;
             }
             if i == self.memory_ptr {
-                write!(f, "[{:02x}", m)?;
+                write!(f, "[{}{:02x}{}", highlight, m, reset)?;
             } else if i == self.memory_ptr + 1 {
                 write!(f, "]{:02x}", m)?;
             } else {
@@ -132,23 +482,284 @@ impl fmt::Display for Interpreter {
             }
         }
 
-        if self.memory_ptr == self.memory.len() - 1 {
+        if self.memory_ptr == self.reached - 1 {
             write!(f, "]")?;
         }
 
         write!(f, "\n\nInstructions:")?;
 
         for (i, c) in self.instructions.iter().enumerate() {
-            if i % WIDTH == 0 {
-                if self.instruction_ptr >= i && self.instruction_ptr < i + WIDTH {
-                    writeln!(f, "\n{:>1$}", "v", self.instruction_ptr % WIDTH + 1)?;
+            if i % width == 0 {
+                if self.instruction_ptr >= i && self.instruction_ptr < i + width {
+                    writeln!(f, "\n{:>1$}", "v", self.instruction_ptr % width + 1)?;
                 } else {
                     writeln!(f)?;
                 }
             }
-            write!(f, "{}", c)?;
+            if i == self.instruction_ptr {
+                write!(f, "{}{}{}", highlight, c, reset)?;
+            } else {
+                write!(f, "{}", c)?;
+            }
         }
 
         write!(f, "\n\nOutput:\n{}", self.output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brainfuck::tape_size_comment, compiler};
+
+    #[test]
+    fn test_memory_snapshot() -> Result<(), RunError> {
+        let mut interpreter = Interpreter::empty();
+        interpreter.load("+++>++>+<");
+        interpreter.run_to_completion()?;
+        assert_eq!(interpreter.memory_snapshot(), &[3, 2, 1]);
+        assert_eq!(interpreter.pointer(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_code() -> Result<(), RunError> {
+        let mut interpreter = Interpreter::from_code("++++++++[>++++++++<-]>+.+.")?;
+        interpreter.run_to_completion()?;
+        assert_eq!(interpreter.output(), "AB");
+        assert!(matches!(
+            Interpreter::from_code("+[>+"),
+            Err(RunError::UnmatchedBracket(1))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pointer_underflow() {
+        let mut interpreter = Interpreter::empty();
+        interpreter.load("+.><<+");
+        assert!(matches!(
+            interpreter.run_to_completion(),
+            Err(RunError::PointerUnderflow(4))
+        ));
+        assert_eq!(interpreter.output(), "\x01");
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let mut interpreter = Interpreter::from_code("+++[.]")
+            .unwrap()
+            .with_output_limit(Some(5));
+        assert!(matches!(
+            interpreter.run_to_completion(),
+            Err(RunError::OutputLimit(5))
+        ));
+        assert_eq!(interpreter.output(), "\x03".repeat(5));
+        // Bytes above 127 count once, like any other byte
+        let mut interpreter = Interpreter::from_code("-[.]")
+            .unwrap()
+            .with_output_limit(Some(5));
+        assert!(matches!(
+            interpreter.run_to_completion(),
+            Err(RunError::OutputLimit(5))
+        ));
+        assert_eq!(interpreter.output(), "\u{ff}".repeat(5));
+    }
+
+    #[test]
+    fn test_run_until_input() -> Result<(), RunError> {
+        // Prompts with `?` and echoes back each character read, until it reads a 0
+        let mut interpreter = Interpreter::from_code("+[[-]>+++++++[<+++++++++>-]<.[-],.]")?;
+        assert_eq!(interpreter.run_until_input()?, Pause::NeedsInput);
+        assert_eq!(interpreter.output(), "?");
+        interpreter.provide_input(b'a');
+        assert_eq!(interpreter.run_until_input()?, Pause::NeedsInput);
+        assert_eq!(interpreter.output(), "?a?");
+        interpreter.provide_input(b'b');
+        interpreter.provide_input(0);
+        assert_eq!(interpreter.run_until_input()?, Pause::Finished);
+        assert_eq!(interpreter.output(), "?a?b?\0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_whitespace() -> Result<(), RunError> {
+        let mut interpreter = Interpreter::from_code(",>,")?.with_whitespace_skipped(true);
+        for byte in b"  \n\t4 2" {
+            interpreter.provide_input(*byte);
+        }
+        interpreter.step()?;
+        assert_eq!(interpreter.memory_snapshot(), b"4");
+        interpreter.run_to_completion()?;
+        assert_eq!(interpreter.memory_snapshot(), b"42");
+
+        let mut interpreter = Interpreter::from_code(",")?.with_whitespace_skipped(true);
+        interpreter.provide_input(b' ');
+        assert_eq!(interpreter.run_until_input()?, Pause::NeedsInput);
+        let mut interpreter = Interpreter::from_code(",")?;
+        interpreter.provide_input(b' ');
+        assert_eq!(interpreter.run_until_input()?, Pause::Finished);
+        assert_eq!(interpreter.memory_snapshot(), b" ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_sentinel() -> Result<(), RunError> {
+        let mut interpreter = Interpreter::from_code(">+<<")?.with_sentinel(true);
+        assert!(matches!(
+            interpreter.run_to_completion(),
+            Err(RunError::PointerUnderflow(3))
+        ));
+        let mut interpreter = Interpreter::from_code(">+[-<+>]")?.with_sentinel(true);
+        assert!(matches!(
+            interpreter.run_to_completion(),
+            Err(RunError::SentinelWrite(5))
+        ));
+        let mut interpreter = Interpreter::from_code("+")?;
+        interpreter.run_to_completion()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unmatched_loaded_brackets() {
+        let mut interpreter = Interpreter::empty();
+        interpreter.load(">[+");
+        assert!(matches!(
+            interpreter.run_to_completion(),
+            Err(RunError::UnmatchedBracket(1))
+        ));
+        let mut interpreter = Interpreter::empty();
+        interpreter.load("+]");
+        assert!(matches!(
+            interpreter.run_to_completion(),
+            Err(RunError::UnmatchedBracket(1))
+        ));
+    }
+
+    #[test]
+    fn test_from_stream() -> Result<(), RunError> {
+        let mut interpreter = Interpreter::from_stream(",.>,.\n!hi!")?;
+        interpreter.run_to_completion()?;
+        assert_eq!(interpreter.output(), "hi");
+        assert_eq!(interpreter.memory_snapshot(), b"hi");
+
+        let mut interpreter = Interpreter::from_stream(",>,>,!ab")?;
+        assert!(matches!(
+            interpreter.run_to_completion(),
+            Err(RunError::InputExhausted(4))
+        ));
+        assert_eq!(interpreter.memory_snapshot(), b"ab\0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrapping_tape() -> Result<(), RunError> {
+        let mut interpreter = Interpreter::from_code("<+")?.with_wrapping_tape(5);
+        interpreter.run_to_completion()?;
+        assert_eq!(interpreter.pointer(), 4);
+        assert_eq!(interpreter.memory_snapshot(), &[0, 0, 0, 0, 1]);
+        let mut interpreter = Interpreter::from_code(">>+>")?.with_wrapping_tape(3);
+        interpreter.run_to_completion()?;
+        assert_eq!(interpreter.pointer(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tape_growth() -> Result<(), RunError> {
+        let code = ">".repeat(99) + "+" + &"<".repeat(50) + "++";
+        let mut interpreter = Interpreter::from_code(&code)?;
+        interpreter.run_to_completion()?;
+        assert_eq!(interpreter.memory_snapshot().len(), 100);
+        assert_eq!(interpreter.memory_snapshot()[49..].iter().sum::<u8>(), 3);
+        // Doubling grows the tape 7 times instead of 99
+        assert_eq!(interpreter.memory.len(), 128);
+
+        let mut interpreter = Interpreter::from_code(&code)?.with_initial_tape_size(100);
+        interpreter.run_to_completion()?;
+        assert_eq!(interpreter.memory.len(), 100);
+        assert_eq!(interpreter.memory_snapshot().len(), 100);
+        assert_eq!(interpreter.memory_snapshot()[99], 1);
+
+        // A header asking for more cells than a tape can have is refused, not allocated
+        for size in [4_000_000_000, usize::MAX] {
+            let code = tape_size_comment(size) + "+";
+            assert!(matches!(
+                Interpreter::from_code(&code),
+                Err(RunError::TapeTooLarge(_))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_final_state() -> Result<(), RunError> {
+        let mut interpreter = Interpreter::from_code("+++>++>+<.")?;
+        interpreter.run_to_completion()?;
+        let dump = interpreter.to_string();
+        assert!(dump.starts_with("Memory:\n 03[02]01\n"));
+        assert!(dump.ends_with("Output:\n\x02"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_breakpoints() -> Result<(), RunError> {
+        let code = "+++#\n>++# not a breakpoint\n#\n.";
+        let mut interpreter = Interpreter::from_code(code)?;
+        assert_eq!(interpreter.run_until_input()?, Pause::Finished);
+        assert_eq!(interpreter.memory_snapshot(), &[3, 2]);
+
+        let mut interpreter = Interpreter::from_code(code)?.with_breakpoints(true);
+        assert_eq!(interpreter.run_until_input()?, Pause::Breakpoint);
+        assert_eq!(interpreter.memory_snapshot(), &[3]);
+        assert_eq!(interpreter.run_until_input()?, Pause::Breakpoint);
+        assert_eq!(interpreter.memory_snapshot(), &[3, 2]);
+        assert_eq!(interpreter.output(), "");
+        assert_eq!(interpreter.run_until_input()?, Pause::Finished);
+        assert_eq!(interpreter.output(), "\x02");
+
+        // The header and the comments of compiled code aren't breakpoints
+        let options = compiler::Options {
+            explain: true,
+            ..compiler::Options::default()
+        };
+        let compilation = compiler::compile_with_ir("let x = 2; print_number(x);", &options)
+            .map_err(RunError::Runtime)?;
+        let code = tape_size_comment(compilation.tape_size) + &compilation.optimized;
+        assert!(code.contains("\n# let x on line 1\n"));
+        let mut interpreter = Interpreter::from_code(&code)?.with_breakpoints(true);
+        assert_eq!(interpreter.run_until_input()?, Pause::Finished);
+        assert_eq!(interpreter.output(), "2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_comments() -> Result<(), RunError> {
+        let mut interpreter = Interpreter::new("tests/brainfuck/comments.bf")?;
+        interpreter.run_to_completion()?;
+        assert_eq!(interpreter.output(), "Hi\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_color() {
+        let mut interpreter = Interpreter::empty();
+        interpreter.load("+>++<");
+        interpreter.step().unwrap();
+        assert!(!interpreter.to_string().contains('\x1b'));
+        let interpreter = interpreter.with_color(true);
+        let display = interpreter.to_string();
+        assert!(display.contains("[\x1b[1;33m01\x1b[0m"));
+        assert!(display.contains("+\x1b[1;33m>\x1b[0m++<"));
+    }
+
+    #[test]
+    fn test_display_size() {
+        let mut interpreter = Interpreter::empty().with_display_size(8, 2);
+        interpreter.load("+>+>+>+>+<<<<");
+        interpreter.run_to_completion().unwrap();
+        let expected = "Memory:\n[01]01\n 01 01\n 01\n\n\
+                        Instructions:\n+>+>+>+>\n     v\n+<<<<\n\n\
+                        Output:\n";
+        assert_eq!(interpreter.to_string(), expected);
+    }
+}
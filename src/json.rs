@@ -0,0 +1,379 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    parser::{Doc, Expr, FormatPiece, MatchArm, Program, Statement, Type},
+    tokenizer::Token,
+};
+
+/// A JSON value, used to hand tokens and syntax trees to external tools.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Creates an object with a `type` field holding `ty`, followed by `fields`.
+    fn tagged(ty: &str, fields: Vec<(&str, Json)>) -> Self {
+        let mut object = vec![("type".to_string(), Json::from(ty))];
+        object.extend(fields.into_iter().map(|(k, v)| (k.to_string(), v)));
+        Json::Object(object)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl From<&Token> for Json {
+    fn from(token: &Token) -> Self {
+        use Token as T;
+        match token {
+            T::Identifier(s) => Json::tagged("Identifier", vec![("value", Json::from(s.as_str()))]),
+            T::String(s) => Json::tagged("String", vec![("value", Json::from(s.as_str()))]),
+            T::Char(c) => Json::tagged("Char", vec![("value", Json::String(c.to_string()))]),
+            T::Number(n) => Json::tagged("Number", vec![("value", Json::Number(*n as u64))]),
+            T::Boolean(b) => Json::tagged("Boolean", vec![("value", Json::Bool(*b))]),
+            T::Error(s) => Json::tagged("Error", vec![("message", Json::from(s.as_str()))]),
+            T::Comment(s) => Json::tagged("Comment", vec![("text", Json::from(s.as_str()))]),
+            // The remaining tokens carry no data, so their debug name is all there is to them
+            _ => Json::tagged(&format!("{:?}", token), vec![]),
+        }
+    }
+}
+
+impl From<&Program> for Json {
+    fn from(program: &Program) -> Self {
+        Json::Array(program.statements.iter().map(Json::from).collect())
+    }
+}
+
+impl From<&Doc> for Json {
+    fn from(doc: &Doc) -> Self {
+        Json::tagged(
+            "Doc",
+            vec![
+                ("line", Json::Number(doc.position.line as u64)),
+                ("column", Json::Number(doc.position.column as u64)),
+                (
+                    "comments",
+                    Json::Array(
+                        doc.comments
+                            .iter()
+                            .map(|c| Json::from(c.as_str()))
+                            .collect(),
+                    ),
+                ),
+            ],
+        )
+    }
+}
+
+impl From<Type> for Json {
+    fn from(ty: Type) -> Self {
+        Json::from(match ty {
+            Type::Number => "u8",
+            Type::Char => "char",
+            Type::Bool => "bool",
+            Type::String => "str",
+        })
+    }
+}
+
+impl From<&MatchArm> for Json {
+    fn from(arm: &MatchArm) -> Self {
+        Json::tagged(
+            "MatchArm",
+            vec![
+                ("pattern", Json::from(&arm.pattern)),
+                ("body", Json::from(&arm.body)),
+            ],
+        )
+    }
+}
+
+impl From<&FormatPiece> for Json {
+    fn from(piece: &FormatPiece) -> Self {
+        match piece {
+            FormatPiece::Text(text) => {
+                Json::tagged("Text", vec![("value", Json::from(text.as_str()))])
+            }
+            FormatPiece::Placeholder => Json::tagged("Placeholder", vec![]),
+        }
+    }
+}
+
+impl From<&Statement> for Json {
+    fn from(stmt: &Statement) -> Self {
+        use Statement as S;
+        match stmt {
+            S::FunctionDefinition { name, params, body } => Json::tagged(
+                "FunctionDefinition",
+                vec![
+                    ("name", Json::from(name.as_str())),
+                    (
+                        "params",
+                        Json::Array(params.iter().map(|p| Json::from(p.as_str())).collect()),
+                    ),
+                    ("body", Json::from(body.as_ref())),
+                ],
+            ),
+            S::VariableDefinition {
+                name,
+                ty,
+                initializer,
+                ..
+            } => Json::tagged(
+                "VariableDefinition",
+                vec![
+                    ("name", Json::from(name.as_str())),
+                    ("ty", Json::from(*ty)),
+                    ("initializer", Json::from(initializer.as_ref())),
+                ],
+            ),
+            S::Assignment { name, value, .. } => Json::tagged(
+                "Assignment",
+                vec![
+                    ("name", Json::from(name.as_str())),
+                    ("value", Json::from(value)),
+                ],
+            ),
+            S::Return(value) => Json::tagged("Return", vec![("value", Json::from(value.as_ref()))]),
+            S::Print { format, args } => Json::tagged(
+                "Print",
+                vec![
+                    (
+                        "format",
+                        Json::Array(format.iter().map(Json::from).collect()),
+                    ),
+                    ("args", Json::Array(args.iter().map(Json::from).collect())),
+                ],
+            ),
+            S::Expression(expr) => Json::tagged("Expression", vec![("expr", Json::from(expr))]),
+            S::Block(statements) => Json::tagged(
+                "Block",
+                vec![(
+                    "statements",
+                    Json::Array(statements.iter().map(Json::from).collect()),
+                )],
+            ),
+            S::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => Json::tagged(
+                "If",
+                vec![
+                    ("condition", Json::from(condition)),
+                    ("then_branch", Json::from(then_branch.as_ref())),
+                    ("else_branch", Json::from(else_branch.as_deref())),
+                ],
+            ),
+            S::While { condition, body } => Json::tagged(
+                "While",
+                vec![
+                    ("condition", Json::from(condition)),
+                    ("body", Json::from(body.as_ref())),
+                ],
+            ),
+            S::DoWhile { body, condition } => Json::tagged(
+                "DoWhile",
+                vec![
+                    ("body", Json::from(body.as_ref())),
+                    ("condition", Json::from(condition)),
+                ],
+            ),
+            S::Break => Json::tagged("Break", vec![]),
+            S::LabeledBlock { label, body } => Json::tagged(
+                "LabeledBlock",
+                vec![
+                    ("label", Json::from(label.as_str())),
+                    ("body", Json::Array(body.iter().map(Json::from).collect())),
+                ],
+            ),
+            S::Exit(label) => Json::tagged("Exit", vec![("label", Json::from(label.as_str()))]),
+            S::Match {
+                value,
+                arms,
+                default,
+            } => Json::tagged(
+                "Match",
+                vec![
+                    ("value", Json::from(value)),
+                    ("arms", Json::Array(arms.iter().map(Json::from).collect())),
+                    ("default", Json::from(default.as_deref())),
+                ],
+            ),
+        }
+    }
+}
+
+impl From<&Expr> for Json {
+    fn from(expr: &Expr) -> Self {
+        use Expr as E;
+        match expr {
+            E::Unary { op, rhs } => Json::tagged(
+                "Unary",
+                vec![
+                    ("op", Json::String(format!("{:?}", op))),
+                    ("rhs", Json::from(rhs.as_ref())),
+                ],
+            ),
+            E::Binary { lhs, op, rhs, .. } => Json::tagged(
+                "Binary",
+                vec![
+                    ("lhs", Json::from(lhs.as_ref())),
+                    ("op", Json::String(format!("{:?}", op))),
+                    ("rhs", Json::from(rhs.as_ref())),
+                ],
+            ),
+            E::Number(n) => Json::tagged("Number", vec![("value", Json::Number(*n as u64))]),
+            E::Char(c) => Json::tagged("Char", vec![("value", Json::String(c.to_string()))]),
+            E::Boolean(b) => Json::tagged("Boolean", vec![("value", Json::Bool(*b))]),
+            E::String(s) => Json::tagged("String", vec![("value", Json::from(s.as_str()))]),
+            E::Identifier(name) => {
+                Json::tagged("Identifier", vec![("name", Json::from(name.as_str()))])
+            }
+            E::FunctionCall { callee, args } => Json::tagged(
+                "FunctionCall",
+                vec![
+                    ("callee", Json::from(callee.as_str())),
+                    ("args", Json::Array(args.iter().map(Json::from).collect())),
+                ],
+            ),
+            E::Assignment { name, value, .. } => Json::tagged(
+                "Assignment",
+                vec![
+                    ("name", Json::from(name.as_str())),
+                    ("value", Json::from(value.as_ref())),
+                ],
+            ),
+            E::Cast { ty, value } => Json::tagged(
+                "Cast",
+                vec![
+                    ("ty", Json::from(*ty)),
+                    ("value", Json::from(value.as_ref())),
+                ],
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parser::parse,
+        tokenizer::{tokenize, tokenize_located},
+    };
+
+    #[test]
+    fn test_tokens_json() {
+        let tokens: Vec<Token> = tokenize("let x = 'a'; // \"hi\"").collect();
+        let json = Json::Array(tokens.iter().map(Json::from).collect());
+        assert_eq!(
+            json.to_string(),
+            concat!(
+                r#"[{"type":"Let"},{"type":"Identifier","value":"x"},{"type":"Equal"},"#,
+                r#"{"type":"Char","value":"a"},{"type":"Semicolon"},"#,
+                r#"{"type":"Comment","text":" \"hi\""},{"type":"Eof"}]"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_ast_json() {
+        let tokens = tokenize_located("let x: u8 = 1 + y; while x { print(x); }");
+        let program = parse(&tokens).unwrap();
+        let Json::Array(statements) = Json::from(&program) else {
+            panic!("Expected the program to be an array of statements");
+        };
+        assert_eq!(statements.len(), 2);
+        assert_eq!(
+            statements[0].to_string(),
+            concat!(
+                r#"{"type":"VariableDefinition","name":"x","ty":"u8","initializer":"#,
+                r#"{"type":"Binary","lhs":{"type":"Number","value":1},"op":"Add","#,
+                r#""rhs":{"type":"Identifier","name":"y"}}}"#
+            )
+        );
+        let Json::Object(fields) = &statements[1] else {
+            panic!("Expected the while statement to be an object");
+        };
+        let keys: Vec<&str> = fields.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["type", "condition", "body"]);
+        assert_eq!(fields[0].1, Json::from("While"));
+    }
+
+    #[test]
+    fn test_cast_json() {
+        let program = parse(&tokenize_located("print((char) 65);")).unwrap();
+        let Json::Array(statements) = Json::from(&program) else {
+            panic!("Expected the program to be an array of statements");
+        };
+        assert!(statements[0].to_string().contains(concat!(
+            r#"{"type":"Cast","ty":"char","value":"#,
+            r#"{"type":"Number","value":65}}"#
+        )));
+    }
+}
@@ -4,11 +4,17 @@ use std::{
 };
 
 use clap::{Parser, Subcommand};
+use json::Json;
 
+mod analysis;
 mod brainfuck;
 mod compiler;
+mod formatter;
 mod interpreter;
+mod json;
 mod parser;
+mod repl;
+mod resolver;
 mod tokenizer;
 
 #[derive(Parser)]
@@ -19,30 +25,560 @@ struct Cli {
 
 #[derive(Subcommand, Clone)]
 enum Command {
+    /// Compile source files into a brainfuck program
     Make {
-        input: String,
+        #[clap(flatten)]
+        compile: CompileArgs,
         #[clap(short, long, default_value = "out.bf")]
         output: String,
+        /// Print the brainfuck code before and after optimization
+        #[clap(long)]
+        dump_ir: bool,
+        /// Annotate the generated code with comments telling which statement each part implements
+        #[clap(long)]
+        explain: bool,
+        /// Break the generated code into lines of at most this many characters
+        #[clap(long)]
+        line_width: Option<usize>,
+        /// Print how long compiling took
+        #[clap(long)]
+        time: bool,
+        /// The input is already brainfuck, so check it and write it to the output unchanged
+        #[clap(long)]
+        passthrough: bool,
+    },
+    /// Compile source files to report errors and warnings, without writing any output
+    Check {
+        #[clap(flatten)]
+        compile: CompileArgs,
     },
     Run {
         srcfile: String,
+        /// Flush output only before reading input and at exit instead of after every byte
+        #[clap(long)]
+        buffered: bool,
+        /// Treat the tape as circular instead of stopping when moving left of the first cell
+        #[clap(long)]
+        wrap: bool,
+        /// Print how long transpiling, compiling with rustc and running took
+        #[clap(long)]
+        time: bool,
     },
     Interpret {
+        /// Brainfuck file to run, or - to read the program and then its input from stdin,
+        /// separated by a !
+        srcfile: String,
+        /// Width of the dashboard in characters. Defaults to the terminal width if known
+        #[clap(short, long)]
+        width: Option<usize>,
+        /// Characters per memory cell divided by two
+        #[clap(short, long, default_value_t = interpreter::DEFAULT_DELTA)]
+        delta: usize,
+        /// Highlight the current cell and instruction using ANSI colors
+        #[clap(short, long)]
+        color: bool,
+        /// Stop the program once it prints more than this many bytes
+        #[clap(long)]
+        max_output: Option<usize>,
+        /// Treat the tape as circular instead of stopping when moving left of the first cell
+        #[clap(long)]
+        wrap: bool,
+        /// Skip whitespace in the input instead of reading it
+        #[clap(long)]
+        skip_whitespace: bool,
+        /// Print the memory and where the program stopped if it fails
+        #[clap(long)]
+        dump_memory_on_error: bool,
+        /// Stop the program when it changes the first cell, to catch pointer underflow bugs
+        #[clap(long)]
+        sentinel: bool,
+        /// Pause at every `#` with nothing after it on its line until enter is pressed
+        #[clap(long)]
+        breakpoints: bool,
+    },
+    /// Interpret a brainfuck file to completion and print the final tape, pointer and output
+    Dump {
+        /// Brainfuck file to run, or - to read the program and then its input from stdin,
+        /// separated by a !
+        srcfile: String,
+        /// Width of the printed tape in characters. Defaults to the terminal width if known
+        #[clap(short, long)]
+        width: Option<usize>,
+        /// Characters per memory cell divided by two
+        #[clap(short, long, default_value_t = interpreter::DEFAULT_DELTA)]
+        delta: usize,
+        /// Highlight the current cell using ANSI colors
+        #[clap(short, long)]
+        color: bool,
+        /// Stop the program once it prints more than this many bytes
+        #[clap(long)]
+        max_output: Option<usize>,
+        /// Treat the tape as circular instead of stopping when moving left of the first cell
+        #[clap(long)]
+        wrap: bool,
+        /// Skip whitespace in the input instead of reading it
+        #[clap(long)]
+        skip_whitespace: bool,
+        /// Print the memory and where the program stopped if it fails
+        #[clap(long)]
+        dump_memory_on_error: bool,
+        /// Stop the program when it changes the first cell, to catch pointer underflow bugs
+        #[clap(long)]
+        sentinel: bool,
+    },
+    Repl,
+    /// Print statistics about a brainfuck file
+    Stats {
+        srcfile: String,
+    },
+    /// Print the tokens of a brang file
+    Tokens {
+        srcfile: String,
+        /// Print the tokens as JSON
+        #[clap(long)]
+        json: bool,
+        /// A character that starts a line comment, like `#`, in addition to `//`
+        #[clap(long, value_parser = comment_prefix)]
+        comment_prefix: Option<char>,
+    },
+    /// Print the syntax tree of a brang file
+    Ast {
         srcfile: String,
+        /// Print the syntax tree as JSON
+        #[clap(long)]
+        json: bool,
+        /// Print each expression with parentheses showing how its operators were grouped
+        #[clap(long, conflicts_with = "json")]
+        parens: bool,
+        /// Keep the comments in front of statements, printed after the syntax tree along with
+        /// where each statement starts. With --json, prints one object with the syntax tree in
+        /// `statements` and the comments in `docs`
+        #[clap(long, conflicts_with = "parens")]
+        comments: bool,
+        /// A character that starts a line comment, like `#`, in addition to `//`
+        #[clap(long, value_parser = comment_prefix)]
+        comment_prefix: Option<char>,
     },
+    /// Print a brang file with consistent indentation and spacing. Fails if a comment is inside
+    /// code that is written on one line, instead of losing it
+    Fmt {
+        srcfile: String,
+        /// A character that starts a line comment, like `#`, in addition to `//`. The comments
+        /// are written back with `//`
+        #[clap(long, value_parser = comment_prefix)]
+        comment_prefix: Option<char>,
+    },
+}
+
+/// The arguments `make` and `check` share, which decide how the program is compiled.
+#[derive(clap::Args, Clone)]
+struct CompileArgs {
+    /// Source files to compile into one program, in the order they run
+    #[clap(required = true)]
+    input: Vec<String>,
+    /// Report reading a variable that was never assigned a value as an error
+    #[clap(long)]
+    check_uninitialized: bool,
+    /// Stop the compiled program when a value breaks an invariant of the generated code
+    #[clap(long)]
+    debug_checks: bool,
+    /// Don't compile the standard prelude in front of the program
+    #[clap(long)]
+    no_std_prelude: bool,
+    /// Compile this file in front of the program instead of the standard prelude
+    #[clap(long, conflicts_with = "no_std_prelude")]
+    prelude: Option<String>,
+    /// A character that starts a line comment, like `#`, in addition to `//`
+    #[clap(long, value_parser = comment_prefix)]
+    comment_prefix: Option<char>,
+    /// Warn about arithmetic known at compile time that goes above this value or wraps around
+    #[clap(long)]
+    max_cell_value: Option<u8>,
+    /// Treat warnings as errors
+    #[clap(long)]
+    strict: bool,
+    /// How deeply function calls can be nested, since each call inlines the function body
+    #[clap(long)]
+    max_inline_depth: Option<usize>,
+    /// Leave the first cell unused, for running with the interpreter's --sentinel
+    #[clap(long)]
+    sentinel: bool,
+    /// The base numbers are printed in
+    #[clap(long, value_enum, default_value_t = PrintRadix::Decimal)]
+    print_radix: PrintRadix,
+}
+
+impl CompileArgs {
+    /// The compiler options these arguments ask for, reading the prelude file if one is given.
+    fn options(&self) -> Result<compiler::Options, Failure> {
+        Ok(compiler::Options {
+            check_uninitialized: self.check_uninitialized,
+            debug_checks: self.debug_checks,
+            prelude: read_prelude(self.prelude.clone(), self.no_std_prelude)?,
+            max_inline_depth: self.max_inline_depth,
+            print_radix: self.print_radix.into(),
+            comment_prefix: self.comment_prefix,
+            max_cell_value: self.max_cell_value,
+            sentinel: self.sentinel,
+            ..compiler::Options::default()
+        })
+    }
+}
+
+/// The values `--print-radix` accepts.
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum PrintRadix {
+    Decimal,
+    Hex,
+}
+
+impl From<PrintRadix> for compiler::Radix {
+    fn from(radix: PrintRadix) -> Self {
+        match radix {
+            PrintRadix::Decimal => compiler::Radix::Decimal,
+            PrintRadix::Hex => compiler::Radix::Hex,
+        }
+    }
+}
+
+/// Parses the value of `--comment-prefix`, which must be a single character that doesn't already
+/// mean something in brang.
+fn comment_prefix(value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if tokenizer::can_start_comment(c) => Ok(c),
+        (Some(c), None) if c.is_whitespace() => Err("whitespace can't start a comment".to_string()),
+        (Some(c), None) => Err(format!("{:?} already starts a token", c)),
+        _ => Err("expected a single character".to_string()),
+    }
+}
+
+/// Why a command failed, which decides the exit code of the process.
+enum Failure {
+    /// The input was invalid, like source code that doesn't compile. Exits with 1.
+    User(String),
+    /// A file could not be read or written. Exits with 2.
+    Io(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::User(_) => 1,
+            Failure::Io(_) => 2,
+        }
+    }
+}
+
+impl From<brainfuck::RunError> for Failure {
+    fn from(e: brainfuck::RunError) -> Self {
+        use brainfuck::RunError as R;
+        match e {
+            R::InvalidPath(_) | R::PermissionDenied => Failure::Io(e.to_string()),
+            _ => Failure::User(e.to_string()),
+        }
+    }
+}
+
+fn io_error(context: &str) -> impl FnOnce(std::io::Error) -> Failure + '_ {
+    move |e| Failure::Io(format!("{}: {}", context, e))
+}
+
+/// Reads every source code file in `paths`.
+fn read_sources(paths: &[String]) -> Result<Vec<String>, Failure> {
+    paths
+        .iter()
+        .map(std::fs::read_to_string)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io_error("Could not read source code file"))
+}
+
+/// The prelude chosen by the `--prelude` and `--no-std-prelude` flags.
+fn read_prelude(path: Option<String>, no_std_prelude: bool) -> Result<compiler::Prelude, Failure> {
+    Ok(match path {
+        Some(path) => compiler::Prelude::Custom(
+            std::fs::read_to_string(path).map_err(io_error("Could not read prelude file"))?,
+        ),
+        None if no_std_prelude => compiler::Prelude::None,
+        None => compiler::Prelude::Std,
+    })
+}
+
+/// Compiles the source code read from `paths` into one program.
+fn compile_sources(
+    paths: &[String],
+    sources: &[String],
+    options: &compiler::Options,
+) -> Result<compiler::Compilation, Failure> {
+    let files: Vec<(&str, &str)> = paths
+        .iter()
+        .map(String::as_str)
+        .zip(sources.iter().map(String::as_str))
+        .collect();
+    compiler::compile_files(&files, options).map_err(Failure::User)
+}
+
+/// Compiles source files like [`compile_sources`], streaming the code to the file at `path`.
+fn stream_sources(
+    paths: &[String],
+    sources: &[String],
+    options: &compiler::Options,
+    path: &str,
+) -> Result<compiler::Streamed, Failure> {
+    let files: Vec<(&str, &str)> = paths
+        .iter()
+        .map(String::as_str)
+        .zip(sources.iter().map(String::as_str))
+        .collect();
+    let file = File::create(path).map_err(io_error("Could not create output file"))?;
+    let mut writer = BufWriter::new(file);
+    let streamed =
+        compiler::compile_files_to(&files, options, &mut writer).map_err(Failure::User)?;
+    writer
+        .flush()
+        .map_err(io_error("Could not write to output file"))?;
+    Ok(streamed)
+}
+
+/// Writes the header telling how a compiled program must be run to `output`, followed by the
+/// code in the file at `code`.
+fn write_with_header(
+    code: &str,
+    output: &str,
+    streamed: &compiler::Streamed,
+) -> Result<(), Failure> {
+    let mut header = brainfuck::tape_size_comment(streamed.tape_size);
+    if streamed.traps {
+        header.push_str(brainfuck::TRAPS_COMMENT);
+    }
+    let mut code = File::open(code).map_err(io_error("Could not read the compiled code"))?;
+    let outfile = File::create(output).map_err(io_error("Could not create output file"))?;
+    let mut writer = BufWriter::new(outfile);
+    writer
+        .write_all(header.as_bytes())
+        .and_then(|_| std::io::copy(&mut code, &mut writer))
+        .and_then(|_| writer.flush())
+        .map_err(io_error("Could not write to output file"))
+}
+
+/// Prints the warnings found while compiling. With `strict`, they are printed as errors and
+/// compiling fails if there are any.
+fn report_warnings(warnings: &[String], strict: bool) -> Result<(), Failure> {
+    let kind = if strict { "error" } else { "warning" };
+    for warning in warnings {
+        eprintln!("{}: {}", kind, warning);
+    }
+    match warnings.len() {
+        n if strict && n > 0 => Err(Failure::User(format!(
+            "Found {} warning{} with --strict",
+            n,
+            if n == 1 { "" } else { "s" }
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// `width` if given, otherwise the width of the terminal if known.
+fn terminal_width(width: Option<usize>) -> usize {
+    width
+        .or_else(|| std::env::var("COLUMNS").ok()?.parse().ok())
+        .unwrap_or(interpreter::DEFAULT_WIDTH)
 }
 
 fn main() {
     let args = Cli::parse();
-    match args.command {
-        Command::Make { input, output } => {
-            let src = std::fs::read_to_string(&input).expect("Could not read source code file");
-            let compilation = compiler::compile(&src).expect("Could not compile source code");
-            let outfile = File::create(&output).expect("Could not create output file");
-            Write::write_all(&mut BufWriter::new(outfile), compilation.as_bytes())
-                .expect("Could not write to output file");
+    if let Err(failure) = run(args.command) {
+        let (Failure::User(message) | Failure::Io(message)) = &failure;
+        eprintln!("error: {}", message);
+        std::process::exit(failure.exit_code());
+    }
+}
+
+fn run(command: Command) -> Result<(), Failure> {
+    match command {
+        Command::Make {
+            compile,
+            output,
+            dump_ir,
+            explain,
+            line_width,
+            time,
+            passthrough,
+        } => {
+            let input = &compile.input;
+            let sources = read_sources(input)?;
+            if passthrough {
+                for (name, src) in input.iter().zip(&sources) {
+                    brainfuck::validate(src)
+                        .map_err(|e| Failure::User(format!("{}: {}", name, e)))?;
+                }
+                // A file may end in the middle of a line, like after a `#` comment
+                std::fs::write(&output, sources.join("\n"))
+                    .map_err(io_error("Could not write to output file"))?;
+                return Ok(());
+            }
+            let options = compiler::Options {
+                explain,
+                line_width,
+                ..compile.options()?
+            };
+            if dump_ir {
+                let compilation = compile_sources(input, &sources, &options)?;
+                println!("Before optimization:\n{}\n", compilation.unoptimized);
+                println!("After optimization:\n{}", compilation.optimized);
+            }
+            // The header is only known once the whole program is compiled, so the code is
+            // streamed to a file next to the output first and copied in after the header
+            let partial = format!("{}.partial", output);
+            let start = std::time::Instant::now();
+            let result = stream_sources(input, &sources, &options, &partial).and_then(|streamed| {
+                if time {
+                    eprintln!("time: compile {:?}", start.elapsed());
+                }
+                report_warnings(&streamed.warnings, compile.strict)?;
+                write_with_header(&partial, &output, &streamed)
+            });
+            let _ = std::fs::remove_file(&partial);
+            result?;
+        }
+        Command::Check { compile } => {
+            let sources = read_sources(&compile.input)?;
+            let compilation = compile_sources(&compile.input, &sources, &compile.options()?)?;
+            report_warnings(&compilation.warnings, compile.strict)?;
+        }
+        Command::Run {
+            srcfile,
+            buffered,
+            wrap,
+            time,
+        } => {
+            let options = brainfuck::Options { buffered, wrap };
+            let timings = brainfuck::run_file(srcfile, options)?;
+            if time {
+                eprintln!("time: {}", timings);
+            }
+        }
+        Command::Interpret {
+            srcfile,
+            width,
+            delta,
+            color,
+            max_output,
+            wrap,
+            skip_whitespace,
+            dump_memory_on_error,
+            sentinel,
+            breakpoints,
+        } => {
+            let settings = interpreter::Settings {
+                width: terminal_width(width),
+                delta,
+                color,
+                max_output,
+                wrap,
+                skip_whitespace,
+                dump_on_error: dump_memory_on_error,
+                sentinel,
+            };
+            interpreter::run(&srcfile, &settings, breakpoints)?;
+        }
+        Command::Dump {
+            srcfile,
+            width,
+            delta,
+            color,
+            max_output,
+            wrap,
+            skip_whitespace,
+            dump_memory_on_error,
+            sentinel,
+        } => {
+            let settings = interpreter::Settings {
+                width: terminal_width(width),
+                delta,
+                color,
+                max_output,
+                wrap,
+                skip_whitespace,
+                dump_on_error: dump_memory_on_error,
+                sentinel,
+            };
+            interpreter::dump(&srcfile, &settings)?;
+        }
+        Command::Repl => repl::run(),
+        Command::Stats { srcfile } => {
+            let src = std::fs::read_to_string(&srcfile)
+                .map_err(io_error("Could not read brainfuck file"))?;
+            let stats = analysis::count_instructions(&src)?;
+            println!("{}", stats);
+        }
+        Command::Tokens {
+            srcfile,
+            json,
+            comment_prefix,
+        } => {
+            let src = std::fs::read_to_string(&srcfile)
+                .map_err(io_error("Could not read source code file"))?;
+            let tokens: Vec<tokenizer::Token> = tokenizer::tokenize(&src)
+                .with_comment_prefix(comment_prefix)
+                .collect();
+            if json {
+                println!("{}", Json::Array(tokens.iter().map(Json::from).collect()));
+            } else {
+                for token in &tokens {
+                    println!("{:?}", token);
+                }
+            }
+        }
+        Command::Ast {
+            srcfile,
+            json,
+            parens,
+            comments,
+            comment_prefix,
+        } => {
+            let src = std::fs::read_to_string(&srcfile)
+                .map_err(io_error("Could not read source code file"))?;
+            let tokens =
+                tokenizer::locate(tokenizer::tokenize(&src).with_comment_prefix(comment_prefix));
+            let program = if comments {
+                parser::parse_with_comments(&tokens)
+            } else {
+                parser::parse(&tokens)
+            }
+            .map_err(Failure::User)?;
+            if json && comments {
+                let docs = Json::Array(program.docs.iter().map(Json::from).collect());
+                let object = vec![
+                    ("statements".to_string(), Json::from(&program)),
+                    ("docs".to_string(), docs),
+                ];
+                println!("{}", Json::Object(object));
+            } else if json {
+                println!("{}", Json::from(&program));
+            } else if parens {
+                for expr in program.statements.iter().flat_map(|s| s.expressions()) {
+                    println!("{}", expr);
+                }
+            } else {
+                println!("{:#?}", program.statements);
+            }
+            if comments && !json {
+                println!("{:#?}", program.docs);
+            }
+        }
+        Command::Fmt {
+            srcfile,
+            comment_prefix,
+        } => {
+            let src = std::fs::read_to_string(&srcfile)
+                .map_err(io_error("Could not read source code file"))?;
+            let tokens =
+                tokenizer::locate(tokenizer::tokenize(&src).with_comment_prefix(comment_prefix));
+            let program = parser::parse_with_comments(&tokens).map_err(Failure::User)?;
+            let formatted = formatter::format_program(&program).map_err(Failure::User)?;
+            print!("{}", formatted);
         }
-        Command::Run { srcfile } => brainfuck::run_file(srcfile).expect("Error when running file"),
-        Command::Interpret { srcfile } => interpreter::run(&srcfile),
     }
+    Ok(())
 }
@@ -1,17 +1,61 @@
-use crate::tokenizer::Token;
+use std::collections::VecDeque;
+
+use crate::tokenizer::{Position, Span, Token};
 
 #[derive(Debug)]
 pub(crate) struct Program {
     pub(crate) statements: Vec<Statement>,
+    /// The comments in front of statements. Only kept when parsing with
+    /// [`parse_with_comments`].
+    pub(crate) docs: Vec<Doc>,
+    /// Every comment with where it starts, in the order they appear. Only kept with
+    /// [`parse_with_comments`].
+    pub(crate) comments: Vec<(Position, String)>,
+    /// The places comments can be put back around, in the order they appear. Only kept with
+    /// [`parse_with_comments`].
+    pub(crate) anchors: Vec<Anchor>,
+    /// The names of the files the program was read from, by the file index of their positions.
+    /// Empty if it was read from a single source.
+    pub(crate) files: Vec<String>,
 }
 
 impl Program {
-    fn new(statements: Vec<Statement>) -> Self {
-        Self { statements }
+    pub(crate) fn new(statements: Vec<Statement>) -> Self {
+        Self {
+            statements,
+            docs: Vec::new(),
+            comments: Vec::new(),
+            anchors: Vec::new(),
+            files: Vec::new(),
+        }
     }
 }
 
-#[derive(Debug)]
+/// A place in the source code where the formatter starts or ends a line, so the comments next
+/// to it can be put back there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Anchor {
+    /// A statement of the program or of a block, from the start of its first token to the end of
+    /// its last one.
+    Statement(Span),
+    /// The `{` of a block.
+    Open(Position),
+    /// The `}` of a block.
+    Close(Position),
+    /// A for loop, which is parsed into a block that looks like any other.
+    For,
+}
+
+/// The comments in front of a statement, which document it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Doc {
+    /// Where the statement starts.
+    pub(crate) position: Position,
+    /// The text of each comment, without the `//` or `/* */`.
+    pub(crate) comments: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
 pub(crate) enum Statement {
     FunctionDefinition {
         name: String,
@@ -20,15 +64,25 @@ pub(crate) enum Statement {
     },
     VariableDefinition {
         name: String,
+        ty: Option<Type>,
         initializer: Option<Expr>,
+        /// Where the name of the variable is.
+        position: Position,
     },
     Assignment {
         name: String,
         value: Expr,
+        /// Where the name of the variable is.
+        position: Position,
     },
-    #[allow(dead_code)]
     Return(Option<Expr>),
-    Print(Expr),
+    /// Prints `format` with each placeholder replaced by the next value in `args`. A print of a
+    /// single expression has a format consisting of one placeholder.
+    Print {
+        format: Vec<FormatPiece>,
+        args: Vec<Expr>,
+    },
+    Expression(Expr),
     Block(Vec<Statement>),
     If {
         condition: Expr,
@@ -39,9 +93,36 @@ pub(crate) enum Statement {
         condition: Expr,
         body: Box<Statement>,
     },
+    DoWhile {
+        body: Box<Statement>,
+        condition: Expr,
+    },
+    Break,
+    LabeledBlock {
+        label: String,
+        body: Vec<Statement>,
+    },
+    /// Leaves the enclosing block with the label.
+    Exit(String),
+    /// Runs the body of the first arm whose pattern equals `value`, or `default` if none does.
+    Match {
+        value: Expr,
+        arms: Vec<MatchArm>,
+        default: Option<Box<Statement>>,
+    },
 }
 
-#[derive(Debug)]
+/// An arm of a match statement, other than the `_` arm.
+#[derive(Debug, Clone)]
+pub(crate) struct MatchArm {
+    /// A number, character or boolean literal.
+    pub(crate) pattern: Expr,
+    /// Where the pattern is.
+    pub(crate) position: Position,
+    pub(crate) body: Statement,
+}
+
+#[derive(Debug, Clone)]
 pub(crate) enum Expr {
     Unary {
         op: UnaryOp,
@@ -51,17 +132,102 @@ pub(crate) enum Expr {
         lhs: Box<Expr>,
         op: BinaryOp,
         rhs: Box<Expr>,
+        /// Where the operator is.
+        position: Position,
     },
     Number(u8),
+    Char(char),
+    Boolean(bool),
     String(String),
     Identifier(String),
     FunctionCall {
         callee: String,
         args: Vec<Expr>,
     },
+    Assignment {
+        name: String,
+        value: Box<Expr>,
+        /// Where the name of the variable is.
+        position: Position,
+    },
+    /// Converts `value` to `ty`, written `(ty) value`.
+    Cast {
+        ty: Type,
+        value: Box<Expr>,
+    },
 }
 
-#[derive(Debug)]
+/// A part of the format string of a print statement.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum FormatPiece {
+    Text(String),
+    /// A `{}`, which is replaced by the value of an argument.
+    Placeholder,
+}
+
+/// Splits a format string into text and placeholders. `{{` and `}}` are printed as `{` and `}`.
+fn parse_format(format: &str) -> Result<Vec<FormatPiece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                text.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                if !text.is_empty() {
+                    pieces.push(FormatPiece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(FormatPiece::Placeholder);
+            }
+            ('{' | '}', _) => return Err(format!("Unmatched '{}'", c)),
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(FormatPiece::Text(text));
+    }
+    Ok(pieces)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Type {
+    Number,
+    Char,
+    Bool,
+    String,
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Type::Number => "u8",
+            Type::Char => "char",
+            Type::Bool => "bool",
+            Type::String => "str",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl TryFrom<&str> for Type {
+    type Error = String;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name {
+            "u8" => Ok(Type::Number),
+            "char" => Ok(Type::Char),
+            "bool" => Ok(Type::Bool),
+            "str" => Ok(Type::String),
+            _ => Err(format!("Unknown type {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub(crate) enum UnaryOp {
     Neg,
     Not,
@@ -79,10 +245,18 @@ impl From<Token> for UnaryOp {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) enum BinaryOp {
     Add,
     Sub,
+    /// `+%`, addition that is meant to wrap around, so it is never reported as overflowing.
+    WrappingAdd,
+    /// `-%`, subtraction that is meant to wrap around, so it is never reported as overflowing.
+    WrappingSub,
+    /// Addition that stops at 255 instead of wrapping around.
+    SaturatingAdd,
+    /// Subtraction that stops at 0 instead of wrapping around.
+    SaturatingSub,
     Mul,
     Div,
     Mod,
@@ -94,6 +268,127 @@ pub(crate) enum BinaryOp {
     Geq,
     And,
     Or,
+    /// `&&`, which only evaluates its right operand if the left one is true.
+    LogicalAnd,
+    /// `||`, which only evaluates its right operand if the left one is false.
+    LogicalOr,
+}
+
+impl std::fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnaryOp::Neg => write!(f, "-"),
+            UnaryOp::Not => write!(f, "!"),
+        }
+    }
+}
+
+impl std::fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use BinaryOp as B;
+        let symbol = match self {
+            B::Add => "+",
+            B::Sub => "-",
+            B::WrappingAdd => "+%",
+            B::WrappingSub => "-%",
+            B::SaturatingAdd => "+|",
+            B::SaturatingSub => "-|",
+            B::Mul => "*",
+            B::Div => "/",
+            B::Mod => "%",
+            B::Eq => "==",
+            B::Neq => "!=",
+            B::Lt => "<",
+            B::Leq => "<=",
+            B::Gt => ">",
+            B::Geq => ">=",
+            B::And => "&",
+            B::Or => "|",
+            B::LogicalAnd => "&&",
+            B::LogicalOr => "||",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// Writes the expression with every binary operation in parentheses, so the string shows how
+/// precedence grouped the operands.
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Unary { op, rhs } => write!(f, "{}{}", op, rhs),
+            Expr::Binary { lhs, op, rhs, .. } => write!(f, "({} {} {})", lhs, op, rhs),
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Char(c) => write!(f, "{:?}", c),
+            Expr::Boolean(b) => write!(f, "{}", b),
+            Expr::String(s) => write!(f, "{:?}", s),
+            Expr::Identifier(name) => write!(f, "{}", name),
+            Expr::FunctionCall { callee, args } => {
+                let args: Vec<String> = args.iter().map(Expr::to_string).collect();
+                write!(f, "{}({})", callee, args.join(", "))
+            }
+            Expr::Assignment { name, value, .. } => write!(f, "({} = {})", name, value),
+            Expr::Cast { ty, value } => write!(f, "({}) {}", ty, value),
+        }
+    }
+}
+
+impl Statement {
+    /// The expressions in the statement and the statements nested in it, in source order.
+    /// Expressions nested in other expressions aren't included on their own.
+    pub(crate) fn expressions(&self) -> Vec<&Expr> {
+        use Statement as S;
+        match self {
+            S::FunctionDefinition { body, .. } => body.expressions(),
+            S::VariableDefinition { initializer, .. } => initializer.iter().collect(),
+            S::Assignment { value, .. } => vec![value],
+            S::Return(value) => value.iter().collect(),
+            S::Print { args, .. } => args.iter().collect(),
+            S::Expression(expr) => vec![expr],
+            S::Block(statements)
+            | S::LabeledBlock {
+                body: statements, ..
+            } => statements.iter().flat_map(Statement::expressions).collect(),
+            S::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let mut exprs = vec![condition];
+                exprs.extend(then_branch.expressions());
+                if let Some(else_branch) = else_branch {
+                    exprs.extend(else_branch.expressions());
+                }
+                exprs
+            }
+            S::While { condition, body } => {
+                let mut exprs = vec![condition];
+                exprs.extend(body.expressions());
+                exprs
+            }
+            S::DoWhile { body, condition } => {
+                let mut exprs = body.expressions();
+                exprs.push(condition);
+                exprs
+            }
+            S::Break | S::Exit(_) => Vec::new(),
+            S::Match {
+                value,
+                arms,
+                default,
+            } => {
+                let mut exprs = vec![value];
+                for arm in arms {
+                    exprs.push(&arm.pattern);
+                    exprs.extend(arm.body.expressions());
+                }
+                if let Some(default) = default {
+                    exprs.extend(default.expressions());
+                }
+                exprs
+            }
+        }
+    }
 }
 
 impl From<Token> for BinaryOp {
@@ -103,6 +398,10 @@ impl From<Token> for BinaryOp {
         match token {
             T::Plus => B::Add,
             T::Minus => B::Sub,
+            T::PlusPercent => B::WrappingAdd,
+            T::MinusPercent => B::WrappingSub,
+            T::PlusPipe => B::SaturatingAdd,
+            T::MinusPipe => B::SaturatingSub,
             T::Star => B::Mul,
             T::Slash => B::Div,
             T::Percent => B::Mod,
@@ -114,52 +413,205 @@ impl From<Token> for BinaryOp {
             T::GreaterEqual => B::Geq,
             T::And => B::And,
             T::Or => B::Or,
+            T::AndAnd => B::LogicalAnd,
+            T::OrOr => B::LogicalOr,
             _ => panic!("Expected binary operator, found {:?}", token),
         }
     }
 }
 
 impl BinaryOp {
-    fn precedence(&self) -> u8 {
+    pub(crate) fn precedence(&self) -> u8 {
         use BinaryOp as B;
         match self {
-            B::Or => 1,
-            B::And => 2,
-            B::Eq | B::Neq => 3,
-            B::Lt | B::Leq | B::Gt | B::Geq => 4,
-            B::Add | B::Sub => 5,
-            B::Mul | B::Div | B::Mod => 6,
+            B::LogicalOr => 1,
+            B::LogicalAnd => 2,
+            B::Or => 3,
+            B::And => 4,
+            B::Eq | B::Neq => 5,
+            B::Lt | B::Leq | B::Gt | B::Geq => 6,
+            B::Add
+            | B::Sub
+            | B::WrappingAdd
+            | B::WrappingSub
+            | B::SaturatingAdd
+            | B::SaturatingSub => 7,
+            B::Mul | B::Div | B::Mod => 8,
         }
     }
 }
 
-pub(crate) fn parse(tokens: &[Token]) -> Result<Program, String> {
-    let mut parser = Parser::new(tokens);
+pub(crate) fn parse(tokens: &[(Token, Span)]) -> Result<Program, String> {
+    parse_tokens(tokens, false)
+}
+
+/// Parses `tokens` like [`parse`], but keeps the comments in front of each statement in
+/// [`Program::docs`] instead of discarding them. A comment on the same line as the end of the
+/// previous statement belongs to that statement, so it isn't kept.
+pub(crate) fn parse_with_comments(tokens: &[(Token, Span)]) -> Result<Program, String> {
+    parse_tokens(tokens, true)
+}
+
+fn parse_tokens(tokens: &[(Token, Span)], keep_comments: bool) -> Result<Program, String> {
+    // Report characters the tokenizer couldn't make sense of as they are, instead of as
+    // unexpected tokens
+    let errors: Vec<&str> = tokens
+        .iter()
+        .filter_map(|(token, _)| match token {
+            Token::Error(error) => Some(error.as_str()),
+            _ => None,
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    let mut parser = Parser::new(tokens, keep_comments);
     parser.program()
 }
 
+/// The error for a square bracket, which has no meaning until arrays are supported.
+fn bracket_error(token: &Token, position: Position) -> String {
+    let bracket = if *token == Token::LeftBracket {
+        '['
+    } else {
+        ']'
+    };
+    format!(
+        "Unexpected '{}' at {}, arrays are not supported",
+        bracket, position
+    )
+}
+
 struct Parser {
     tokens: Vec<Token>,
+    /// Where each token in `tokens` was read from.
+    spans: Vec<Span>,
     errors: Vec<String>,
     current: usize,
+    /// The comments that may document a statement, with the index of the token after them, in
+    /// the order they appear.
+    comments: VecDeque<(usize, String)>,
+    docs: Vec<Doc>,
+    keep_comments: bool,
+    /// Every comment with where it starts, if comments are kept.
+    all_comments: Vec<(Position, String)>,
+    anchors: Vec<Anchor>,
 }
 
 impl Parser {
-    fn new(tokens: &[Token]) -> Self {
-        let tokens = Vec::from(tokens)
+    /// Creates a parser for `tokens`, skipping comments. With `keep_comments`, the comments in
+    /// front of statements are collected in [`Program::docs`].
+    fn new(tokens: &[(Token, Span)], keep_comments: bool) -> Self {
+        let mut comments = VecDeque::new();
+        let mut all_comments = Vec::new();
+        if keep_comments {
+            let mut count = 0;
+            let mut last_line = None;
+            for (token, span) in tokens {
+                if let Token::Comment(text) = token {
+                    all_comments.push((span.start, text.clone()));
+                }
+                match token {
+                    // A comment after code on the same line is about that code
+                    Token::Comment(text) if last_line != Some(span.start.line) => {
+                        comments.push_back((count, text.clone()))
+                    }
+                    token if token.is_ignorable() => (),
+                    _ => {
+                        count += 1;
+                        last_line = Some(span.end.line);
+                    }
+                }
+            }
+        }
+        let (mut tokens, mut spans): (Vec<Token>, Vec<Span>) = tokens
             .iter()
-            .filter(|t| !t.is_ignorable())
+            .filter(|(t, _)| !t.is_ignorable())
             .cloned()
-            .collect::<Vec<_>>();
+            .unzip();
+        // The tokenizer ends every stream with `Eof`, but tokens may come from elsewhere
+        if tokens.last() != Some(&Token::Eof) {
+            let end = spans.last().map_or(
+                Position {
+                    file: 0,
+                    line: 1,
+                    column: 1,
+                },
+                |span| span.end,
+            );
+            tokens.push(Token::Eof);
+            spans.push(Span { start: end, end });
+        }
         Self {
             tokens,
+            spans,
             errors: Vec::new(),
             current: 0,
+            comments,
+            docs: Vec::new(),
+            keep_comments,
+            all_comments,
+            anchors: Vec::new(),
+        }
+    }
+
+    /// Records `anchor`, if comments are kept.
+    fn anchor(&mut self, anchor: Anchor) {
+        if self.keep_comments {
+            self.anchors.push(anchor);
+        }
+    }
+
+    /// Parses a statement of the program or of a block, which the formatter puts on lines of its
+    /// own, recording where it starts and ends if comments are kept.
+    fn line_statement(&mut self) -> Result<Statement, String> {
+        let start = self.spans[self.current].start;
+        let index = self.anchors.len();
+        self.anchor(Anchor::Statement(Span { start, end: start }));
+        let statement = self.statement();
+        if self.keep_comments {
+            let end = self.spans[self.current.saturating_sub(1)].end;
+            self.anchors[index] = Anchor::Statement(Span { start, end });
+        }
+        statement
+    }
+
+    /// Moves the comments right in front of the current token into the docs, as documenting the
+    /// statement starting there. Comments in front of earlier tokens, which don't start a
+    /// statement, are dropped on the way.
+    fn take_doc(&mut self) {
+        while self
+            .comments
+            .front()
+            .is_some_and(|(next, _)| *next < self.current)
+        {
+            self.comments.pop_front();
+        }
+        let mut comments = Vec::new();
+        while self
+            .comments
+            .front()
+            .is_some_and(|(next, _)| *next == self.current)
+        {
+            comments.extend(self.comments.pop_front().map(|(_, text)| text));
+        }
+        if !comments.is_empty() {
+            self.docs.push(Doc {
+                position: self.spans[self.current].start,
+                comments,
+            });
         }
     }
 
     fn peek(&self) -> Token {
-        self.tokens[self.current].clone()
+        self.tokens.get(self.current).cloned().unwrap_or(Token::Eof)
+    }
+
+    fn peek_next(&self) -> Token {
+        self.tokens
+            .get(self.current + 1)
+            .cloned()
+            .unwrap_or(Token::Eof)
     }
 
     fn consume(&mut self) -> Token {
@@ -169,30 +621,46 @@ impl Parser {
     }
 
     fn is_at_end(&self) -> bool {
-        self.peek() == Token::Eof || self.current >= self.tokens.len()
+        self.peek() == Token::Eof
     }
 
     fn expect(&mut self, token: Token) -> Result<Token, String> {
         if self.peek() == token {
             Ok(self.consume())
+        } else if token == Token::Semicolon && self.current > 0 {
+            // Point right after the statement rather than at whatever follows it, which is often
+            // on the next line
+            let end = self.spans[self.current - 1].end;
+            Err(format!("Missing ';' after statement at {}", end))
         } else {
-            Err(format!("Expected {:?}, found {:?}", token, self.peek()))
+            Err(format!(
+                "Expected {:?}, found {:?} at {}",
+                token,
+                self.peek(),
+                self.spans[self.current].start
+            ))
         }
     }
 
     fn program(&mut self) -> Result<Program, String> {
         let mut statements = Vec::new();
-        let mut errors = Vec::new();
         while !self.is_at_end() {
-            match self.statement() {
+            match self.line_statement() {
                 Ok(statement) => statements.push(statement),
-                Err(error) => errors.push(error),
+                Err(error) => self.errors.push(error),
             }
         }
-        if errors.is_empty() {
-            Ok(Program::new(statements))
+        // Errors inside blocks are collected in `self.errors` as well
+        if self.errors.is_empty() {
+            Ok(Program {
+                statements,
+                docs: std::mem::take(&mut self.docs),
+                comments: std::mem::take(&mut self.all_comments),
+                anchors: std::mem::take(&mut self.anchors),
+                files: Vec::new(),
+            })
         } else {
-            Err(errors.join("\n"))
+            Err(self.errors.join("\n"))
         }
     }
 
@@ -200,6 +668,7 @@ impl Parser {
 
     fn statement(&mut self) -> Result<Statement, String> {
         use Token as T;
+        self.take_doc();
         match self.peek() {
             T::Let => self.variable_definition(),
             T::Print => self.print(),
@@ -207,9 +676,27 @@ impl Parser {
             T::LeftBrace => self.block(),
             T::If => self.if_statement(),
             T::While => self.while_statement(),
+            T::For => self.for_statement(),
+            T::Do => self.do_while_statement(),
+            T::Break => self.break_statement(),
+            T::Exit => self.exit_statement(),
+            T::Match => self.match_statement(),
+            T::Identifier(_) if self.peek_next() == T::Colon => self.labeled_block(),
+            T::Identifier(_) if self.peek_next() == T::LeftParen => self.expression_statement(),
             T::Identifier(_) => self.assignment(),
             T::Function => self.function_declaration(),
-            _ => Err(format!("Expected statement, found {:?}", self.consume())),
+            T::LeftBracket | T::RightBracket => {
+                let start = self.spans[self.current].start;
+                Err(bracket_error(&self.consume(), start))
+            }
+            _ => {
+                let start = self.spans[self.current].start;
+                Err(format!(
+                    "Expected statement, found {:?} at {}",
+                    self.consume(),
+                    start
+                ))
+            }
         }
     }
 
@@ -236,7 +723,6 @@ impl Parser {
             }
         }
         self.expect(Token::RightParen)?; // )
-        self.expect(Token::LeftBrace)?; // {
         let body = self.block()?;
         Ok(Statement::FunctionDefinition {
             name,
@@ -247,11 +733,22 @@ impl Parser {
 
     fn variable_definition(&mut self) -> Result<Statement, String> {
         self.expect(Token::Let)?; // let
+        let position = self.spans[self.current].start;
         let name = if let Token::Identifier(name) = self.consume() {
             name
         } else {
             return Err("Expected variable name".to_string());
         };
+        let ty = if self.peek() == Token::Colon {
+            self.consume(); // :
+            if let Token::Identifier(ty) = self.consume() {
+                Some(Type::try_from(ty.as_str())?)
+            } else {
+                return Err("Expected type name".to_string());
+            }
+        } else {
+            None
+        };
         let initializer = if self.peek() == Token::Equal {
             self.consume(); // =
             Some(self.expression()?)
@@ -259,16 +756,62 @@ impl Parser {
             None
         };
         self.expect(Token::Semicolon)?; // ;
-        Ok(Statement::VariableDefinition { name, initializer })
+        Ok(Statement::VariableDefinition {
+            name,
+            ty,
+            initializer,
+            position,
+        })
     }
 
     fn print(&mut self) -> Result<Statement, String> {
         self.expect(Token::Print)?; // print
         self.expect(Token::LeftParen)?; // (
-        let expr = self.expression()?;
+
+        // A string literal followed by `,` or `)` is a format string, anything else is printed as
+        // a single value
+        let is_format = matches!(
+            (self.peek(), self.peek_next()),
+            (Token::String(_), Token::Comma | Token::RightParen)
+        );
+        if !is_format {
+            let expr = self.expression()?;
+            self.expect(Token::RightParen)?; // )
+            self.expect(Token::Semicolon)?; // ;
+            return Ok(Statement::Print {
+                format: vec![FormatPiece::Placeholder],
+                args: vec![expr],
+            });
+        }
+        let position = self.spans[self.current].start;
+        let Token::String(format) = self.consume() else {
+            unreachable!("Format strings are string literals");
+        };
+        let mut args = Vec::new();
+        while self.peek() == Token::Comma {
+            self.consume(); // ,
+            args.push(self.expression()?);
+        }
         self.expect(Token::RightParen)?; // )
         self.expect(Token::Semicolon)?; // ;
-        Ok(Statement::Print(expr))
+
+        // The whole statement is consumed before validating the format, so a mismatch doesn't
+        // cascade into errors about the rest of the statement
+        let format =
+            parse_format(&format).map_err(|e| format!("{} in format string at {}", e, position))?;
+        let placeholders = format
+            .iter()
+            .filter(|piece| **piece == FormatPiece::Placeholder)
+            .count();
+        if placeholders != args.len() {
+            return Err(format!(
+                "Format string at {} has {} placeholder(s) but {} argument(s) were given",
+                position,
+                placeholders,
+                args.len()
+            ));
+        }
+        Ok(Statement::Print { format, args })
     }
 
     fn return_statement(&mut self) -> Result<Statement, String> {
@@ -285,25 +828,37 @@ impl Parser {
     fn block(&mut self) -> Result<Statement, String> {
         let mut statements = Vec::new();
         self.expect(Token::LeftBrace)?; // {
+        self.anchor(Anchor::Open(self.spans[self.current - 1].start));
         while self.peek() != Token::RightBrace && !self.is_at_end() {
-            match self.statement() {
+            match self.line_statement() {
                 Ok(statement) => statements.push(statement),
                 Err(error) => self.errors.push(error),
             }
         }
+        self.anchor(Anchor::Close(self.spans[self.current].start));
         self.expect(Token::RightBrace)?; // }
         Ok(Statement::Block(statements))
     }
 
     fn if_statement(&mut self) -> Result<Statement, String> {
         self.expect(Token::If)?; // if
+        self.if_branches()
+    }
+
+    /// Parses the condition and branches of an if statement. `elif` is sugar for `else if`.
+    fn if_branches(&mut self) -> Result<Statement, String> {
         let condition = self.expression()?;
         let then_branch = Box::new(self.statement()?);
-        let else_branch = if self.peek() == Token::Else {
-            self.consume(); // else
-            Some(Box::new(self.statement()?))
-        } else {
-            None
+        let else_branch = match self.peek() {
+            Token::Else => {
+                self.consume(); // else
+                Some(Box::new(self.statement()?))
+            }
+            Token::Elif => {
+                self.consume(); // elif
+                Some(Box::new(self.if_branches()?))
+            }
+            _ => None,
         };
         Ok(Statement::If {
             condition,
@@ -319,23 +874,200 @@ impl Parser {
         Ok(Statement::While { condition, body })
     }
 
+    /// Parses `for init; condition; step body`, where `init` is a variable definition or an
+    /// assignment and `step` is an assignment. The loop is desugared into
+    /// `{ init; while condition { body step; } }`.
+    fn for_statement(&mut self) -> Result<Statement, String> {
+        self.expect(Token::For)?; // for
+        self.anchor(Anchor::For);
+        let init = match self.peek() {
+            Token::Let => self.variable_definition()?,
+            _ => self.assignment()?,
+        };
+        let condition = self.expression()?;
+        self.expect(Token::Semicolon)?; // ;
+        let step = self.assignment_without_semicolon()?;
+        let body = self.statement()?;
+        Ok(Statement::Block(vec![
+            init,
+            Statement::While {
+                condition,
+                body: Box::new(Statement::Block(vec![body, step])),
+            },
+        ]))
+    }
+
+    fn do_while_statement(&mut self) -> Result<Statement, String> {
+        self.expect(Token::Do)?; // do
+        let body = Box::new(self.statement()?);
+        self.expect(Token::While)?; // while
+        let condition = self.expression()?;
+        self.expect(Token::Semicolon)?; // ;
+        Ok(Statement::DoWhile { body, condition })
+    }
+
+    fn break_statement(&mut self) -> Result<Statement, String> {
+        self.expect(Token::Break)?; // break
+        self.expect(Token::Semicolon)?; // ;
+        Ok(Statement::Break)
+    }
+
+    fn exit_statement(&mut self) -> Result<Statement, String> {
+        self.expect(Token::Exit)?; // exit
+        let label = if let Token::Identifier(label) = self.consume() {
+            label
+        } else {
+            return Err("Expected block label".to_string());
+        };
+        self.expect(Token::Semicolon)?; // ;
+        Ok(Statement::Exit(label))
+    }
+
+    /// Parses `match value { pattern => body, ... }`. Patterns are literals, and a `_` arm
+    /// matching everything else may come last. The commas between arms are optional.
+    fn match_statement(&mut self) -> Result<Statement, String> {
+        self.expect(Token::Match)?; // match
+        let value = self.expression()?;
+        self.expect(Token::LeftBrace)?; // {
+        let mut arms = Vec::new();
+        let mut default = None;
+        while self.peek() != Token::RightBrace {
+            let position = self.spans[self.current].start;
+            if default.is_some() {
+                return Err(format!("Match arm after the _ arm at {}", position));
+            }
+            let pattern = match self.consume() {
+                Token::Number(n) => Some(Expr::Number(n)),
+                Token::Char(c) => Some(Expr::Char(c)),
+                Token::Boolean(b) => Some(Expr::Boolean(b)),
+                Token::Identifier(name) if name == "_" => None,
+                token => {
+                    return Err(format!(
+                        "Expected a literal or _ as match pattern, found {:?} at {}",
+                        token, position
+                    ))
+                }
+            };
+            self.expect(Token::FatArrow)?; // =>
+            let body = self.statement()?;
+            match pattern {
+                Some(pattern) => arms.push(MatchArm {
+                    pattern,
+                    position,
+                    body,
+                }),
+                None => default = Some(Box::new(body)),
+            }
+            if self.peek() == Token::Comma {
+                self.consume(); // ,
+            }
+        }
+        self.expect(Token::RightBrace)?; // }
+        Ok(Statement::Match {
+            value,
+            arms,
+            default,
+        })
+    }
+
+    fn labeled_block(&mut self) -> Result<Statement, String> {
+        let label = if let Token::Identifier(label) = self.consume() {
+            label
+        } else {
+            return Err("Expected block label".to_string());
+        };
+        self.expect(Token::Colon)?; // :
+        let Statement::Block(body) = self.block()? else {
+            unreachable!()
+        };
+        Ok(Statement::LabeledBlock { label, body })
+    }
+
     fn assignment(&mut self) -> Result<Statement, String> {
+        let statement = self.assignment_without_semicolon()?;
+        self.expect(Token::Semicolon)?; // ;
+        Ok(statement)
+    }
+
+    fn assignment_without_semicolon(&mut self) -> Result<Statement, String> {
+        let position = self.spans[self.current].start;
         let name = if let Token::Identifier(name) = self.consume() {
             name
         } else {
             return Err("Expected variable name".to_string());
         };
-        self.expect(Token::Equal)?; // =
-        let value = self.expression()?;
+        let value = if self.peek() == Token::PercentEqual {
+            // `x %= y` is short for `x = x % y`
+            let position = self.spans[self.current].start;
+            self.consume(); // %=
+            Expr::Binary {
+                lhs: Box::new(Expr::Identifier(name.clone())),
+                op: BinaryOp::Mod,
+                rhs: Box::new(self.expression()?),
+                position,
+            }
+        } else {
+            self.expect(Token::Equal)?; // =
+            self.expression()?
+        };
+        Ok(Statement::Assignment {
+            name,
+            value,
+            position,
+        })
+    }
+
+    fn expression_statement(&mut self) -> Result<Statement, String> {
+        let expr = self.expression()?;
         self.expect(Token::Semicolon)?; // ;
-        Ok(Statement::Assignment { name, value })
+        Ok(Statement::Expression(expr))
     }
 
     fn expression(&mut self) -> Result<Expr, String> {
+        // Assignment has the lowest precedence and is right-associative
+        if let (Token::Identifier(name), Token::Equal) = (self.peek(), self.peek_next()) {
+            let position = self.spans[self.current].start;
+            self.consume(); // name
+            self.consume(); // =
+            let value = self.expression()?;
+            return Ok(Expr::Assignment {
+                name,
+                value: Box::new(value),
+                position,
+            });
+        }
+        self.binary_expression(0)
+    }
+
+    /// Parses a chain of binary operations by precedence climbing. Only operators binding
+    /// tighter than `min_precedence` are consumed, which makes operators left-associative.
+    fn binary_expression(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while self.peek().is_binary_op() {
+            let op = BinaryOp::from(self.peek());
+            if op.precedence() <= min_precedence {
+                break;
+            }
+            let position = self.spans[self.current].start;
+            self.consume(); // operator
+            let rhs = self.binary_expression(op.precedence())?;
+            lhs = Expr::Binary {
+                lhs: Box::new(lhs),
+                op,
+                rhs: Box::new(rhs),
+                position,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
         use Token as T;
         use UnaryOp as U;
         let expr = match self.consume() {
             T::Number(n) => Expr::Number(n),
+            T::Char(c) => Expr::Char(c),
+            T::Boolean(b) => Expr::Boolean(b),
             T::String(s) => Expr::String(s),
             T::Identifier(name) => match self.peek() {
                 T::LeftParen => {
@@ -353,49 +1085,296 @@ impl Parser {
                     self.expect(T::RightParen)?; // )
                     Expr::FunctionCall { callee: name, args }
                 }
+                T::LeftBracket => {
+                    let position = self.spans[self.current].start;
+                    return Err(bracket_error(&self.consume(), position));
+                }
                 _ => Expr::Identifier(name),
             },
-            T::LeftParen => {
-                let expr = self.expression()?;
-                self.expect(T::RightParen)?; // )
-                expr
-            }
+            T::LeftParen => match (self.peek(), self.peek_next()) {
+                (T::Identifier(name), T::RightParen) if Type::try_from(name.as_str()).is_ok() => {
+                    let ty = Type::try_from(name.as_str())?;
+                    self.consume(); // type
+                    self.expect(T::RightParen)?; // )
+                    Expr::Cast {
+                        ty,
+                        value: Box::new(self.unary()?),
+                    }
+                }
+                _ => {
+                    let expr = self.expression()?;
+                    self.expect(T::RightParen)?; // )
+                    expr
+                }
+            },
             T::Minus => Expr::Unary {
                 op: U::Neg,
-                rhs: Box::new(self.expression()?),
+                rhs: Box::new(self.unary()?),
             },
             T::Not => Expr::Unary {
                 op: U::Not,
-                rhs: Box::new(self.expression()?),
+                rhs: Box::new(self.unary()?),
             },
-            _ => return Err("Expected expression".to_string()),
-        };
-        // Check for binary expressions
-        let expr = if self.peek().is_binary_op() {
-            let mut lhs = expr;
-            let mut op: BinaryOp = self.consume().into();
-            let mut rhs = self.expression()?;
-            while self.peek().is_binary_op()
-                && op.precedence() < BinaryOp::from(self.peek()).precedence()
-            {
-                let next_op = self.consume().into();
-                let next_rhs = self.expression()?;
-                lhs = Expr::Binary {
-                    lhs: Box::new(lhs),
-                    op,
-                    rhs: Box::new(rhs),
-                };
-                op = next_op;
-                rhs = next_rhs;
-            }
-            Expr::Binary {
-                lhs: Box::new(lhs),
-                op,
-                rhs: Box::new(rhs),
+            token => {
+                let position = self.spans[self.current - 1].start;
+                return Err(match token {
+                    T::LeftBracket | T::RightBracket => bracket_error(&token, position),
+                    _ => format!("Expected expression, found {:?} at {}", token, position),
+                });
             }
-        } else {
-            expr
         };
         Ok(expr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tokenize_located;
+
+    fn parse_src(src: &str) -> Result<Program, String> {
+        let tokens = tokenize_located(src);
+        parse(&tokens)
+    }
+
+    #[test]
+    fn test_display_expr() {
+        let exprs = |src| -> Vec<String> {
+            parse_src(src)
+                .unwrap()
+                .statements
+                .iter()
+                .flat_map(Statement::expressions)
+                .map(Expr::to_string)
+                .collect()
+        };
+        assert_eq!(exprs("print(1 + 2 * 3);"), ["(1 + (2 * 3))"]);
+        assert_eq!(
+            exprs("let x = (1 + 2) * 3 - 4 / 2; while !(x < 3) { x = f(x, 'a') -| 1; }"),
+            ["(((1 + 2) * 3) - (4 / 2))", "!(x < 3)", "(f(x, 'a') -| 1)"]
+        );
+        assert_eq!(exprs("let y = x = 1 == 2;"), ["(x = (1 == 2))"]);
+        assert_eq!(exprs("print(x +% 1 -% 2);"), ["((x +% 1) -% 2)"]);
+        assert_eq!(
+            exprs("let z = a || b && c < 2 || d;"),
+            ["((a || (b && (c < 2))) || d)"]
+        );
+    }
+
+    #[test]
+    fn test_cast() {
+        let program = parse_src("let c = (char) x + 1;").unwrap();
+        let [Statement::VariableDefinition {
+            initializer: Some(init),
+            ..
+        }] = program.statements.as_slice()
+        else {
+            panic!("Expected a variable definition");
+        };
+        assert_eq!(init.to_string(), "((char) x + 1)");
+        assert!(parse_src("let c = (x) + 1;").is_ok());
+        assert!(parse_src("let c = (u16) x;").is_err());
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let src = "// The answer\n/* twice */\nlet x = 42; // not a doc\n\
+                   while x {\n    // Count down\n    x = x - 1;\n}\n// At the end\n";
+        let tokens = tokenize_located(src);
+        assert!(parse(&tokens).unwrap().docs.is_empty());
+        let program = parse_with_comments(&tokens).unwrap();
+        assert_eq!(program.statements.len(), 2);
+        assert_eq!(
+            program.docs,
+            [
+                Doc {
+                    position: Position {
+                        file: 0,
+                        line: 3,
+                        column: 1,
+                    },
+                    comments: vec![" The answer".to_string(), " twice ".to_string()],
+                },
+                Doc {
+                    position: Position {
+                        file: 0,
+                        line: 6,
+                        column: 5,
+                    },
+                    comments: vec![" Count down".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_match() {
+        let program = parse_src("match x { 0 => print(0); 'a' => { } _ => x = 1; }").unwrap();
+        let [Statement::Match { arms, default, .. }] = program.statements.as_slice() else {
+            panic!("Expected a match statement");
+        };
+        assert_eq!(arms.len(), 2);
+        assert!(matches!(arms[1].pattern, Expr::Char('a')));
+        assert!(matches!(
+            default.as_deref(),
+            Some(Statement::Assignment { .. })
+        ));
+        assert_eq!(
+            parse_src("match x { _ => { }, 1 => { } }")
+                .unwrap_err()
+                .lines()
+                .next(),
+            Some("Match arm after the _ arm at line 1, column 21")
+        );
+        assert_eq!(
+            parse_src("match x { y => { } }").unwrap_err().lines().next(),
+            Some("Expected a literal or _ as match pattern, found Identifier(\"y\") at line 1, column 11")
+        );
+    }
+
+    #[test]
+    fn test_empty_program() {
+        for src in ["", "   \n\t", "// just a comment\n"] {
+            assert!(parse_src(src).unwrap().statements.is_empty());
+        }
+        assert!(parse(&[]).unwrap().statements.is_empty());
+        let mut tokens = tokenize_located("let x = 1;");
+        tokens.pop();
+        assert_eq!(parse(&tokens).unwrap().statements.len(), 1);
+        tokens.pop();
+        assert_eq!(
+            parse(&tokens).unwrap_err(),
+            "Missing ';' after statement at line 1, column 10"
+        );
+    }
+
+    #[test]
+    fn test_non_ascii() {
+        let err = parse_src("let x = 1;\nlet sjö = 2;").unwrap_err();
+        assert_eq!(
+            err,
+            "Identifier sjö at line 2, column 5 contains non-ASCII characters"
+        );
+        let err = parse_src("let x = 1 → 2;").unwrap_err();
+        assert_eq!(err, "Unexpected character '→' at line 1, column 11");
+    }
+
+    #[test]
+    fn test_missing_semicolon() {
+        let err = parse_src("let x = 5\nprint(x);").unwrap_err();
+        assert_eq!(err, "Missing ';' after statement at line 1, column 10");
+        let err = parse_src("while true {\n    x = x + 1\n}").unwrap_err();
+        assert_eq!(err, "Missing ';' after statement at line 2, column 14");
+        let err = parse_src("print(1;").unwrap_err();
+        assert_eq!(
+            err.lines().next(),
+            Some("Expected RightParen, found Semicolon at line 1, column 8")
+        );
+    }
+
+    #[test]
+    fn test_modulo_assign() {
+        let program = parse_src("x %= 3;").unwrap();
+        let [Statement::Assignment { name, value, .. }] = program.statements.as_slice() else {
+            panic!(
+                "Expected a single assignment, found {:?}",
+                program.statements
+            );
+        };
+        assert_eq!(name, "x");
+        let Expr::Binary { lhs, op, rhs, .. } = value else {
+            panic!("Expected a binary expression, found {:?}", value);
+        };
+        assert!(matches!(lhs.as_ref(), Expr::Identifier(lhs) if lhs == "x"));
+        assert!(matches!(op, BinaryOp::Mod));
+        assert!(matches!(rhs.as_ref(), Expr::Number(3)));
+    }
+
+    #[test]
+    fn test_elif() -> Result<(), String> {
+        let program =
+            parse_src("if a { print(1); } elif b { print(2); } elif c { print(3); } else { }")?;
+        let [Statement::If {
+            condition: Expr::Identifier(a),
+            else_branch: Some(elif_b),
+            ..
+        }] = program.statements.as_slice()
+        else {
+            panic!("Expected an if statement, found {:?}", program.statements);
+        };
+        assert_eq!(a, "a");
+        let Statement::If {
+            condition: Expr::Identifier(b),
+            else_branch: Some(elif_c),
+            ..
+        } = elif_b.as_ref()
+        else {
+            panic!("Expected elif to become a nested if, found {:?}", elif_b);
+        };
+        assert_eq!(b, "b");
+        let Statement::If {
+            condition: Expr::Identifier(c),
+            else_branch: Some(else_branch),
+            ..
+        } = elif_c.as_ref()
+        else {
+            panic!("Expected elif to become a nested if, found {:?}", elif_c);
+        };
+        assert_eq!(c, "c");
+        assert!(matches!(else_branch.as_ref(), Statement::Block(b) if b.is_empty()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_arguments() {
+        assert_eq!(
+            parse_src("print(\"{} {}\", 1);").unwrap_err(),
+            "Format string at line 1, column 7 has 2 placeholder(s) but 1 argument(s) were given"
+        );
+        assert_eq!(
+            parse_src("print(\"}\");").unwrap_err(),
+            "Unmatched '}' in format string at line 1, column 7"
+        );
+        let program = parse_src("print(\"{{{}}}!\", x);").unwrap();
+        let [Statement::Print { format, args }] = program.statements.as_slice() else {
+            panic!("Expected a print statement, found {:?}", program.statements);
+        };
+        assert_eq!(
+            format,
+            &[
+                FormatPiece::Text("{".to_string()),
+                FormatPiece::Placeholder,
+                FormatPiece::Text("}!".to_string())
+            ]
+        );
+        assert!(matches!(args.as_slice(), [Expr::Identifier(x)] if x == "x"));
+    }
+
+    #[test]
+    fn test_brackets() {
+        let first_error = |src| {
+            parse_src(src)
+                .unwrap_err()
+                .lines()
+                .next()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            first_error("let x = a[1];"),
+            "Unexpected '[' at line 1, column 10, arrays are not supported"
+        );
+        assert_eq!(
+            first_error("let x = 1;\n  [x];"),
+            "Unexpected '[' at line 2, column 3, arrays are not supported"
+        );
+        assert_eq!(
+            first_error("let x = ];"),
+            "Unexpected ']' at line 1, column 9, arrays are not supported"
+        );
+        assert_eq!(
+            first_error("let x = ;"),
+            "Expected expression, found Semicolon at line 1, column 9"
+        );
+    }
+}
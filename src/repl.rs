@@ -0,0 +1,104 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{compiler::Compiler, interpreter::Interpreter};
+
+/// Compiles brang source incrementally and runs it against a persistent tape, so variables
+/// defined on one line stay available on the following ones.
+pub(crate) struct Repl {
+    compiler: Compiler,
+    interpreter: Interpreter,
+    buffer: String,
+}
+
+impl Repl {
+    pub(crate) fn new() -> Self {
+        Self {
+            compiler: Compiler::new(),
+            interpreter: Interpreter::empty(),
+            buffer: String::new(),
+        }
+    }
+
+    /// Whether a previous line left a block open and more input is needed.
+    pub(crate) fn is_continuing(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Feeds a line of source to the REPL.
+    ///
+    /// # Returns
+    /// The output produced by running the line, or `None` if the line left a block open and
+    /// was buffered until it is closed.
+    pub(crate) fn feed(&mut self, line: &str) -> Result<Option<String>, String> {
+        self.buffer.push_str(line);
+        self.buffer.push('\n');
+        let depth =
+            self.buffer.matches('{').count() as isize - self.buffer.matches('}').count() as isize;
+        if depth > 0 {
+            return Ok(None);
+        }
+        let src = std::mem::take(&mut self.buffer);
+        let code = self.compiler.compile_chunk(&src)?;
+        let start = self.interpreter.output().len();
+        self.interpreter.load(&code);
+        self.interpreter
+            .run_to_completion()
+            .map_err(|e| e.to_string())?;
+        Ok(Some(self.interpreter.output()[start..].to_string()))
+    }
+}
+
+pub fn run() {
+    let mut repl = Repl::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", if repl.is_continuing() { "... " } else { "> " });
+        io::stdout().flush().expect("Could not flush stdout");
+        let Some(Ok(line)) = lines.next() else {
+            println!();
+            return;
+        };
+        match repl.feed(&line) {
+            Ok(Some(output)) if !output.is_empty() => println!("{}", output),
+            Ok(_) => (),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repl() -> Result<(), String> {
+        let mut repl = Repl::new();
+        let mut output = String::new();
+        for line in [
+            "let x = 5;",
+            "print_number(x);",
+            "x = x + 1;",
+            "print_number(x);",
+            "if x == 6 {",
+            "    print(\" six\");",
+            "}",
+        ] {
+            if let Some(out) = repl.feed(line)? {
+                output.push_str(&out);
+            }
+        }
+        assert_eq!(output, "56 six");
+        Ok(())
+    }
+
+    #[test]
+    fn test_repl_recovers_from_errors() -> Result<(), String> {
+        let mut repl = Repl::new();
+        repl.feed("let x = 3;")?;
+        assert!(repl.feed("let x = 4;").is_err());
+        assert!(repl.feed("y = 1;").is_err());
+        assert_eq!(repl.feed("print_number(x);")?, Some("3".to_string()));
+        Ok(())
+    }
+}
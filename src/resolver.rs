@@ -0,0 +1,180 @@
+use crate::{
+    parser::{Expr, Program, Statement},
+    tokenizer::Position,
+};
+
+/// Checks that every assignment in `program` is to a variable declared in an enclosing scope,
+/// so the mistake is reported with its position before any code is generated.
+pub(crate) fn check_assignments(program: &Program) -> Result<(), String> {
+    let mut resolver = Resolver {
+        scopes: vec![Vec::new()],
+        errors: Vec::new(),
+        files: &program.files,
+    };
+    resolver.statements(&program.statements);
+    if resolver.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(resolver.errors.join("\n"))
+    }
+}
+
+struct Resolver<'a> {
+    /// The names declared in each enclosing scope, innermost last.
+    scopes: Vec<Vec<&'a str>>,
+    errors: Vec<String>,
+    /// The names of the files the program was read from.
+    files: &'a [String],
+}
+
+impl<'a> Resolver<'a> {
+    fn is_declared(&self, name: &str) -> bool {
+        self.scopes
+            .iter()
+            .flatten()
+            .any(|declared| *declared == name)
+    }
+
+    fn assign(&mut self, name: &str, position: Position) {
+        if !self.is_declared(name) {
+            self.errors.push(position.in_file(
+                self.files,
+                format!("Assignment to undeclared variable {} at {}", name, position),
+            ));
+        }
+    }
+
+    /// Resolves `statements` in a new scope.
+    fn scope(&mut self, statements: &'a [Statement]) {
+        self.scopes.push(Vec::new());
+        self.statements(statements);
+        self.scopes.pop();
+    }
+
+    fn statements(&mut self, statements: &'a [Statement]) {
+        for stmt in statements {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &'a Statement) {
+        use Statement as S;
+        match stmt {
+            S::FunctionDefinition { params, body, .. } => {
+                // Function bodies only see their parameters
+                let caller_scopes = std::mem::replace(
+                    &mut self.scopes,
+                    vec![params.iter().map(String::as_str).collect()],
+                );
+                self.statement(body);
+                self.scopes = caller_scopes;
+            }
+            S::VariableDefinition {
+                name, initializer, ..
+            } => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.scopes.last_mut().unwrap().push(name);
+            }
+            S::Assignment {
+                name,
+                value,
+                position,
+            } => {
+                self.expression(value);
+                self.assign(name, *position);
+            }
+            S::Return(value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            S::Print { args, .. } => args.iter().for_each(|arg| self.expression(arg)),
+            S::Expression(expr) => self.expression(expr),
+            S::Block(statements)
+            | S::LabeledBlock {
+                body: statements, ..
+            } => self.scope(statements),
+            S::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            S::While { condition, body } | S::DoWhile { body, condition } => {
+                self.expression(condition);
+                self.statement(body);
+            }
+            S::Break | S::Exit(_) => (),
+            S::Match {
+                value,
+                arms,
+                default,
+            } => {
+                self.expression(value);
+                for arm in arms {
+                    self.statement(&arm.body);
+                }
+                if let Some(default) = default {
+                    self.statement(default);
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        use Expr as E;
+        match expr {
+            E::Unary { rhs, .. } | E::Cast { value: rhs, .. } => self.expression(rhs),
+            E::Binary { lhs, rhs, .. } => {
+                self.expression(lhs);
+                self.expression(rhs);
+            }
+            E::FunctionCall { args, .. } => args.iter().for_each(|arg| self.expression(arg)),
+            E::Assignment {
+                name,
+                value,
+                position,
+            } => {
+                self.expression(value);
+                self.assign(name, *position);
+            }
+            E::Number(_) | E::Char(_) | E::Boolean(_) | E::String(_) | E::Identifier(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::parse, tokenizer::tokenize_located};
+
+    fn check(src: &str) -> Result<(), String> {
+        check_assignments(&parse(&tokenize_located(src))?)
+    }
+
+    #[test]
+    fn test_undeclared_assignment() {
+        assert_eq!(
+            check("let x = 1;\ny = 5;"),
+            Err("Assignment to undeclared variable y at line 2, column 1".to_string())
+        );
+        assert_eq!(
+            check("{ let x = 1; } x = 2; let z = w = 3;"),
+            Err(concat!(
+                "Assignment to undeclared variable x at line 1, column 16\n",
+                "Assignment to undeclared variable w at line 1, column 31"
+            )
+            .to_string())
+        );
+        assert!(check("let x; { x = 2; } fn f(a) { a = x = 1; }").is_err());
+        assert!(check("let x; { x = 2; } fn f(a) { a = 1; }").is_ok());
+        assert!(check("for let i = 0; i != 3; i = i + 1 { }").is_ok());
+    }
+}
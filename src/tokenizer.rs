@@ -1,4 +1,8 @@
-use std::{iter::Peekable, str::Chars};
+use std::{
+    fmt::{self, Display},
+    iter::Peekable,
+    str::Chars,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
@@ -10,6 +14,7 @@ pub enum Token {
     LeftBracket,
     RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
@@ -31,22 +36,39 @@ pub enum Token {
     NotEqual,
     AndAnd,
     OrOr,
+    PercentEqual,
+    /// `+%`, addition that wraps around.
+    PlusPercent,
+    /// `+|`, addition that saturates at 255.
+    PlusPipe,
+    /// `-%`, subtraction that wraps around.
+    MinusPercent,
+    /// `-|`, subtraction that saturates at 0.
+    MinusPipe,
+    /// `=>`, between the pattern and the body of a match arm.
+    FatArrow,
 
     // Literals.
     Identifier(String),
     String(String),
+    Char(char),
     Number(u8),
     Boolean(bool),
 
     // Keywords.
     If,
     Else,
+    Elif,
     For,
     While,
+    Do,
+    Break,
+    Exit,
     Return,
     Function,
     Let,
     Print,
+    Match,
 
     // Misc
     Eof,
@@ -64,6 +86,10 @@ impl Token {
             self,
             Token::Plus
                 | Token::Minus
+                | Token::PlusPercent
+                | Token::PlusPipe
+                | Token::MinusPercent
+                | Token::MinusPipe
                 | Token::Star
                 | Token::Slash
                 | Token::Percent
@@ -75,13 +101,113 @@ impl Token {
                 | Token::GreaterEqual
                 | Token::And
                 | Token::Or
+                | Token::AndAnd
+                | Token::OrOr
         )
     }
 }
 
-pub struct TokenStream<'a> {
+/// A line and column in the source code, both starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Position {
+    /// The index of the file among the files compiled together, or 0 for a single source.
+    pub(crate) file: usize,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+impl Position {
+    /// `message` about the code at this position, after the name of its file if `files` has it.
+    pub(crate) fn in_file(&self, files: &[String], message: String) -> String {
+        match files.get(self.file) {
+            Some(name) => format!("{}: {}", name, message),
+            None => message,
+        }
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// The part of the source code a token was read from. `end` is the position right after the
+/// token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Span {
+    pub(crate) start: Position,
+    pub(crate) end: Position,
+}
+
+/// The characters of the source code, keeping track of the position of the next one.
+struct Source<'a> {
     chars: Peekable<Chars<'a>>,
+    position: Position,
+}
+
+impl<'a> Source<'a> {
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    /// Looks at the character after the next one without consuming anything.
+    fn peek_second(&self) -> Option<char> {
+        self.chars.clone().nth(1)
+    }
+
+    fn next_if(&mut self, predicate: impl FnOnce(&char) -> bool) -> Option<char> {
+        if self.peek().is_some_and(predicate) {
+            self.next()
+        } else {
+            None
+        }
+    }
+
+    fn next_if_eq(&mut self, expected: &char) -> Option<char> {
+        self.next_if(|c| c == expected)
+    }
+}
+
+impl<'a> Iterator for Source<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+        Some(c)
+    }
+}
+
+pub struct TokenStream<'a> {
+    chars: Source<'a>,
+    /// Where the last token read starts.
+    start: Position,
     is_eof: bool,
+    /// A character that starts a line comment like `//` does.
+    comment_prefix: Option<char>,
+}
+
+/// The characters other than letters and digits that start a token.
+const TOKEN_STARTS: &str = "(){}[],:.-+;*%!=<></&|\"'_";
+
+/// Whether `c` is free to start line comments, because it is not whitespace and doesn't already
+/// start a token.
+pub(crate) fn can_start_comment(c: char) -> bool {
+    !c.is_whitespace() && !c.is_alphanumeric() && !TOKEN_STARTS.contains(c)
+}
+
+impl TokenStream<'_> {
+    /// Makes `prefix`, e.g. `#`, start a line comment too. `//` always starts one.
+    pub fn with_comment_prefix(mut self, prefix: Option<char>) -> Self {
+        self.comment_prefix = prefix;
+        self
+    }
 }
 
 impl<'a> Iterator for TokenStream<'a> {
@@ -92,8 +218,14 @@ impl<'a> Iterator for TokenStream<'a> {
             return None;
         }
 
+        while self.chars.next_if(char::is_ascii_whitespace).is_some() {}
+        let start = self.chars.position;
+        self.start = start;
         if let Some(c) = self.chars.next() {
             let token = match c {
+                c if Some(c) == self.comment_prefix => {
+                    Token::Comment(read_comment(&mut self.chars))
+                }
                 '(' => Token::LeftParen,
                 ')' => Token::RightParen,
                 '{' => Token::LeftBrace,
@@ -101,12 +233,28 @@ impl<'a> Iterator for TokenStream<'a> {
                 '[' => Token::LeftBracket,
                 ']' => Token::RightBracket,
                 ',' => Token::Comma,
+                ':' => Token::Colon,
                 '.' => Token::Dot,
-                '-' => Token::Minus,
-                '+' => Token::Plus,
+                '-' => match self.chars.next_if(|c| *c == '%' || *c == '|') {
+                    Some('%') => Token::MinusPercent,
+                    Some(_) => Token::MinusPipe,
+                    None => Token::Minus,
+                },
+                '+' => match self.chars.next_if(|c| *c == '%' || *c == '|') {
+                    Some('%') => Token::PlusPercent,
+                    Some(_) => Token::PlusPipe,
+                    None => Token::Plus,
+                },
                 ';' => Token::Semicolon,
                 '*' => Token::Star,
-                '%' => Token::Percent,
+                '%' => {
+                    if self.chars.peek() == Some(&'=') {
+                        self.chars.next();
+                        Token::PercentEqual
+                    } else {
+                        Token::Percent
+                    }
+                }
                 '!' => {
                     if self.chars.peek() == Some(&'=') {
                         self.chars.next();
@@ -119,6 +267,9 @@ impl<'a> Iterator for TokenStream<'a> {
                     if self.chars.peek() == Some(&'=') {
                         self.chars.next();
                         Token::EqualEqual
+                    } else if self.chars.peek() == Some(&'>') {
+                        self.chars.next();
+                        Token::FatArrow
                     } else {
                         Token::Equal
                     }
@@ -139,14 +290,14 @@ impl<'a> Iterator for TokenStream<'a> {
                         Token::Greater
                     }
                 }
-                '/' => {
-                    if self.chars.peek() == Some(&'/') {
-                        self.chars.next();
-                        Token::Comment(read_comment(&mut self.chars))
-                    } else {
-                        Token::Slash
-                    }
-                }
+                '/' => match self.chars.next_if(|c| *c == '/' || *c == '*') {
+                    Some('/') => Token::Comment(read_comment(&mut self.chars)),
+                    Some(_) => match read_block_comment(&mut self.chars) {
+                        Some(comment) => Token::Comment(comment),
+                        None => Token::Error(format!("Unterminated block comment at {}", start)),
+                    },
+                    None => Token::Slash,
+                },
                 '&' => {
                     if self.chars.peek() == Some(&'&') {
                         self.chars.next();
@@ -164,10 +315,31 @@ impl<'a> Iterator for TokenStream<'a> {
                     }
                 }
                 '"' => Token::String(read_string(&mut self.chars)),
-                ' ' | '\n' | '\t' | '\r' => return self.next(),
-                n if n.is_ascii_digit() => Token::Number(read_number(&mut self.chars, n)),
-                n if n.is_ascii() => read_identifier(&mut self.chars, n),
-                _ => Token::Error(format!("Unexpected character: {}", c)),
+                '\'' => read_char(&mut self.chars),
+                n if n.is_ascii_digit() => {
+                    let number = read_number(&mut self.chars, n);
+                    if self.chars.peek() == Some(&'.')
+                        && self.chars.peek_second().is_some_and(|c| c.is_ascii_digit())
+                    {
+                        self.chars.next(); // .
+                        let first = self.chars.next().unwrap();
+                        let fraction = read_number(&mut self.chars, first);
+                        Token::Error(format!(
+                            "Floating-point literal {}.{} at {} is not supported",
+                            number, fraction, start
+                        ))
+                    } else {
+                        match number.parse() {
+                            Ok(n) => Token::Number(n),
+                            Err(_) => Token::Error(format!(
+                                "Number literal {} at {} is out of range",
+                                number, start
+                            )),
+                        }
+                    }
+                }
+                n if n.is_alphabetic() || n == '_' => read_identifier(&mut self.chars, n, start),
+                _ => Token::Error(format!("Unexpected character {:?} at {}", c, start)),
             };
             Some(token)
         } else {
@@ -179,12 +351,43 @@ impl<'a> Iterator for TokenStream<'a> {
 
 pub fn tokenize(src: &str) -> TokenStream<'_> {
     TokenStream {
-        chars: src.chars().peekable(),
+        chars: Source {
+            chars: src.chars().peekable(),
+            position: Position {
+                file: 0,
+                line: 1,
+                column: 1,
+            },
+        },
+        start: Position {
+            file: 0,
+            line: 1,
+            column: 1,
+        },
         is_eof: false,
+        comment_prefix: None,
+    }
+}
+
+/// Tokenizes `src`, pairing each token with the part of the source it was read from.
+pub(crate) fn tokenize_located(src: &str) -> Vec<(Token, Span)> {
+    locate(tokenize(src))
+}
+
+/// Reads the rest of `stream`, pairing each token with the part of the source it was read from.
+pub(crate) fn locate(mut stream: TokenStream<'_>) -> Vec<(Token, Span)> {
+    let mut tokens = Vec::new();
+    while let Some(token) = stream.next() {
+        let span = Span {
+            start: stream.start,
+            end: stream.chars.position,
+        };
+        tokens.push((token, span));
     }
+    tokens
 }
 
-fn read_number(chars: &mut Peekable<Chars<'_>>, first_num: char) -> u8 {
+fn read_number(chars: &mut Source<'_>, first_num: char) -> String {
     let mut number = String::new();
     number.push(first_num);
     while let Some(c) = chars.peek() {
@@ -195,10 +398,10 @@ fn read_number(chars: &mut Peekable<Chars<'_>>, first_num: char) -> u8 {
             break;
         }
     }
-    number.parse().unwrap()
+    number
 }
 
-fn read_string(chars: &mut Peekable<Chars<'_>>) -> String {
+fn read_string(chars: &mut Source<'_>) -> String {
     let mut string = String::new();
     while let Some(c) = chars.next() {
         match c {
@@ -219,7 +422,42 @@ fn read_string(chars: &mut Peekable<Chars<'_>>) -> String {
     string
 }
 
-fn read_comment(chars: &mut Peekable<Chars<'_>>) -> String {
+fn read_char(chars: &mut Source<'_>) -> Token {
+    let c = match chars.next() {
+        Some('\\') => match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c) => c,
+            None => return Token::Error("Unterminated character literal".to_string()),
+        },
+        Some('\'') => return Token::Error("Empty character literal".to_string()),
+        Some(c) => c,
+        None => return Token::Error("Unterminated character literal".to_string()),
+    };
+    if chars.next_if_eq(&'\'').is_none() {
+        // Skip the rest of the literal so it doesn't derail the following tokens
+        for c in chars.by_ref() {
+            match c {
+                '\'' => {
+                    return Token::Error(
+                        "Character literal must contain a single character".to_string(),
+                    )
+                }
+                '\n' => break,
+                _ => (),
+            }
+        }
+        return Token::Error("Unterminated character literal".to_string());
+    }
+    if c as u32 > u8::MAX as u32 {
+        return Token::Error(format!("Character literal does not fit in a byte: {}", c));
+    }
+    Token::Char(c)
+}
+
+fn read_comment(chars: &mut Source<'_>) -> String {
     let mut comment = String::new();
     for c in chars.by_ref() {
         match c {
@@ -230,29 +468,63 @@ fn read_comment(chars: &mut Peekable<Chars<'_>>) -> String {
     comment
 }
 
-fn read_identifier(chars: &mut Peekable<Chars<'_>>, first_char: char) -> Token {
+/// Reads the text of a `/* */` comment up to and including the closing `*/`. Block comments
+/// don't nest.
+///
+/// # Returns
+/// The text of the comment, or `None` if the source ends before the comment is closed
+fn read_block_comment(chars: &mut Source<'_>) -> Option<String> {
+    let mut comment = String::new();
+    loop {
+        match chars.next()? {
+            '*' if chars.next_if_eq(&'/').is_some() => return Some(comment),
+            c => comment.push(c),
+        }
+    }
+}
+
+/// The words that are read as keywords instead of identifiers, and their tokens.
+const KEYWORDS: &[(&str, Token)] = &[
+    ("if", Token::If),
+    ("else", Token::Else),
+    ("elif", Token::Elif),
+    ("for", Token::For),
+    ("while", Token::While),
+    ("do", Token::Do),
+    ("break", Token::Break),
+    ("exit", Token::Exit),
+    ("return", Token::Return),
+    ("fn", Token::Function),
+    ("let", Token::Let),
+    ("print", Token::Print),
+    ("match", Token::Match),
+    ("true", Token::Boolean(true)),
+    ("false", Token::Boolean(false)),
+];
+
+/// Reads an identifier or keyword. Identifiers may only contain ASCII letters, digits and
+/// underscores, but other letters are read as part of the identifier so that a word
+/// containing them is reported as a single error.
+fn read_identifier(chars: &mut Source<'_>, first_char: char, start: Position) -> Token {
     let mut identifier = String::new();
     identifier.push(first_char);
     while let Some(c) = chars.peek() {
-        if c.is_alphanumeric() {
+        if c.is_alphanumeric() || *c == '_' {
             identifier.push(*c);
             chars.next();
         } else {
             break;
         }
     }
-    match identifier.as_str() {
-        "if" => Token::If,
-        "else" => Token::Else,
-        "for" => Token::For,
-        "while" => Token::While,
-        "return" => Token::Return,
-        "fn" => Token::Function,
-        "let" => Token::Let,
-        "print" => Token::Print,
-        "true" => Token::Boolean(true),
-        "false" => Token::Boolean(false),
-        _ => Token::Identifier(identifier),
+    if !identifier.is_ascii() {
+        return Token::Error(format!(
+            "Identifier {} at {} contains non-ASCII characters",
+            identifier, start
+        ));
+    }
+    match KEYWORDS.iter().find(|(keyword, _)| *keyword == identifier) {
+        Some((_, token)) => token.clone(),
+        None => Token::Identifier(identifier),
     }
 }
 
@@ -266,6 +538,76 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_ends_with_eof() {
+        for src in [
+            "",
+            "  ",
+            "// comment",
+            "let x = 1;",
+            "x\n",
+            "\"unterminated",
+            "1.5",
+        ] {
+            let tokens: Vec<Token> = tokenize(src).collect();
+            assert_eq!(tokens.last(), Some(&Token::Eof), "{:?}", src);
+            assert_eq!(tokens.iter().filter(|t| **t == Token::Eof).count(), 1);
+            assert_eq!(
+                tokenize_located(src).last().map(|(t, _)| t),
+                Some(&Token::Eof)
+            );
+        }
+    }
+
+    #[test]
+    fn test_keywords() {
+        for (keyword, token) in KEYWORDS {
+            let tokens: Vec<Token> = tokenize(keyword).collect();
+            assert_eq!(tokens, [token.clone(), Token::Eof]);
+            // A keyword is only a keyword as a whole word
+            let longer = format!("{}_", keyword);
+            let tokens: Vec<Token> = tokenize(&longer).collect();
+            assert_eq!(tokens, [Token::Identifier(longer), Token::Eof]);
+        }
+    }
+
+    #[test]
+    fn test_number_out_of_range() {
+        let tokens: Vec<Token> = tokenize("print_number(255);\nlet x = 300;").collect();
+        assert!(tokens.contains(&Token::Number(255)));
+        assert!(tokens.contains(&Token::Error(
+            "Number literal 300 at line 2, column 9 is out of range".to_string()
+        )));
+    }
+
+    #[test]
+    fn test_comment_prefix() {
+        let tokens: Vec<Token> = tokenize("x # y\n// z\n/* # */ 1")
+            .with_comment_prefix(Some('#'))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                Token::Identifier("x".to_string()),
+                Token::Comment(" y".to_string()),
+                Token::Comment(" z".to_string()),
+                Token::Comment(" # ".to_string()),
+                Token::Number(1),
+                Token::Eof,
+            ]
+        );
+        // Without a prefix, `#` is not part of the language
+        let tokens: Vec<Token> = tokenize("# y").collect();
+        assert!(matches!(tokens[0], Token::Error(_)));
+        // Only characters that would otherwise be unexpected can start comments
+        for c in (' '..='~').filter(|c| can_start_comment(*c)) {
+            let tokens: Vec<Token> = tokenize(&c.to_string()).collect();
+            assert!(matches!(tokens[0], Token::Error(_)), "{:?}", c);
+        }
+        assert!(can_start_comment('#') && can_start_comment('@'));
+        assert!(!"+\"'a7_ \t".chars().any(can_start_comment));
+    }
+
     #[test]
     fn test_tokenizer() -> Result<(), io::Error> {
         let dir = read_dir("tests/tokenizer")?.filter(|e| {
@@ -0,0 +1,5 @@
+# Prints "Hi" followed by a newline.
+# Comments may contain instructions [like these] without running them: +-<>.,
+++++++++[>+++++++++<-]>.   # 72 = H
++++++++++++++++++++++++++++++++++.   # 105 = i
+[-]++++++++++.   # newline
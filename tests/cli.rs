@@ -0,0 +1,359 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+fn brang2(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_brang2"))
+        .args(args)
+        .output()
+        .expect("Could not run brang2")
+}
+
+/// A path in the temporary directory that is unique to this test process.
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("brang2-cli-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn test_make_exit_codes() {
+    let output = temp_path("hello.bf");
+    let result = brang2(&[
+        "make",
+        "tests/compile/hello.brang",
+        "-o",
+        output.to_str().unwrap(),
+    ]);
+    assert_eq!(result.status.code(), Some(0));
+    assert!(fs::metadata(&output).is_ok());
+    fs::remove_file(&output).unwrap();
+
+    let bad = temp_path("bad.brang");
+    fs::write(&bad, "let x = ;").unwrap();
+    let result = brang2(&[
+        "make",
+        bad.to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+    ]);
+    fs::remove_file(&bad).unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).starts_with("error: "));
+    assert!(fs::metadata(&output).is_err());
+
+    let result = brang2(&["make", "tests/compile/missing.brang"]);
+    assert_eq!(result.status.code(), Some(2));
+}
+
+#[test]
+fn test_stats_exit_codes() {
+    assert_eq!(
+        brang2(&["stats", "tests/brainfuck/comments.bf"])
+            .status
+            .code(),
+        Some(0)
+    );
+    let bad = temp_path("unmatched.bf");
+    fs::write(&bad, "[[]").unwrap();
+    let result = brang2(&["stats", bad.to_str().unwrap()]);
+    fs::remove_file(&bad).unwrap();
+    assert_eq!(result.status.code(), Some(1));
+}
+
+#[test]
+fn test_time() {
+    let output = temp_path("timed.bf");
+    let result = brang2(&[
+        "make",
+        "tests/compile/hello.brang",
+        "-o",
+        output.to_str().unwrap(),
+        "--time",
+    ]);
+    assert_eq!(result.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("time: compile "));
+    let timed = fs::read_to_string(&output).unwrap();
+    brang2(&[
+        "make",
+        "tests/compile/hello.brang",
+        "-o",
+        output.to_str().unwrap(),
+    ]);
+    assert_eq!(fs::read_to_string(&output).unwrap(), timed);
+    fs::remove_file(&output).unwrap();
+
+    // Running writes its temporary files to the working directory
+    let dir = temp_path("time");
+    fs::create_dir_all(&dir).unwrap();
+    let src = fs::canonicalize("tests/brainfuck/comments.bf").unwrap();
+    let run = |time: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_brang2"));
+        command.current_dir(&dir).arg("run").arg(&src);
+        if time {
+            command.arg("--time");
+        }
+        command.output().expect("Could not run brang2")
+    };
+    let (plain, timed) = (run(false), run(true));
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(timed.status.code(), Some(0));
+    assert_eq!(timed.stdout, plain.stdout);
+    assert_eq!(String::from_utf8_lossy(&timed.stdout), "Hi\n");
+    let stderr = String::from_utf8_lossy(&timed.stderr);
+    assert!(stderr.contains("time: transpile ") && stderr.contains(", run "));
+}
+
+#[test]
+fn test_dump() {
+    let src = temp_path("dump.bf");
+    fs::write(&src, "+++>++>+<").unwrap();
+    let result = brang2(&["dump", src.to_str().unwrap()]);
+    fs::remove_file(&src).unwrap();
+    assert_eq!(result.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&result.stdout).contains(" 03[02]01"));
+}
+
+#[test]
+fn test_passthrough() {
+    let output = temp_path("passthrough.bf");
+    let result = brang2(&[
+        "make",
+        "--passthrough",
+        "tests/brainfuck/comments.bf",
+        "-o",
+        output.to_str().unwrap(),
+    ]);
+    assert_eq!(result.status.code(), Some(0));
+    assert_eq!(
+        fs::read_to_string(&output).unwrap(),
+        fs::read_to_string("tests/brainfuck/comments.bf").unwrap()
+    );
+    let result = brang2(&["dump", output.to_str().unwrap()]);
+    fs::remove_file(&output).unwrap();
+    assert!(String::from_utf8_lossy(&result.stdout).ends_with("Output:\nHi\n\n"));
+
+    let bad = temp_path("stray.bf");
+    fs::write(&bad, "+++\n>x").unwrap();
+    let result = brang2(&["make", "--passthrough", bad.to_str().unwrap()]);
+    fs::remove_file(&bad).unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("line 2, column 2"));
+
+    // Each file starts on a line of its own
+    let first = temp_path("first.bf");
+    let second = temp_path("second.bf");
+    fs::write(&first, "+++ # three").unwrap();
+    fs::write(&second, "# one less\n-").unwrap();
+    let result = brang2(&[
+        "make",
+        "--passthrough",
+        first.to_str().unwrap(),
+        second.to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+    ]);
+    fs::remove_file(&first).unwrap();
+    fs::remove_file(&second).unwrap();
+    assert_eq!(result.status.code(), Some(0));
+    assert_eq!(
+        fs::read_to_string(&output).unwrap(),
+        "+++ # three\n# one less\n-"
+    );
+    fs::remove_file(&output).unwrap();
+}
+
+#[test]
+fn test_check() {
+    let result = brang2(&["check", "tests/compile/hello.brang"]);
+    assert_eq!(result.status.code(), Some(0));
+    assert!(result.stdout.is_empty());
+
+    let bad = temp_path("check.brang");
+    fs::write(&bad, "let x = 1;\ny = 2;\nz = 3;").unwrap();
+    let result = brang2(&["check", bad.to_str().unwrap()]);
+    fs::remove_file(&bad).unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("undeclared variable y at line 2"));
+    assert!(stderr.contains("undeclared variable z at line 3"));
+
+    // Options that change how the program compiles can fail a check like they fail make
+    let nested = temp_path("nested.brang");
+    fs::write(
+        &nested,
+        "fn f(n) { return n; } fn g(n) { return f(n); } print_number(g(1));",
+    )
+    .unwrap();
+    let result = brang2(&["check", "--max-inline-depth", "1", nested.to_str().unwrap()]);
+    fs::remove_file(&nested).unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("nests calls deeper than 1 levels"));
+    for flag in ["--debug-checks", "--sentinel", "--print-radix=hex"] {
+        let result = brang2(&["check", flag, "tests/compile/hello.brang"]);
+        assert_eq!(result.status.code(), Some(0), "{}", flag);
+    }
+}
+
+#[test]
+fn test_dump_memory_on_error() {
+    let src = temp_path("underflow.bf");
+    fs::write(&src, "++>+<<+").unwrap();
+    let plain = brang2(&["dump", src.to_str().unwrap()]);
+    let dumped = brang2(&["dump", "--dump-memory-on-error", src.to_str().unwrap()]);
+    fs::remove_file(&src).unwrap();
+    assert_eq!(plain.status.code(), Some(1));
+    assert!(!String::from_utf8_lossy(&plain.stderr).contains("Memory:"));
+    assert_eq!(dumped.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&dumped.stderr);
+    assert!(stderr.contains("Memory:\n[02]01"));
+    assert!(stderr.contains("Stopped at instruction 5"));
+    assert!(stderr.contains("error: Pointer moved left of the first cell at index 5"));
+}
+
+#[test]
+fn test_comment_prefix() {
+    let src = temp_path("hash_comments.brang");
+    fs::write(
+        &src,
+        "# A shell-style comment\nlet x = 1; // and a regular one\nprint(x);",
+    )
+    .unwrap();
+    let plain = brang2(&["check", src.to_str().unwrap()]);
+    let hashed = brang2(&["check", "--comment-prefix", "#", src.to_str().unwrap()]);
+    let formatted = brang2(&["fmt", "--comment-prefix", "#", src.to_str().unwrap()]);
+    fs::remove_file(&src).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&formatted.stdout),
+        "// A shell-style comment\nlet x = 1; // and a regular one\nprint(x);\n"
+    );
+    assert_eq!(plain.status.code(), Some(1));
+    assert_eq!(hashed.status.code(), Some(0));
+    // A character that already starts a token can't start comments
+    let taken = brang2(&["check", "--comment-prefix", "+", "unused.brang"]);
+    assert_eq!(taken.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&taken.stderr).contains("'+' already starts a token"));
+}
+
+#[test]
+fn test_program_from_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_brang2"))
+        .args(["dump", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Could not run brang2");
+    // The `,`s must read what follows the `!`, not the rest of the program
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b",.>,.\n+++!ok")
+        .unwrap();
+    let result = child.wait_with_output().unwrap();
+    assert_eq!(result.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(stdout.contains("6f[6e]"), "{}", stdout);
+    assert!(stdout.contains("ok"));
+}
+
+#[test]
+fn test_strict() {
+    let src = temp_path("unused.brang");
+    let output = temp_path("unused.bf");
+    fs::write(&src, "let unused = 1;").unwrap();
+    let plain = brang2(&["check", src.to_str().unwrap()]);
+    let strict = brang2(&["check", "--strict", src.to_str().unwrap()]);
+    let strict_make = brang2(&[
+        "make",
+        "--strict",
+        src.to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+    ]);
+    fs::remove_file(&src).unwrap();
+    assert_eq!(plain.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&plain.stderr).starts_with("warning: "));
+    assert_eq!(strict.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&strict.stderr);
+    assert!(stderr.contains(&format!(
+        "error: {}: Variable unused at line 1, column 5 is never used",
+        src.to_str().unwrap()
+    )));
+    assert!(stderr.contains("error: Found 1 warning with --strict"));
+    // Nothing is written when the warnings fail the build
+    assert_eq!(strict_make.status.code(), Some(1));
+    assert!(fs::metadata(&output).is_err());
+}
+
+#[test]
+fn test_ast_comments() {
+    let src = temp_path("ast_comments.brang");
+    fs::write(&src, "// One\nlet x = 1;\nprint(x); // Two\n").unwrap();
+    let result = brang2(&["ast", "--json", "--comments", src.to_str().unwrap()]);
+    fs::remove_file(&src).unwrap();
+    assert_eq!(result.status.code(), Some(0));
+    // A single document, so the output can be read by a JSON parser
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert_eq!(stdout.lines().count(), 1);
+    assert!(stdout.starts_with("{\"statements\":[{\"type\":\"VariableDefinition\""));
+    assert!(stdout.ends_with(
+        ",\"docs\":[{\"type\":\"Doc\",\"line\":2,\"column\":1,\"comments\":[\" One\"]}]}\n"
+    ));
+}
+
+#[test]
+fn test_fmt() {
+    let src = temp_path("fmt.brang");
+    fs::write(
+        &src,
+        "// Start\nlet x=1;// kept\nwhile x{\n/* Count down */ x=x-1;\n// Done\n}\n// End",
+    )
+    .unwrap();
+    let result = brang2(&["fmt", src.to_str().unwrap()]);
+    fs::write(&src, "let x = 1 + // lost\n2;").unwrap();
+    let misplaced = brang2(&["fmt", src.to_str().unwrap()]);
+    fs::remove_file(&src).unwrap();
+    assert_eq!(result.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "// Start\nlet x = 1; // kept\nwhile x {\n    // Count down\n    x = x - 1;\n    // Done\n}\n\
+         // End\n"
+    );
+    assert_eq!(misplaced.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&misplaced.stderr)
+        .contains("error: Could not keep the comment at line 1, column 13 in its place"));
+}
+
+#[test]
+fn test_wrap_with_traps() {
+    let src = temp_path("traps.brang");
+    let output = temp_path("traps.bf");
+    let run = |program: &str| {
+        fs::write(&src, program).unwrap();
+        let args = [
+            "make",
+            src.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+        ];
+        assert_eq!(brang2(&args).status.code(), Some(0));
+        let plain = brang2(&["dump", output.to_str().unwrap()]);
+        let wrapped = brang2(&["dump", "--wrap", output.to_str().unwrap()]);
+        (plain, wrapped)
+    };
+    let (_, wrapped) = run("let x = 2; print(\"ok\");");
+    assert_eq!(wrapped.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&wrapped.stdout).contains("Output:\nok"));
+    // Hitting the recursion limit traps, which a circular tape would wrap around
+    let (plain, wrapped) =
+        run("fn f(n) { if n == 0 { return 0; } return f(n - 1); } print_number(f(20));");
+    fs::remove_file(&src).unwrap();
+    fs::remove_file(&output).unwrap();
+    assert_eq!(plain.status.code(), Some(1));
+    assert_eq!(wrapped.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&wrapped.stderr)
+        .contains("error: The program stops itself by moving left of the first cell"));
+}
@@ -0,0 +1,82 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Directories with programs covering functions, scopes, strings and warnings.
+const CORPUS: [&str; 3] = ["tests/compile", "tests/compiler", "tests/determinism"];
+
+/// Files compiled together, each declaring an unused global at the same line and column.
+const MULTI: [&str; 3] = [
+    "tests/determinism/multi/a.brang",
+    "tests/determinism/multi/b.brang",
+    "tests/determinism/multi/c.brang",
+];
+
+/// Compiles `srcs` in a new process, returning the generated code and the printed warnings.
+fn make<P: AsRef<Path>>(srcs: &[P], output: &Path) -> (Vec<u8>, Vec<u8>) {
+    let result = Command::new(env!("CARGO_BIN_EXE_brang2"))
+        .arg("make")
+        .args(srcs.iter().map(AsRef::as_ref))
+        .arg("-o")
+        .arg(output)
+        .output()
+        .expect("Could not run brang2");
+    assert!(
+        result.status.success(),
+        "Could not compile {:?}",
+        srcs.iter().map(AsRef::as_ref).collect::<Vec<_>>()
+    );
+    let code = fs::read(output).unwrap();
+    fs::remove_file(output).unwrap();
+    (code, result.stderr)
+}
+
+#[test]
+fn test_deterministic_output() {
+    let output = env::temp_dir().join(format!("brang2-determinism-{}.bf", std::process::id()));
+    let mut programs: Vec<PathBuf> = CORPUS
+        .iter()
+        .flat_map(|dir| fs::read_dir(dir).unwrap())
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "brang"))
+        .collect();
+    programs.sort();
+    assert!(programs.len() >= 6);
+    for program in &programs {
+        let first = make(&[program], &output);
+        let second = make(&[program], &output);
+        assert!(
+            first == second,
+            "Compiling {:?} twice gave different results",
+            program
+        );
+    }
+}
+
+#[test]
+fn test_deterministic_multiple_files() {
+    let output = env::temp_dir().join(format!(
+        "brang2-determinism-multi-{}.bf",
+        std::process::id()
+    ));
+    let first = make(&MULTI, &output);
+    for _ in 0..4 {
+        assert!(
+            make(&MULTI, &output) == first,
+            "Compiling {:?} again gave different results",
+            MULTI
+        );
+    }
+    let warnings = String::from_utf8(first.1).unwrap();
+    let positions: Vec<usize> = MULTI
+        .iter()
+        .map(|file| warnings.find(&format!("{}: Variable", file)).unwrap())
+        .collect();
+    assert!(
+        positions.windows(2).all(|pair| pair[0] < pair[1]),
+        "{}",
+        warnings
+    );
+}